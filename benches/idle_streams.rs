@@ -0,0 +1,83 @@
+//! Round-trip latency on one busy stream while hundreds of others sit idle in `read`.
+//!
+//! Needs CAP_NET_ADMIN: it creates tun0, gives the kernel side 192.168.0.1, and talks to our
+//! stack at 192.168.0.2 through ordinary kernel sockets. Run with `cargo bench --bench
+//! idle_streams`, optionally passing the number of idle streams (default 500).
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PORT: u16 = 1000;
+const ROUND_TRIPS: usize = 2000;
+
+fn main() -> io::Result<()> {
+    let idle: usize = std::env::args()
+        .skip(1)
+        .find_map(|a| a.parse().ok())
+        .unwrap_or(500);
+
+    let mut i = trust::InterfaceBuilder::new()
+        .kernel_address(Ipv4Addr::new(192, 168, 0, 1), 24)
+        .build()?;
+    let mut l = i.bind(PORT)?;
+    let addr = SocketAddr::from(([192, 168, 0, 2], PORT));
+
+    // the idle streams: a thread per stream, blocked in `read` until the peer hangs up
+    let mut peers = Vec::with_capacity(idle);
+    let mut readers = Vec::with_capacity(idle);
+    for _ in 0..idle {
+        peers.push(TcpStream::connect(addr)?);
+        let mut s = l.accept()?;
+        readers.push(thread::spawn(move || {
+            let mut buf = [0; 64];
+            while s.read(&mut buf).unwrap_or(0) != 0 {}
+        }));
+    }
+
+    // the busy stream: echo everything back
+    let mut client = TcpStream::connect(addr)?;
+    client.set_nodelay(true)?;
+    let mut server = l.accept()?;
+    let echo = thread::spawn(move || {
+        let mut buf = [0; 64];
+        loop {
+            match server.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if server.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut rtts = Vec::with_capacity(ROUND_TRIPS);
+    let mut buf = [0; 1];
+    for _ in 0..ROUND_TRIPS {
+        let start = Instant::now();
+        client.write_all(b"x")?;
+        client.read_exact(&mut buf)?;
+        rtts.push(start.elapsed());
+    }
+    rtts.sort();
+    let total: Duration = rtts.iter().sum();
+    println!(
+        "{} idle streams, {} round trips: mean {:?}, p50 {:?}, p99 {:?}",
+        idle,
+        ROUND_TRIPS,
+        total / ROUND_TRIPS as u32,
+        rtts[ROUND_TRIPS / 2],
+        rtts[ROUND_TRIPS * 99 / 100],
+    );
+
+    drop(client);
+    echo.join().unwrap();
+    drop(peers);
+    for r in readers {
+        r.join().unwrap();
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! Aggregate receive throughput with many streams being read from concurrently.
+//!
+//! Needs CAP_NET_ADMIN: it creates tun0, gives the kernel side 192.168.0.1, and has kernel
+//! sockets send to our stack at 192.168.0.2, with one reader thread per stream on our side.
+//! Run with `cargo bench --bench throughput`, optionally passing the number of streams (default
+//! 16).
+//!
+//! With `--pipe`, the senders are instead streams on a second interface, connected to ours by a
+//! `PipeDevice`, so that both ends are this stack and no privileges are needed.
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream};
+use std::thread;
+use std::time::Instant;
+
+const PORT: u16 = 1000;
+const BYTES_PER_STREAM: usize = 4 << 20;
+
+fn main() -> io::Result<()> {
+    let streams: usize = std::env::args()
+        .skip(1)
+        .find_map(|a| a.parse().ok())
+        .unwrap_or(16);
+
+    if std::env::args().any(|a| a == "--pipe") {
+        over_pipe(streams)
+    } else {
+        over_tun(streams)
+    }
+}
+
+fn over_tun(streams: usize) -> io::Result<()> {
+    // with the default queue of 500 packets, bursts from many senders get dropped before
+    // packet_loop sees them, and the benchmark ends up measuring retransmission timeouts
+    let mut i = trust::InterfaceBuilder::new()
+        .kernel_address(Ipv4Addr::new(192, 168, 0, 1), 24)
+        .txqueuelen(10_000)
+        .build()?;
+    let mut l = i.bind(PORT)?;
+    let addr = SocketAddr::from(([192, 168, 0, 2], PORT));
+
+    let mut peers = Vec::with_capacity(streams);
+    let mut ours = Vec::with_capacity(streams);
+    for _ in 0..streams {
+        peers.push(TcpStream::connect(addr)?);
+        ours.push(l.accept()?);
+    }
+    measure(peers, ours);
+    Ok(())
+}
+
+fn over_pipe(streams: usize) -> io::Result<()> {
+    let (a, b) = trust::PipeDevice::pair(1500);
+    let mut peer = trust::Interface::with_device(a)?;
+    let mut i = trust::Interface::with_device(b)?;
+    let mut l = i.bind(PORT)?;
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), PORT);
+
+    let mut peers = Vec::with_capacity(streams);
+    let mut ours = Vec::with_capacity(streams);
+    for _ in 0..streams {
+        peers.push(peer.connect(Ipv4Addr::new(10, 0, 0, 1), addr)?);
+        ours.push(l.accept()?);
+    }
+    measure(peers, ours);
+    Ok(())
+}
+
+/// Streams of either stack, which the senders shut down once they are done.
+trait HalfClose {
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl HalfClose for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl HalfClose for trust::TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// Has each of `peers` send `BYTES_PER_STREAM` bytes to its counterpart in `ours`, and reports
+/// how fast they arrived.
+fn measure<P, S>(peers: Vec<P>, ours: Vec<S>)
+where
+    P: Read + Write + HalfClose + Send + 'static,
+    S: Read + Send + 'static,
+{
+    let streams = ours.len();
+    let start = Instant::now();
+    let senders: Vec<_> = peers
+        .into_iter()
+        .map(|mut s| {
+            thread::spawn(move || {
+                let chunk = [0xa5; 64 << 10];
+                let mut left = BYTES_PER_STREAM;
+                while left > 0 {
+                    let n = std::cmp::min(left, chunk.len());
+                    s.write_all(&chunk[..n]).unwrap();
+                    left -= n;
+                }
+                s.shutdown_write().unwrap();
+                // hold on to the socket until our side has read everything
+                let _ = s.read(&mut [0]);
+            })
+        })
+        .collect();
+    let readers: Vec<_> = ours
+        .into_iter()
+        .map(|mut s| {
+            thread::spawn(move || {
+                let mut buf = [0; 16 << 10];
+                let mut total = 0;
+                loop {
+                    match s.read(&mut buf).unwrap() {
+                        0 => break total,
+                        n => total += n,
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let total: usize = readers.into_iter().map(|r| r.join().unwrap()).sum();
+    let elapsed = start.elapsed();
+    for s in senders {
+        s.join().unwrap();
+    }
+    assert_eq!(total, streams * BYTES_PER_STREAM);
+
+    println!(
+        "{} streams, {} MiB in {:?}: {:.1} MiB/s",
+        streams,
+        total >> 20,
+        elapsed,
+        (total >> 20) as f64 / elapsed.as_secs_f64(),
+    );
+}
@@ -0,0 +1,13 @@
+//! Arbitrary bytes, one packet at a time, into an interface with a listener.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trust_fuzz::Peer;
+
+fuzz_target!(|packets: Vec<Vec<u8>>| {
+    let mut peer = Peer::new();
+    for packet in packets {
+        peer.send(&packet);
+    }
+});
@@ -0,0 +1,14 @@
+//! Well-formed segments with arbitrary contents, interleaved with whatever the application
+//! might do, to drive connections through their states.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trust_fuzz::{Op, Peer};
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut peer = Peer::new();
+    for op in ops {
+        peer.apply(op);
+    }
+});
@@ -0,0 +1,357 @@
+//! What the fuzz targets share: an interface under simulation, and a peer that sends it
+//! whatever the fuzzer comes up with.
+//!
+//! Run a target with `cargo +nightly fuzz run segments` (or `packets`) from the repository root.
+//! Any panic, or any error out of the interface, is a bug: remote input must never be able to
+//! stop a real interface's packet loop.
+
+use arbitrary::Arbitrary;
+use etherparse::{
+    IpHeader, IpTrafficClass, Ipv4Header, Ipv6Header, SlicedPacket, TcpHeader, TransportSlice,
+    UdpHeader,
+};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::time::Duration;
+use trust::{Interface, NetDevice, PipeDevice, Simulation, TcpListener, TcpStream, UdpSocket};
+
+pub const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+pub const REMOTE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+pub const LOCAL6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+pub const REMOTE6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+pub const LISTEN_PORT: u16 = 80;
+pub const UDP_PORT: u16 = 53;
+pub const REMOTE_PORT: u16 = 40000;
+const MTU: usize = 1500;
+
+/// A segment from the peer. Everything the stack might trip over is up to the fuzzer.
+#[derive(Arbitrary, Debug)]
+pub struct Segment {
+    /// over IPv6 rather than IPv4
+    pub v6: bool,
+    /// which of the interface's ports it goes to: the listening one, or one it connected from
+    pub port: u8,
+    pub flags: u8,
+    /// how far the sequence number is from the one the interface last acknowledged
+    pub seq: i32,
+    /// how far the acknowledgment number is from the end of what the interface last sent
+    pub ack: i32,
+    pub window: u16,
+    pub options: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// Something the peer or the application does.
+#[derive(Arbitrary, Debug)]
+pub enum Op {
+    Segment(Segment),
+    /// a packet the peer sends as is
+    Raw(Vec<u8>),
+    /// a UDP datagram from the peer, to one of the bound sockets or to a port nobody listens on
+    Datagram {
+        v6: bool,
+        port: u8,
+        payload: Vec<u8>,
+    },
+    Connect {
+        v6: bool,
+    },
+    Accept,
+    Write {
+        stream: u8,
+        data: Vec<u8>,
+    },
+    Read {
+        stream: u8,
+        len: u16,
+    },
+    Shutdown {
+        stream: u8,
+        how: u8,
+    },
+    Drop {
+        stream: u8,
+    },
+    DropListener,
+    SendTo {
+        v6: bool,
+        data: Vec<u8>,
+    },
+    RecvFrom {
+        v6: bool,
+        len: u16,
+    },
+    /// lets this many tens of milliseconds pass, for timers to fire
+    Wait(u8),
+}
+
+/// An interface listening on `LISTEN_PORT`, and the other end of its link.
+pub struct Peer {
+    // dropped first, so that the simulation outlives everything that uses the interface
+    streams: Vec<TcpStream>,
+    listener: Option<TcpListener>,
+    /// bound to `UDP_PORT` on `LOCAL`, and the port after it on `LOCAL6`
+    udp: [UdpSocket; 2],
+    iface: Interface,
+    sim: Simulation,
+    wire: PipeDevice,
+    /// the interface's ports segments can go to
+    ports: Vec<u16>,
+    /// for each of the interface's ports, the last acknowledgment number it sent, and the end
+    /// of the last segment it sent
+    seen: HashMap<u16, (u32, u32)>,
+    buf: Vec<u8>,
+}
+
+impl Default for Peer {
+    fn default() -> Self {
+        Peer::new()
+    }
+}
+
+impl Peer {
+    pub fn new() -> Self {
+        let mut sim = Simulation::new(0);
+        let (a, wire) = PipeDevice::pair(MTU);
+        let mut iface = sim.add_interface(a).unwrap();
+        let listener = iface.bind(LISTEN_PORT).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let udp = [false, true].map(|v6| {
+            let s = iface.bind_udp((local(v6), UDP_PORT + v6 as u16)).unwrap();
+            s.set_nonblocking(true).unwrap();
+            s
+        });
+        Peer {
+            streams: Vec::new(),
+            listener: Some(listener),
+            udp,
+            iface,
+            sim,
+            wire,
+            ports: vec![LISTEN_PORT],
+            seen: HashMap::new(),
+            // room for more than the link carries, to catch packets that would not fit it
+            buf: vec![0; 1 << 16],
+        }
+    }
+
+    /// Has the peer send `packet`, and lets the interface handle it.
+    ///
+    /// Packets larger than the MTU would not make it across the link, so they are not sent.
+    pub fn send(&mut self, packet: &[u8]) {
+        if packet.len() <= MTU {
+            self.wire.send(packet).unwrap();
+        }
+        self.step();
+    }
+
+    /// Moves the simulation on by a tick, and checks what the interface sent in the meantime.
+    ///
+    /// The interface must never fail: in a real one, that would end its packet loop. Nor may
+    /// it send packets larger than the MTU.
+    pub fn step(&mut self) {
+        if let Err(e) = self.sim.step() {
+            panic!("the interface failed: {}", e);
+        }
+        while let Some(n) = self.wire.recv(&mut self.buf, Duration::ZERO).unwrap() {
+            assert!(
+                n <= MTU,
+                "sent {} bytes over a link with an MTU of {}",
+                n,
+                MTU
+            );
+            let packet = SlicedPacket::from_ip(&self.buf[..n]).expect("sent a malformed packet");
+            // ICMP and UDP leave nothing to keep track of
+            let tcph = match packet.transport {
+                Some(TransportSlice::Tcp(tcph)) => tcph,
+                _ => continue,
+            };
+            let port = tcph.source_port();
+            if tcph.syn() && !tcph.ack() && !self.ports.contains(&port) {
+                self.ports.push(port);
+            }
+            let len = packet.payload.len();
+            let end = tcph
+                .sequence_number()
+                .wrapping_add(len as u32 + tcph.syn() as u32 + tcph.fin() as u32);
+            let seen = self.seen.entry(port).or_default();
+            if tcph.ack() {
+                seen.0 = tcph.acknowledgment_number();
+            }
+            seen.1 = end;
+        }
+    }
+
+    pub fn apply(&mut self, op: Op) {
+        match op {
+            Op::Segment(s) => {
+                let packet = self.build(&s);
+                self.send(&packet);
+            }
+            Op::Raw(packet) => self.send(&packet),
+            Op::Datagram { v6, port, payload } => {
+                let packet = datagram(v6, UDP_PORT + (port % 3) as u16, &payload);
+                self.send(&packet);
+            }
+            Op::Connect { v6 } => {
+                let addr = SocketAddr::new(remote(v6), REMOTE_PORT);
+                if let Ok(s) = self.iface.connect_nonblocking(local(v6), addr) {
+                    self.streams.push(s);
+                }
+            }
+            Op::Accept => {
+                if let Some(Ok(s)) = self.listener.as_mut().map(|l| l.accept()) {
+                    s.set_nonblocking(true).unwrap();
+                    self.streams.push(s);
+                }
+            }
+            Op::Write { stream, data } => {
+                if let Some(s) = self.stream(stream) {
+                    let _ = s.write(&data);
+                }
+            }
+            Op::Read { stream, len } => {
+                if let Some(s) = self.stream(stream) {
+                    let _ = s.read(&mut vec![0; len as usize]);
+                }
+            }
+            Op::Shutdown { stream, how } => {
+                let how = match how % 3 {
+                    0 => Shutdown::Read,
+                    1 => Shutdown::Write,
+                    _ => Shutdown::Both,
+                };
+                if let Some(s) = self.stream(stream) {
+                    let _ = s.shutdown(how);
+                }
+            }
+            Op::Drop { stream } => {
+                if !self.streams.is_empty() {
+                    let i = stream as usize % self.streams.len();
+                    self.streams.remove(i);
+                }
+            }
+            Op::DropListener => drop(self.listener.take()),
+            Op::SendTo { v6, data } => {
+                let _ = self.udp[v6 as usize].send_to(&data, (remote(v6), REMOTE_PORT));
+            }
+            Op::RecvFrom { v6, len } => {
+                let _ = self.udp[v6 as usize].recv_from(&mut vec![0; len as usize]);
+            }
+            Op::Wait(ms) => {
+                let until = self.sim.elapsed() + Duration::from_millis(ms as u64 * 10);
+                while self.sim.elapsed() < until {
+                    self.step();
+                }
+            }
+        }
+    }
+
+    fn stream(&mut self, i: u8) -> Option<&mut TcpStream> {
+        if self.streams.is_empty() {
+            return None;
+        }
+        let n = self.streams.len();
+        self.streams.get_mut(i as usize % n)
+    }
+
+    /// Builds a well-formed packet carrying `s`, so that the fuzzer spends its time on what the
+    /// segment says rather than on getting past the parser.
+    fn build(&self, s: &Segment) -> Vec<u8> {
+        let port = self.ports[s.port as usize % self.ports.len()];
+        let (acked, sent) = self.seen.get(&port).copied().unwrap_or_default();
+        let seq = acked.wrapping_add(s.seq as u32);
+        let mut tcp = TcpHeader::new(REMOTE_PORT, port, seq, s.window);
+        tcp.fin = s.flags & 0x01 != 0;
+        tcp.syn = s.flags & 0x02 != 0;
+        tcp.rst = s.flags & 0x04 != 0;
+        tcp.psh = s.flags & 0x08 != 0;
+        tcp.ack = s.flags & 0x10 != 0;
+        tcp.urg = s.flags & 0x20 != 0;
+        tcp.acknowledgment_number = sent.wrapping_add(s.ack as u32);
+        let mut options = s.options[..s.options.len().min(40)].to_vec();
+        options.resize(options.len().div_ceil(4) * 4, 0);
+        tcp.set_options_raw(&options).unwrap();
+
+        let room = MTU - ip_header_len(s.v6) - tcp.header_len() as usize;
+        let payload = &s.payload[..s.payload.len().min(room)];
+        let len = tcp.header_len() as usize + payload.len();
+        let ip = ip_header(s.v6, IpTrafficClass::Tcp, len);
+        tcp.checksum = match &ip {
+            IpHeader::Version4(ip) => tcp.calc_checksum_ipv4(ip, payload),
+            IpHeader::Version6(ip) => tcp.calc_checksum_ipv6(ip, payload),
+        }
+        .unwrap();
+
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+fn local(v6: bool) -> IpAddr {
+    if v6 {
+        LOCAL6.into()
+    } else {
+        LOCAL.into()
+    }
+}
+
+fn remote(v6: bool) -> IpAddr {
+    if v6 {
+        REMOTE6.into()
+    } else {
+        REMOTE.into()
+    }
+}
+
+fn ip_header_len(v6: bool) -> usize {
+    if v6 {
+        40
+    } else {
+        20
+    }
+}
+
+/// The header of a packet from the peer to the interface, carrying `len` bytes of `protocol`.
+fn ip_header(v6: bool, protocol: IpTrafficClass, len: usize) -> IpHeader {
+    if v6 {
+        IpHeader::Version6(Ipv6Header {
+            traffic_class: 0,
+            flow_label: 0,
+            payload_length: len as u16,
+            next_header: protocol as u8,
+            hop_limit: 64,
+            source: REMOTE6.octets(),
+            destination: LOCAL6.octets(),
+        })
+    } else {
+        IpHeader::Version4(Ipv4Header::new(
+            len as u16,
+            64,
+            protocol,
+            REMOTE.octets(),
+            LOCAL.octets(),
+        ))
+    }
+}
+
+/// A well-formed UDP datagram from the peer to `port`, with as much of `payload` as fits.
+fn datagram(v6: bool, port: u16, payload: &[u8]) -> Vec<u8> {
+    let payload = &payload[..payload.len().min(MTU - ip_header_len(v6) - 8)];
+    let ip = ip_header(v6, IpTrafficClass::Udp, 8 + payload.len());
+    let udp = match &ip {
+        IpHeader::Version4(ip) => UdpHeader::with_ipv4_checksum(REMOTE_PORT, port, ip, payload),
+        IpHeader::Version6(ip) => UdpHeader::with_ipv6_checksum(REMOTE_PORT, port, ip, payload),
+    }
+    .unwrap();
+
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    udp.write(&mut packet).unwrap();
+    packet.extend_from_slice(payload);
+    packet
+}
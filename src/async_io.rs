@@ -0,0 +1,202 @@
+//! Async front end to the stack.
+//!
+//! Instead of blocking on the interface's condvars, the futures and `AsyncRead`/`AsyncWrite`
+//! implementations here register their task's `Waker` with the socket or listener they are
+//! waiting on, and whatever drives the interface (`packet_loop`, or a `Simulation`) wakes it when
+//! that socket or listener makes progress.
+
+use crate::{tcp, Interface, InterfaceHandle, Quad, Socket, TcpListener, TcpStream};
+use futures_io::{AsyncRead, AsyncWrite};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+/// Tasks waiting on a socket or listener.
+#[derive(Default)]
+pub(crate) struct Wakers {
+    read: Option<Waker>,
+    write: Option<Waker>,
+}
+
+fn register(slot: &mut Option<Waker>, waker: &Waker) {
+    match slot {
+        Some(w) if w.will_wake(waker) => {}
+        Some(w) => w.clone_from(waker),
+        None => *slot = Some(waker.clone()),
+    }
+}
+
+impl Wakers {
+    /// Takes out the wakers of the tasks that can now make progress.
+    pub(crate) fn take(&mut self, a: tcp::Available) -> Vec<Waker> {
+        let mut woken = Vec::new();
+        if a.contains(tcp::Available::READ) {
+            woken.extend(self.read.take());
+        }
+        if a.contains(tcp::Available::WRITE) {
+            woken.extend(self.write.take());
+        }
+        woken
+    }
+}
+
+/// Future returned by [`TcpListener::accept_async`].
+pub struct Accept<'a> {
+    listener: &'a mut TcpListener,
+}
+
+impl Future for Accept<'_> {
+    type Output = io::Result<TcpStream>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let listener = &*self.get_mut().listener;
+        let mut backlog = listener.l.backlog.lock().unwrap();
+        if let Some((quad, sock)) = backlog.pop_front() {
+            return Poll::Ready(Ok(TcpStream::new(quad, sock, listener.h.clone())));
+        }
+
+        register(
+            &mut listener.l.waiters.lock().unwrap().tasks.read,
+            cx.waker(),
+        );
+        Poll::Pending
+    }
+}
+
+/// Future returned by [`Interface::connect_async`].
+///
+/// Dropping it before it completes abandons the connection attempt.
+pub struct Connect {
+    h: InterfaceHandle,
+    /// the connection being established, until the future completes
+    sock: Option<(Quad, Arc<Socket>)>,
+    /// why the connection could not even be started
+    error: Option<io::Error>,
+}
+
+impl Future for Connect {
+    type Output = io::Result<TcpStream>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(e) = this.error.take() {
+            return Poll::Ready(Err(e));
+        }
+        let (quad, sock) = this.sock.take().expect("Connect polled after completion");
+
+        let c = sock.conn.lock().unwrap();
+        match crate::poll_connect(&c) {
+            Some(r) => {
+                drop(c);
+                sock.waiters.lock().unwrap().tasks = Wakers::default();
+                match r {
+                    Ok(()) => Poll::Ready(Ok(TcpStream::new(quad, sock, this.h.clone()))),
+                    Err(e) => {
+                        this.h.manager.lock().unwrap().forget(&quad, &sock);
+                        Poll::Ready(Err(e))
+                    }
+                }
+            }
+            None => {
+                register(&mut sock.waiters.lock().unwrap().tasks.write, cx.waker());
+                drop(c);
+                this.sock = Some((quad, sock));
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Connect {
+    fn drop(&mut self) {
+        if let Some((quad, sock)) = self.sock.take() {
+            self.h.manager.lock().unwrap().forget(&quad, &sock);
+        }
+    }
+}
+
+impl Interface {
+    /// Like [`Interface::connect`], but resolves once the connection is established instead of
+    /// blocking the calling thread.
+    pub fn connect_async(
+        &mut self,
+        local: impl Into<IpAddr>,
+        addr: impl Into<SocketAddr>,
+    ) -> Connect {
+        let h = self.ih.as_mut().unwrap().clone();
+        let r = h.manager.lock().unwrap().connect(local.into(), addr.into());
+        let (sock, error) = match r {
+            Ok(sock) => (Some(sock), None),
+            Err(e) => (None, Some(e)),
+        };
+        Connect { h, sock, error }
+    }
+}
+
+impl TcpListener {
+    /// Like [`TcpListener::accept`], but resolves once a connection arrives instead of blocking
+    /// the calling thread.
+    pub fn accept_async(&mut self) -> Accept<'_> {
+        Accept { listener: self }
+    }
+}
+
+impl TcpStream {
+    /// Runs `f` against this stream's connection, registering the task in the slot picked by
+    /// `wakers` if `f` cannot make progress yet.
+    fn poll_with<T>(
+        &self,
+        cx: &mut Context<'_>,
+        wakers: fn(&mut Wakers) -> &mut Option<Waker>,
+        f: impl FnOnce(&mut tcp::Connection) -> Option<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let mut c = self.sock.conn.lock().unwrap();
+        match f(&mut c) {
+            Some(r) => Poll::Ready(r),
+            None => {
+                // still holding the connection, so packet_loop cannot slip in between
+                let mut waiters = self.sock.waiters.lock().unwrap();
+                register(wakers(&mut waiters.tasks), cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_with(cx, |w| &mut w.read, |c| c.try_read(buf))
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_with(cx, |w| &mut w.write, |c| c.try_write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_with(cx, |w| &mut w.write, |c| c.try_flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_with(
+            cx,
+            |w| &mut w.write,
+            |c| {
+                c.close();
+                c.try_flush()
+            },
+        )
+    }
+}
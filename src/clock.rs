@@ -0,0 +1,27 @@
+//! The time that TCP timers and impaired links go by.
+//!
+//! Normally this is the wall clock. A `Simulation` replaces it with a virtual clock for the
+//! thread it runs on, which only moves when the simulation says so.
+
+use std::cell::Cell;
+use std::time::Instant;
+
+thread_local! {
+    static VIRTUAL: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+pub(crate) fn now() -> Instant {
+    VIRTUAL.with(|v| v.get()).unwrap_or_else(Instant::now)
+}
+
+/// Whether a simulation has replaced the wall clock on this thread.
+pub(crate) fn is_virtual() -> bool {
+    VIRTUAL.with(|v| v.get()).is_some()
+}
+
+/// Makes `now` return `t` on this thread, or the wall clock again if `t` is `None`.
+///
+/// Returns what the clock was set to before.
+pub(crate) fn set(t: Option<Instant>) -> Option<Instant> {
+    VIRTUAL.with(|v| v.replace(t))
+}
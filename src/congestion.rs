@@ -0,0 +1,74 @@
+//! Congestion control (RFC 5681): how much a connection may have in flight, besides what the
+//! peer's window allows.
+
+/// The congestion control connections on an interface use, as set with
+/// `InterfaceBuilder::congestion_control`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CongestionControl {
+    /// Nothing but the peer's window limits what is in flight.
+    #[default]
+    Off,
+    /// Slow start and congestion avoidance, starting over from a single segment after a
+    /// retransmission timeout (RFC 5681 S3.1).
+    Reno,
+}
+
+/// The congestion window of a connection.
+pub(crate) struct Window {
+    algorithm: CongestionControl,
+    /// congestion window, in bytes
+    cwnd: usize,
+    /// slow start threshold, in bytes
+    ssthresh: usize,
+}
+
+impl Window {
+    pub(crate) fn new(algorithm: CongestionControl, mss: usize) -> Self {
+        Window {
+            algorithm,
+            cwnd: initial_window(mss),
+            // as high as it goes, until the first loss says otherwise
+            ssthresh: usize::MAX,
+        }
+    }
+
+    /// How many bytes may be in flight.
+    pub(crate) fn get(&self) -> usize {
+        match self.algorithm {
+            CongestionControl::Off => usize::MAX,
+            CongestionControl::Reno => self.cwnd,
+        }
+    }
+
+    /// Opens the window for `acked` bytes that were newly acknowledged (RFC 5681 S3.1).
+    pub(crate) fn on_ack(&mut self, acked: usize, mss: usize) {
+        if acked == 0 {
+            return;
+        }
+        let grow = if self.cwnd < self.ssthresh {
+            std::cmp::min(acked, mss)
+        } else {
+            std::cmp::max(1, mss * mss / self.cwnd)
+        };
+        self.cwnd = self.cwnd.saturating_add(grow);
+    }
+
+    /// Closes the window after a retransmission timeout, with `in_flight` bytes outstanding
+    /// (RFC 5681 S3.1, equation 4).
+    pub(crate) fn on_timeout(&mut self, in_flight: usize, mss: usize) {
+        self.ssthresh = std::cmp::max(in_flight / 2, 2 * mss);
+        self.cwnd = mss;
+    }
+}
+
+/// The initial window for segments of `mss` bytes (RFC 5681 S3.1).
+fn initial_window(mss: usize) -> usize {
+    if mss > 2190 {
+        2 * mss
+    } else if mss > 1095 {
+        3 * mss
+    } else {
+        4 * mss
+    }
+}
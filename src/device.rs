@@ -0,0 +1,382 @@
+//! Network devices that `packet_loop` exchanges IP packets over.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Something that carries raw IP packets in and out of the stack.
+///
+/// The `Interface` hands the device to its `packet_loop`, which is the only one to use it.
+pub trait NetDevice: Send + 'static {
+    /// Waits up to `timeout` for a packet to arrive, and copies it into `buf`.
+    ///
+    /// Returns `Ok(None)` if nothing arrived in time. `buf` is at least `mtu()` bytes long.
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>>;
+
+    /// Sends a single packet of at most `mtu()` bytes.
+    fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+
+    /// Largest packet the device carries, IP header included.
+    fn mtu(&self) -> usize;
+
+    /// Whether the checksums of the packet `recv` last returned are known to be right already,
+    /// e.g. because hardware checked them, so that the stack need not.
+    fn checksums_verified(&self) -> bool {
+        false
+    }
+
+    /// What the packet `recv` last returned is, if the device was told: its EtherType, e.g.
+    /// 0x0800 for IPv4. Otherwise the stack goes by the version in the IP header.
+    fn ethertype(&self) -> Option<u16> {
+        None
+    }
+}
+
+/// EtherTypes of the packets the stack handles.
+pub(crate) const ETHERTYPE_IPV4: u16 = 0x0800;
+pub(crate) const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// Length of the `tun_pi` in front of every packet on a tun device opened without
+/// `IFF_NO_PI`: flags, then the EtherType of the packet.
+const PI_LEN: usize = 4;
+
+/// Set in the packet info flags if the packet did not fit in what we read it into.
+const PI_TRUNCATED: u16 = libc::TUN_PKT_STRIP as u16;
+
+/// Length of the `virtio_net_hdr` in front of every packet on a tun device opened with
+/// `IFF_VNET_HDR`.
+const VNET_HDR_LEN: usize = 10;
+
+/// Flags in the vnet header: the packet comes from this host, and its checksum is left for
+/// whoever sends it on to fill in (`VIRTIO_NET_HDR_F_NEEDS_CSUM`), or the checksum was already
+/// checked (`VIRTIO_NET_HDR_F_DATA_VALID`).
+const VNET_NEEDS_CSUM: u8 = 1;
+const VNET_DATA_VALID: u8 = 2;
+
+/// What the kernel puts in front of every packet on a tun device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Prefix {
+    None,
+    PacketInfo,
+    VnetHeader,
+}
+
+impl Prefix {
+    fn len(self) -> usize {
+        match self {
+            Prefix::None => 0,
+            Prefix::PacketInfo => PI_LEN,
+            Prefix::VnetHeader => VNET_HDR_LEN,
+        }
+    }
+}
+
+/// A Linux tun device, which carries packets to and from the kernel.
+pub struct TunDevice {
+    /// the open device, whether `tun_tap` opened it or we did
+    fd: Box<dyn AsRawFd + Send>,
+    mtu: usize,
+    prefix: Prefix,
+    /// the vnet header of the last packet received vouched for its checksums
+    verified: bool,
+    /// the EtherType the packet info of the last packet received gave
+    ethertype: Option<u16>,
+}
+
+impl TunDevice {
+    /// Opens the tun device called `name`, creating it if it does not exist yet.
+    ///
+    /// Needs CAP_NET_ADMIN.
+    pub fn new(name: &str) -> io::Result<Self> {
+        let iface = tun_tap::Iface::without_packet_info(name, tun_tap::Mode::Tun)?;
+        TunDevice::from_iface(iface, Prefix::None)
+    }
+
+    /// Like `new`, but has the kernel put packet info in front of every packet, which says
+    /// whether it is IPv4 or IPv6 (or something else altogether).
+    pub fn with_packet_info(name: &str) -> io::Result<Self> {
+        let iface = tun_tap::Iface::new(name, tun_tap::Mode::Tun)?;
+        TunDevice::from_iface(iface, Prefix::PacketInfo)
+    }
+
+    fn from_iface(iface: tun_tap::Iface, prefix: Prefix) -> io::Result<Self> {
+        let mtu = read_mtu(iface.name())?;
+        Ok(TunDevice {
+            fd: Box::new(iface),
+            mtu,
+            prefix,
+            verified: false,
+            ethertype: None,
+        })
+    }
+
+    /// Like `new`, but has the kernel put a vnet header in front of every packet, and leaves
+    /// checksums the kernel has already checked (or has yet to fill in, for packets that never
+    /// left the host) to the kernel.
+    pub fn with_vnet_header(name: &str) -> io::Result<Self> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface name too long",
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+        let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (c, b) in req.ifr_name.iter_mut().zip(name.bytes()) {
+            *c = b as libc::c_char;
+        }
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI | libc::IFF_VNET_HDR) as _;
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // the kernel may then hand us packets with checksums still to be filled in
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETOFFLOAD, libc::TUN_F_CSUM) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let name: Vec<u8> = req
+            .ifr_name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+        let mtu = read_mtu(&String::from_utf8_lossy(&name))?;
+        Ok(TunDevice {
+            fd: Box::new(file),
+            mtu,
+            prefix: Prefix::VnetHeader,
+            verified: false,
+            ethertype: None,
+        })
+    }
+
+    /// Takes note that the kernel's MTU for the device was changed to `mtu` since it was opened.
+    pub(crate) fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+    }
+}
+
+/// The MTU the kernel has for the interface called `name`.
+fn read_mtu(name: &str) -> io::Result<usize> {
+    std::fs::read_to_string(format!("/sys/class/net/{}/mtu", name))?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Turns what a `read`/`write` style call returned into a byte count.
+fn byte_count(n: isize) -> io::Result<usize> {
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+impl NetDevice for TunDevice {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let fd = self.fd.as_raw_fd();
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = std::cmp::max(1, timeout.as_millis()) as libc::c_int;
+        match unsafe { libc::poll(&mut pfd, 1, timeout) } {
+            -1 => match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => return Ok(None),
+                e => return Err(e),
+            },
+            0 => return Ok(None),
+            _ => {}
+        }
+
+        let mut hdr = [0u8; VNET_HDR_LEN];
+        let hdr = &mut hdr[..self.prefix.len()];
+        let iov = [
+            libc::iovec {
+                iov_base: hdr.as_mut_ptr().cast(),
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            },
+        ];
+        let n = byte_count(unsafe { libc::readv(fd, iov.as_ptr(), 2) })?;
+        let n = n.checked_sub(hdr.len()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "packet shorter than the header in front of it",
+            )
+        })?;
+        match self.prefix {
+            Prefix::None => {}
+            Prefix::PacketInfo => {
+                if u16::from_be_bytes([hdr[0], hdr[1]]) & PI_TRUNCATED != 0 {
+                    // the rest of it is lost, so there is nothing to hand on
+                    return Ok(None);
+                }
+                self.ethertype = Some(u16::from_be_bytes([hdr[2], hdr[3]]));
+            }
+            Prefix::VnetHeader => {
+                self.verified = hdr[0] & (VNET_NEEDS_CSUM | VNET_DATA_VALID) != 0;
+            }
+        }
+        Ok(Some(n))
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        let fd = self.fd.as_raw_fd();
+        // our checksums are complete, so there is nothing for a vnet header to say
+        let mut hdr = [0u8; VNET_HDR_LEN];
+        let hdr = &mut hdr[..self.prefix.len()];
+        if self.prefix == Prefix::PacketInfo {
+            let ethertype = match packet.first().map(|b| b >> 4) {
+                Some(4) => ETHERTYPE_IPV4,
+                Some(6) => ETHERTYPE_IPV6,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "neither IPv4 nor IPv6",
+                    ))
+                }
+            };
+            hdr[2..4].copy_from_slice(&ethertype.to_be_bytes());
+        }
+        let iov = [
+            libc::iovec {
+                iov_base: hdr.as_mut_ptr().cast(),
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: packet.as_ptr() as *mut _,
+                iov_len: packet.len(),
+            },
+        ];
+        byte_count(unsafe { libc::writev(fd, iov.as_ptr(), 2) }).map(drop)
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn checksums_verified(&self) -> bool {
+        self.verified
+    }
+
+    fn ethertype(&self) -> Option<u16> {
+        self.ethertype
+    }
+}
+
+/// A Linux tap device, which carries Ethernet frames to and from the kernel.
+///
+/// Frames are not IP packets: put an `EthernetDevice` over it to run the stack on it.
+pub struct TapDevice(TunDevice);
+
+impl TapDevice {
+    /// Opens the tap device called `name`, creating it if it does not exist yet.
+    ///
+    /// Needs CAP_NET_ADMIN.
+    pub fn new(name: &str) -> io::Result<Self> {
+        let iface = tun_tap::Iface::without_packet_info(name, tun_tap::Mode::Tap)?;
+        let mtu = read_mtu(iface.name())? + crate::ethernet::HEADER_LEN;
+        Ok(TapDevice(TunDevice {
+            fd: Box::new(iface),
+            mtu,
+            prefix: Prefix::None,
+            verified: false,
+            ethertype: None,
+        }))
+    }
+}
+
+impl NetDevice for TapDevice {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        self.0.recv(buf, timeout)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.0.send(frame)
+    }
+
+    /// Largest frame the device carries, Ethernet header included.
+    fn mtu(&self) -> usize {
+        self.0.mtu()
+    }
+}
+
+/// One end of an in-memory link between two interfaces in the same process.
+///
+/// Useful for running a client and a server over this stack without a tun device, and so
+/// without CAP_NET_ADMIN. Packets sent after the other end has gone away are dropped, as if the
+/// cable had been pulled. Packets larger than the MTU are refused with
+/// `io::ErrorKind::InvalidInput`.
+pub struct PipeDevice {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+    mtu: usize,
+}
+
+impl PipeDevice {
+    /// Creates two devices connected to each other, both with the given MTU.
+    ///
+    /// Panics if `mtu` is below the 68 bytes every IPv4 link must carry (RFC 791).
+    pub fn pair(mtu: usize) -> (PipeDevice, PipeDevice) {
+        assert!(mtu >= 68, "MTU of {} is too small for IPv4", mtu);
+        let (atx, brx) = mpsc::channel();
+        let (btx, arx) = mpsc::channel();
+        (
+            PipeDevice {
+                tx: atx,
+                rx: arx,
+                mtu,
+            },
+            PipeDevice {
+                tx: btx,
+                rx: brx,
+                mtu,
+            },
+        )
+    }
+}
+
+impl NetDevice for PipeDevice {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(packet) => {
+                // like a read from a tun device, whatever does not fit is cut off
+                let n = std::cmp::min(packet.len(), buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Ok(Some(n))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                // nothing will ever arrive, but packet_loop still has timers to run
+                std::thread::sleep(timeout);
+                Ok(None)
+            }
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        if packet.len() > self.mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet larger than the MTU",
+            ));
+        }
+        let _ = self.tx.send(packet.to_vec());
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
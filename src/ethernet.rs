@@ -0,0 +1,347 @@
+//! Ethernet II framing and ARP (RFC 894, RFC 826), for running the stack on a tap device, as a
+//! host on a LAN or a Linux bridge.
+//!
+//! An `EthernetDevice` sits between the interface and a device that carries frames. It strips
+//! and adds Ethernet headers, answers ARP requests for its address, and resolves the hardware
+//! address of each next hop before sending to it, holding packets back meanwhile.
+//!
+//! ```no_run
+//! use std::net::Ipv4Addr;
+//! use trust::{EthernetDevice, Interface, TapDevice};
+//!
+//! let tap = TapDevice::new("tap0")?;
+//! let mac = [0x02, 0, 0, 0, 0, 1];
+//! let dev = EthernetDevice::new(tap, mac, Ipv4Addr::new(192, 168, 0, 2), 24)
+//!     .with_gateway(Ipv4Addr::new(192, 168, 0, 1));
+//! let iface = Interface::with_device(dev)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::clock;
+use crate::device::{NetDevice, ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+/// Length of an Ethernet II header: destination, source and EtherType.
+pub(crate) const HEADER_LEN: usize = 14;
+
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+/// Length of an ARP packet for IPv4 over Ethernet.
+const ARP_LEN: usize = 28;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+/// How long a neighbor's hardware address is good for before we ask again (Linux's
+/// `base_reachable_time`).
+const REACHABLE_TIME: Duration = Duration::from_secs(30);
+
+/// How long we wait for an answer before asking again, and how many times we ask before giving
+/// up on a neighbor (RFC 1122 S2.3.2.1 asks for no more than one request a second).
+const RETRANS_TIME: Duration = Duration::from_secs(1);
+const MAX_REQUESTS: u32 = 3;
+
+/// Most packets held back for a neighbor we are still resolving.
+const MAX_QUEUED: usize = 16;
+
+/// Most neighbors we keep track of at once.
+const MAX_NEIGHBORS: usize = 256;
+
+/// What we know of the hardware address of a host on the link.
+enum Neighbor {
+    /// we asked, and are waiting for an answer; `queue` holds what we have to send it
+    Incomplete {
+        queue: VecDeque<Vec<u8>>,
+        asked: u32,
+        ask_again: Instant,
+    },
+    Reachable {
+        mac: [u8; 6],
+        expires: Instant,
+    },
+}
+
+/// Wraps a device that carries Ethernet frames, such as a `TapDevice`, so that the stack can
+/// send and receive IP packets over it.
+///
+/// Only IPv4 next hops are resolved; IPv6 packets are received, but only sent to multicast
+/// groups, since we do not do neighbor discovery.
+pub struct EthernetDevice<D> {
+    inner: D,
+    mac: [u8; 6],
+    addr: Ipv4Addr,
+    prefix_len: u8,
+    gateway: Option<Ipv4Addr>,
+    neighbors: HashMap<Ipv4Addr, Neighbor>,
+    /// a frame, on its way in
+    frame: Vec<u8>,
+    /// the EtherType of the last packet `recv` returned
+    ethertype: Option<u16>,
+}
+
+impl<D: NetDevice> EthernetDevice<D> {
+    /// Runs the stack over `inner` as the host with hardware address `mac` and IPv4 address
+    /// `addr`, on a subnet of `prefix_len` bits.
+    ///
+    /// Panics if `prefix_len` is over 32.
+    pub fn new(inner: D, mac: [u8; 6], addr: Ipv4Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 32, "prefix of {} bits", prefix_len);
+        EthernetDevice {
+            frame: vec![0; inner.mtu()],
+            inner,
+            mac,
+            addr,
+            prefix_len,
+            gateway: None,
+            neighbors: HashMap::new(),
+            ethertype: None,
+        }
+    }
+
+    /// Sends packets for hosts outside the subnet through the router at `gateway`, rather than
+    /// dropping them.
+    pub fn with_gateway(mut self, gateway: Ipv4Addr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    fn netmask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0)
+    }
+
+    fn on_link(&self, ip: Ipv4Addr) -> bool {
+        (u32::from(ip) ^ u32::from(self.addr)) & self.netmask() == 0
+    }
+
+    /// Whether `ip` is the broadcast address of the subnet, or of every subnet.
+    fn is_broadcast(&self, ip: Ipv4Addr) -> bool {
+        ip.is_broadcast()
+            || (self.prefix_len < 31 && self.on_link(ip) && u32::from(ip) | self.netmask() == !0)
+    }
+
+    fn send_frame(&mut self, dst: [u8; 6], ethertype: u16, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.inner.send(&frame)
+    }
+
+    fn send_arp(&mut self, op: u16, dst: [u8; 6], tha: [u8; 6], tpa: Ipv4Addr) -> io::Result<()> {
+        let mut arp = Vec::with_capacity(ARP_LEN);
+        arp.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+        arp.extend_from_slice(&op.to_be_bytes());
+        arp.extend_from_slice(&self.mac);
+        arp.extend_from_slice(&self.addr.octets());
+        arp.extend_from_slice(&tha);
+        arp.extend_from_slice(&tpa.octets());
+        self.send_frame(dst, ETHERTYPE_ARP, &arp)
+    }
+
+    /// Asks again about neighbors that have not answered, and gives up on those that never
+    /// will.
+    fn on_timers(&mut self) -> io::Result<()> {
+        let now = clock::now();
+        let mut ask = Vec::new();
+        self.neighbors.retain(|ip, n| match n {
+            Neighbor::Incomplete {
+                queue,
+                asked,
+                ask_again,
+            } if *ask_again <= now => {
+                if *asked >= MAX_REQUESTS {
+                    eprintln!(
+                        "{} did not answer ARP, dropping {} packets",
+                        ip,
+                        queue.len()
+                    );
+                    return false;
+                }
+                *asked += 1;
+                *ask_again = now + RETRANS_TIME;
+                ask.push(*ip);
+                true
+            }
+            _ => true,
+        });
+        for ip in ask {
+            self.send_arp(ARP_REQUEST, BROADCAST, [0; 6], ip)?;
+        }
+        Ok(())
+    }
+
+    /// Records that `ip` is at `mac`, and sends whatever was waiting for it.
+    fn learn(&mut self, ip: Ipv4Addr, mac: [u8; 6]) -> io::Result<()> {
+        let reachable = Neighbor::Reachable {
+            mac,
+            expires: clock::now() + REACHABLE_TIME,
+        };
+        if let Some(Neighbor::Incomplete { queue, .. }) = self.neighbors.insert(ip, reachable) {
+            for packet in queue {
+                self.send_frame(mac, ETHERTYPE_IPV4, &packet)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes in an ARP packet (RFC 826, "Packet Reception").
+    fn on_arp(&mut self, arp: &[u8]) -> io::Result<()> {
+        if arp.len() < ARP_LEN || arp[..6] != [0, 1, 0x08, 0x00, 6, 4] {
+            return Ok(());
+        }
+        let op = u16::from_be_bytes([arp[6], arp[7]]);
+        let sha: [u8; 6] = arp[8..14].try_into().unwrap();
+        let spa = Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]);
+        let tpa = Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]);
+        if sha == self.mac || sha[0] & 1 != 0 {
+            return Ok(());
+        }
+
+        // a host probing for an address it wants (RFC 5227) has none yet to remember
+        let known = self.neighbors.contains_key(&spa);
+        if !spa.is_unspecified() && (known || tpa == self.addr) {
+            if !known && self.neighbors.len() >= MAX_NEIGHBORS {
+                self.forget_expired();
+            }
+            if known || self.neighbors.len() < MAX_NEIGHBORS {
+                self.learn(spa, sha)?;
+            }
+        }
+        if tpa == self.addr && op == ARP_REQUEST {
+            self.send_arp(ARP_REPLY, sha, sha, spa)?;
+        }
+        Ok(())
+    }
+
+    fn forget_expired(&mut self) {
+        let now = clock::now();
+        self.neighbors
+            .retain(|_, n| !matches!(n, Neighbor::Reachable { expires, .. } if *expires <= now));
+    }
+
+    /// Sends an IPv4 packet to `dst`, or to the router on the way there, once we know the
+    /// hardware address to send it to.
+    fn send_v4(&mut self, dst: Ipv4Addr, packet: &[u8]) -> io::Result<()> {
+        if self.is_broadcast(dst) {
+            return self.send_frame(BROADCAST, ETHERTYPE_IPV4, packet);
+        }
+        if dst.is_multicast() {
+            // the low 23 bits of the group go into 01:00:5e:00:00:00 (RFC 1112 S6.4)
+            let g = dst.octets();
+            let mac = [0x01, 0x00, 0x5e, g[1] & 0x7f, g[2], g[3]];
+            return self.send_frame(mac, ETHERTYPE_IPV4, packet);
+        }
+        let next_hop = match self.gateway {
+            _ if self.on_link(dst) => dst,
+            Some(gateway) => gateway,
+            None => {
+                eprintln!("no route to {}, dropping packet", dst);
+                return Ok(());
+            }
+        };
+
+        let now = clock::now();
+        match self.neighbors.get_mut(&next_hop) {
+            Some(Neighbor::Reachable { mac, expires }) if *expires > now => {
+                let mac = *mac;
+                self.send_frame(mac, ETHERTYPE_IPV4, packet)
+            }
+            Some(Neighbor::Incomplete { queue, .. }) => {
+                if queue.len() < MAX_QUEUED {
+                    queue.push_back(packet.to_vec());
+                }
+                Ok(())
+            }
+            _ => {
+                if self.neighbors.len() >= MAX_NEIGHBORS {
+                    self.forget_expired();
+                    if self.neighbors.len() >= MAX_NEIGHBORS {
+                        eprintln!("neighbor table full, dropping packet to {}", dst);
+                        return Ok(());
+                    }
+                }
+                self.neighbors.insert(
+                    next_hop,
+                    Neighbor::Incomplete {
+                        queue: VecDeque::from([packet.to_vec()]),
+                        asked: 1,
+                        ask_again: now + RETRANS_TIME,
+                    },
+                );
+                self.send_arp(ARP_REQUEST, BROADCAST, [0; 6], next_hop)
+            }
+        }
+    }
+}
+
+impl<D: NetDevice> NetDevice for EthernetDevice<D> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let deadline = clock::now() + timeout;
+        loop {
+            self.on_timers()?;
+            let wait = deadline.saturating_duration_since(clock::now());
+            let n = match self.inner.recv(&mut self.frame, wait)? {
+                Some(n) if n >= HEADER_LEN => n,
+                Some(_) => continue,
+                None => return Ok(None),
+            };
+
+            // frames for other hosts on the bridge are none of our business
+            let dst = &self.frame[..6];
+            if dst != self.mac && dst[0] & 1 == 0 {
+                continue;
+            }
+            let payload = &self.frame[HEADER_LEN..n];
+            match u16::from_be_bytes([self.frame[12], self.frame[13]]) {
+                ethertype @ (ETHERTYPE_IPV4 | ETHERTYPE_IPV6) => {
+                    self.ethertype = Some(ethertype);
+                    buf[..payload.len()].copy_from_slice(payload);
+                    return Ok(Some(payload.len()));
+                }
+                ETHERTYPE_ARP => {
+                    let arp = payload.to_vec();
+                    self.on_arp(&arp)?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.on_timers()?;
+        match crate::ip::parse(packet).map(|p| p.dst) {
+            Ok(IpAddr::V4(dst)) => self.send_v4(dst, packet),
+            Ok(IpAddr::V6(dst)) if dst.is_multicast() => {
+                // the low 32 bits of the group go into 33:33:00:00:00:00 (RFC 2464 S7)
+                let g = dst.octets();
+                let mac = [0x33, 0x33, g[12], g[13], g[14], g[15]];
+                self.send_frame(mac, ETHERTYPE_IPV6, packet)
+            }
+            Ok(IpAddr::V6(dst)) => {
+                eprintln!("no neighbor discovery for {}, dropping packet", dst);
+                Ok(())
+            }
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        }
+    }
+
+    /// The device's MTU, less the Ethernet header.
+    fn mtu(&self) -> usize {
+        self.inner.mtu() - HEADER_LEN
+    }
+
+    fn checksums_verified(&self) -> bool {
+        self.inner.checksums_verified()
+    }
+
+    fn ethertype(&self) -> Option<u16> {
+        self.ethertype
+    }
+}
@@ -0,0 +1,248 @@
+//! Putting IPv4 fragments back together into the datagrams they were cut from (RFC 791 S3.2,
+//! RFC 815).
+//!
+//! Fragments that overlap each other are not to be trusted, since they are how firewalls get
+//! fooled (RFC 1858): they throw away the whole datagram. Incomplete datagrams are dropped after
+//! `TIMEOUT`, or to make room once `MAX_BYTES` are held.
+
+use crate::clock;
+use crate::ip::{self, Fragment};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How long the fragments of a datagram wait for the rest (Linux's default; RFC 1122 S3.3.2
+/// suggests 60 to 120 seconds).
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most bytes we hold in fragments of incomplete datagrams, across all of them.
+const MAX_BYTES: usize = 256 * 1024;
+
+/// Most datagrams we put together at once.
+const MAX_DATAGRAMS: usize = 64;
+
+/// Largest datagram there can be, header and all.
+const MAX_DATAGRAM_LEN: usize = u16::MAX as usize;
+
+/// The don't fragment flag, in the field it shares with the fragment offset.
+const DONT_FRAGMENT: u16 = 0x4000;
+
+/// What tells the fragments of one datagram apart from those of another (RFC 791 S3.2).
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    src: IpAddr,
+    dst: IpAddr,
+    id: u16,
+    protocol: u8,
+}
+
+/// A datagram whose fragments are coming in.
+struct Datagram {
+    /// the IP header of the first fragment, once it has arrived
+    header: Option<Vec<u8>>,
+    /// the payloads of the fragments so far, by offset; no two overlap
+    parts: BTreeMap<usize, Vec<u8>>,
+    /// length of the whole payload, once the last fragment has arrived
+    len: Option<usize>,
+    /// bytes held, counted against `MAX_BYTES`
+    size: usize,
+    /// when we give up waiting for the rest
+    expires: Instant,
+}
+
+impl Datagram {
+    /// Adds the payload of a fragment at `offset`.
+    ///
+    /// Fails if the fragment overlaps one we already have, or runs past the end of the datagram.
+    /// An exact copy of one we have is let through, and ignored.
+    fn add(&mut self, offset: usize, payload: &[u8]) -> Result<(), ()> {
+        let end = offset + payload.len();
+        if self.len.is_some_and(|len| end > len) {
+            return Err(());
+        }
+        if let Some((&at, part)) = self.parts.range(..=offset).next_back() {
+            if at == offset && part[..] == *payload {
+                return Ok(());
+            }
+            if at + part.len() > offset {
+                return Err(());
+            }
+        }
+        if let Some((&at, _)) = self.parts.range(offset..).next() {
+            if at < end {
+                return Err(());
+            }
+        }
+        self.size += payload.len();
+        self.parts.insert(offset, payload.to_vec());
+        Ok(())
+    }
+
+    /// The whole datagram, if all of it is here.
+    fn assemble(&self) -> Option<Vec<u8>> {
+        let header = self.header.as_ref()?;
+        let len = self.len?;
+        // the parts do not overlap and all lie within the datagram, so they cover it if their
+        // lengths add up
+        if self.parts.values().map(Vec::len).sum::<usize>() != len {
+            return None;
+        }
+
+        let mut packet = Vec::with_capacity(header.len() + len);
+        packet.extend_from_slice(header);
+        for part in self.parts.values() {
+            packet.extend_from_slice(part);
+        }
+        // the header now describes a whole datagram rather than its first fragment
+        let total_len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        let flags = u16::from_be_bytes([packet[6], packet[7]]) & DONT_FRAGMENT;
+        packet[6..8].copy_from_slice(&flags.to_be_bytes());
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let sum = ip::checksum(&[&packet[..header.len()]]);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        Some(packet)
+    }
+
+    /// The first fragment as it arrived, if it has.
+    fn first(&self) -> Option<Vec<u8>> {
+        let mut packet = self.header.clone()?;
+        packet.extend_from_slice(self.parts.get(&0)?);
+        Some(packet)
+    }
+}
+
+/// The fragments of the datagrams an interface is putting back together.
+#[derive(Default)]
+pub(crate) struct Reassembler {
+    datagrams: HashMap<Key, Datagram>,
+    /// bytes held across all of `datagrams`
+    size: usize,
+}
+
+impl Reassembler {
+    /// Takes in `raw`, parsed as `packet`, which is a fragment.
+    ///
+    /// Returns the whole datagram once this was the last of its fragments to arrive. Fails with
+    /// `io::ErrorKind::InvalidData` if the fragment is malformed, or does not fit with the
+    /// others; in the latter case the datagram is dropped.
+    pub(crate) fn add(
+        &mut self,
+        raw: &[u8],
+        packet: &ip::Packet<'_>,
+        fragment: Fragment,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let header_len = (raw[0] & 0xf) as usize * 4;
+        let end = fragment.offset + packet.payload.len();
+        // all but the last fragment carry a multiple of eight bytes (RFC 791 S3.2)
+        if fragment.more && !packet.payload.len().is_multiple_of(8) {
+            return Err(invalid("fragment of a length that is not a multiple of 8"));
+        }
+        if fragment.more && packet.payload.is_empty() {
+            return Err(invalid("empty fragment"));
+        }
+        if header_len + end > MAX_DATAGRAM_LEN {
+            return Err(invalid("fragment past the largest datagram there can be"));
+        }
+
+        let key = Key {
+            src: packet.src,
+            dst: packet.dst,
+            id: fragment.id,
+            protocol: packet.protocol,
+        };
+        if !self.datagrams.contains_key(&key) {
+            self.make_room(packet.payload.len())?;
+            self.datagrams.insert(
+                key,
+                Datagram {
+                    header: None,
+                    parts: BTreeMap::new(),
+                    len: None,
+                    size: 0,
+                    expires: clock::now() + TIMEOUT,
+                },
+            );
+        } else if self.size + packet.payload.len() > MAX_BYTES {
+            self.drop_datagram(&key);
+            return Err(invalid("no room for the fragments of another datagram"));
+        }
+
+        let d = self.datagrams.get_mut(&key).unwrap();
+        let before = d.size;
+        let mut ok = d.add(fragment.offset, packet.payload).is_ok();
+        if !fragment.more {
+            // the last fragment, which says how long the datagram is
+            let fits = d.parts.iter().next_back().map_or(0, |(at, p)| at + p.len()) <= end;
+            ok &= fits && d.len.is_none_or(|len| len == end);
+            d.len = Some(end);
+        }
+        if fragment.offset == 0 && d.header.is_none() {
+            d.size += header_len;
+            d.header = Some(raw[..header_len].to_vec());
+        }
+        self.size += d.size - before;
+        if !ok {
+            self.drop_datagram(&key);
+            return Err(invalid(
+                "fragment does not fit with the others of its datagram",
+            ));
+        }
+
+        let whole = d.assemble();
+        if whole.is_some() {
+            self.drop_datagram(&key);
+        }
+        Ok(whole)
+    }
+
+    /// Drops the datagrams that have waited too long for the rest of their fragments, and
+    /// returns the first fragment of each, where it had come.
+    ///
+    /// They come in the order the datagrams started arriving, so that a simulation reports them
+    /// the same way every time it is replayed.
+    pub(crate) fn expire(&mut self) -> Vec<Vec<u8>> {
+        let now = clock::now();
+        let mut expired: Vec<(Instant, Key)> = self
+            .datagrams
+            .iter()
+            .filter(|(_, d)| d.expires <= now)
+            .map(|(k, d)| (d.expires, *k))
+            .collect();
+        expired.sort_unstable();
+        expired
+            .into_iter()
+            .filter_map(|(_, k)| self.drop_datagram(&k).and_then(|d| d.first()))
+            .collect()
+    }
+
+    /// Drops the datagrams closest to expiring until there is room for one more, with `size`
+    /// bytes in it.
+    fn make_room(&mut self, size: usize) -> io::Result<()> {
+        if size > MAX_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "fragment larger than the reassembly buffer",
+            ));
+        }
+        while self.datagrams.len() >= MAX_DATAGRAMS || self.size + size > MAX_BYTES {
+            // ties broken by key rather than by where they happen to be in the map
+            let oldest = self
+                .datagrams
+                .iter()
+                .min_by_key(|(k, d)| (d.expires, **k))
+                .map(|(k, _)| *k)
+                .unwrap();
+            self.drop_datagram(&oldest);
+        }
+        Ok(())
+    }
+
+    fn drop_datagram(&mut self, key: &Key) -> Option<Datagram> {
+        let d = self.datagrams.remove(key)?;
+        self.size -= d.size;
+        Some(d)
+    }
+}
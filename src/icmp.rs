@@ -0,0 +1,271 @@
+//! ICMP and ICMPv6 (RFC 792, RFC 4443): answering pings, reporting packets we have no use for,
+//! and making sense of the errors that come back about packets we sent.
+
+use crate::ip;
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Length of the ICMP header: type, code, checksum and four bytes that depend on the type.
+const HEADER_LEN: usize = 8;
+
+/// How large an error we send may get, quoted packet and all: what every IPv4 host must accept
+/// (RFC 1812 S4.3.2.3), and the IPv6 minimum MTU (RFC 4443 S2.4).
+const MAX_ERROR_LEN_V4: usize = 576;
+const MAX_ERROR_LEN_V6: usize = 1280;
+
+/// Errors we send are limited to a burst of `RATE_BURST`, then one every `RATE_INTERVAL`
+/// (RFC 4443 S2.4(f)).
+const RATE_BURST: u32 = 50;
+const RATE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The ICMP protocol number that goes with `addr`'s family.
+pub(crate) fn protocol(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => ip::ICMP,
+        IpAddr::V6(_) => ip::ICMPV6,
+    }
+}
+
+/// An ICMP message that arrived.
+pub(crate) enum Message<'a> {
+    /// a ping, which `echo_reply` answers
+    EchoRequest,
+    /// something went wrong with the packet (partly) quoted in `packet`, which we sent
+    Error {
+        error: Error,
+        packet: ip::Packet<'a>,
+    },
+    /// the packet (partly) quoted in `packet`, which we sent, was too big for a link with an
+    /// MTU of `mtu`, or for some link if `mtu` is zero
+    TooBig { mtu: usize, packet: ip::Packet<'a> },
+    /// something we have no use for
+    Other,
+}
+
+/// What an ICMP error says about the connection it concerns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Error {
+    pub(crate) kind: io::ErrorKind,
+    /// the destination cannot be reached at all, rather than just not right now
+    /// (RFC 1122 S4.2.3.9)
+    pub(crate) hard: bool,
+}
+
+impl Error {
+    fn soft(kind: io::ErrorKind) -> Option<Self> {
+        Some(Error { kind, hard: false })
+    }
+
+    fn hard(kind: io::ErrorKind) -> Option<Self> {
+        Some(Error { kind, hard: true })
+    }
+}
+
+/// Why we dropped a packet.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Dropped {
+    /// we do not speak its protocol
+    UnknownProtocol,
+    /// nothing is listening on its port
+    ClosedPort,
+    /// it was the first fragment of a datagram whose other fragments did not all arrive in time
+    ReassemblyTimedOut,
+}
+
+/// Makes sense of the ICMP message that `packet` carries.
+///
+/// Fails with `io::ErrorKind::InvalidData` if the message is truncated or its checksum is wrong.
+pub(crate) fn parse<'a>(packet: &ip::Packet<'a>) -> io::Result<Message<'a>> {
+    let msg = packet.payload;
+    if msg.len() < HEADER_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("ICMP message of {} bytes", msg.len()),
+        ));
+    }
+    if checksum(packet.src, packet.dst, msg) != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad ICMP checksum",
+        ));
+    }
+
+    let (ty, code) = (msg[0], msg[1]);
+    let rest = [msg[4], msg[5], msg[6], msg[7]];
+    let problem = match packet.src {
+        IpAddr::V4(_) => match (ty, code) {
+            (8, _) => return Ok(Message::EchoRequest),
+            // fragmentation needed, with the next hop's MTU in the low half of the rest of the
+            // header (RFC 1191 S4)
+            (3, 4) => Some(Problem::TooBig(
+                u16::from_be_bytes([rest[2], rest[3]]) as usize
+            )),
+            (3, _) => unreachable_v4(code).map(Problem::Error),
+            (11, _) => Error::soft(io::ErrorKind::HostUnreachable).map(Problem::Error),
+            _ => None,
+        },
+        IpAddr::V6(_) => match ty {
+            128 => return Ok(Message::EchoRequest),
+            1 => unreachable_v6(code).map(Problem::Error),
+            2 => Some(Problem::TooBig(u32::from_be_bytes(rest) as usize)),
+            3 => Error::soft(io::ErrorKind::HostUnreachable).map(Problem::Error),
+            _ => None,
+        },
+    };
+    let Some(problem) = problem else {
+        return Ok(Message::Other);
+    };
+    let packet = match ip::parse_quoted(&msg[HEADER_LEN..]) {
+        // the quoted packet must have been one of ours, sent from where the error came to
+        Ok(quoted) if quoted.src == packet.dst => quoted,
+        _ => return Ok(Message::Other),
+    };
+    Ok(match problem {
+        Problem::Error(error) => Message::Error { error, packet },
+        Problem::TooBig(mtu) => Message::TooBig { mtu, packet },
+    })
+}
+
+/// What an ICMP error message is about, before we look at the packet it quotes.
+enum Problem {
+    Error(Error),
+    TooBig(usize),
+}
+
+/// The meaning of a destination unreachable code (RFC 792, RFC 1812 S5.2.7.1), as Linux has it.
+fn unreachable_v4(code: u8) -> Option<Error> {
+    use io::ErrorKind::*;
+    match code {
+        0 | 11 => Error::soft(NetworkUnreachable),
+        1 | 5 | 12 => Error::soft(HostUnreachable),
+        2 | 3 => Error::hard(ConnectionRefused),
+        6 | 9 => Error::hard(NetworkUnreachable),
+        7 | 8 | 10 | 13..=15 => Error::hard(HostUnreachable),
+        _ => None,
+    }
+}
+
+/// The meaning of an ICMPv6 destination unreachable code (RFC 4443 S3.1), as Linux has it.
+fn unreachable_v6(code: u8) -> Option<Error> {
+    use io::ErrorKind::*;
+    match code {
+        0 => Error::soft(NetworkUnreachable),
+        2 | 3 => Error::soft(HostUnreachable),
+        1 | 5 | 6 => Error::hard(PermissionDenied),
+        4 => Error::hard(ConnectionRefused),
+        _ => None,
+    }
+}
+
+/// The answer to an echo request, or nothing if the request was sent to a group.
+pub(crate) fn echo_reply(packet: &ip::Packet<'_>) -> io::Result<Option<Vec<u8>>> {
+    if !is_unicast(packet.dst) {
+        return Ok(None);
+    }
+    let ty = match packet.src {
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(_) => 129,
+    };
+    let msg = packet.payload;
+    // the identifier, sequence number and data all come back as they were
+    build(packet.dst, packet.src, ty, 0, &msg[4..]).map(Some)
+}
+
+/// The error that tells whoever sent `raw` (parsed as `packet`) why it was dropped.
+///
+/// Returns nothing if the rules say no error should be sent: errors are never sent about
+/// packets to a group, or from somewhere that is not a single host (RFC 1122 S3.2.2,
+/// RFC 4443 S2.4(e)).
+pub(crate) fn dropped(
+    raw: &[u8],
+    packet: &ip::Packet<'_>,
+    why: Dropped,
+) -> io::Result<Option<Vec<u8>>> {
+    if !is_unicast(packet.dst) || !is_unicast(packet.src) || packet.src.is_unspecified() {
+        return Ok(None);
+    }
+
+    let (ty, code, rest, max_len) = match (packet.src, why) {
+        (IpAddr::V4(_), Dropped::UnknownProtocol) => (3, 2, [0; 4], MAX_ERROR_LEN_V4),
+        (IpAddr::V4(_), Dropped::ClosedPort) => (3, 3, [0; 4], MAX_ERROR_LEN_V4),
+        (IpAddr::V4(_), Dropped::ReassemblyTimedOut) => (11, 1, [0; 4], MAX_ERROR_LEN_V4),
+        // a parameter problem, pointing at the next header field we did not recognize
+        (IpAddr::V6(_), Dropped::UnknownProtocol) => (
+            4,
+            1,
+            (packet.protocol_at as u32).to_be_bytes(),
+            MAX_ERROR_LEN_V6,
+        ),
+        (IpAddr::V6(_), Dropped::ClosedPort) => (1, 4, [0; 4], MAX_ERROR_LEN_V6),
+        (IpAddr::V6(_), Dropped::ReassemblyTimedOut) => (3, 1, [0; 4], MAX_ERROR_LEN_V6),
+    };
+    let room = max_len - ip::Header::new(packet.dst, packet.src, 0)?.len() - HEADER_LEN;
+    let quoted = &raw[..raw.len().min(room)];
+    let mut body = Vec::with_capacity(4 + quoted.len());
+    body.extend_from_slice(&rest);
+    body.extend_from_slice(quoted);
+    build(packet.dst, packet.src, ty, code, &body).map(Some)
+}
+
+fn is_unicast(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(a) => !a.is_multicast() && !a.is_broadcast(),
+        IpAddr::V6(a) => !a.is_multicast(),
+    }
+}
+
+/// Builds a packet carrying an ICMP message of type `ty`, whose header ends with the first
+/// four bytes of `body`.
+fn build(src: IpAddr, dst: IpAddr, ty: u8, code: u8, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut ip = ip::Header::new(src, dst, protocol(src))?;
+    let at = ip.len();
+    let mut buf = vec![0; at + 4 + body.len()];
+    buf[at] = ty;
+    buf[at + 1] = code;
+    buf[at + 4..].copy_from_slice(body);
+    let sum = checksum(src, dst, &buf[at..]);
+    buf[at + 2..at + 4].copy_from_slice(&sum.to_be_bytes());
+    ip.write_header(&mut buf)?;
+    Ok(buf)
+}
+
+/// The checksum of `msg`, over the IPv6 pseudo-header too if the message goes between IPv6
+/// addresses. A message with the right checksum in it sums to zero.
+fn checksum(src: IpAddr, dst: IpAddr, msg: &[u8]) -> u16 {
+    match src {
+        IpAddr::V6(_) => ip::pseudo_checksum(src, dst, ip::ICMPV6, msg),
+        IpAddr::V4(_) => ip::checksum(&[msg]),
+    }
+}
+
+/// Keeps the errors we send down to a trickle, so that a flood of packets we cannot deliver
+/// does not turn into a flood of errors.
+#[derive(Default)]
+pub(crate) struct RateLimit {
+    /// errors we may send right away, as of `at`
+    tokens: u32,
+    at: Option<Instant>,
+}
+
+impl RateLimit {
+    /// True if an error may be sent now, which then counts against the limit.
+    pub(crate) fn allow(&mut self) -> bool {
+        let now = crate::clock::now();
+        let (tokens, at) = match self.at {
+            None => (RATE_BURST, now),
+            Some(at) => {
+                let earned = ((now - at).as_nanos() / RATE_INTERVAL.as_nanos())
+                    .min(RATE_BURST as u128) as u32;
+                if self.tokens + earned >= RATE_BURST {
+                    (RATE_BURST, now)
+                } else {
+                    (self.tokens + earned, at + RATE_INTERVAL * earned)
+                }
+            }
+        };
+        self.at = Some(at);
+        self.tokens = tokens.saturating_sub(1);
+        tokens > 0
+    }
+}
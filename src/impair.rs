@@ -0,0 +1,156 @@
+//! A device that mistreats packets on their way out, to exercise loss recovery.
+//!
+//! Modelled on Linux's netem: packets can be dropped, duplicated, corrupted, held back by a
+//! fixed delay plus random jitter, let through ahead of delayed ones, and squeezed through a link
+//! of limited bandwidth. All randomness comes from a seed, so a run can be repeated exactly.
+
+use crate::clock;
+use crate::device::NetDevice;
+use crate::rng::Rng;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// How an `ImpairedDevice` treats the packets sent through it.
+///
+/// Probabilities are between 0 and 1, and apply to each packet independently. The default
+/// leaves packets alone.
+#[derive(Clone, Debug, Default)]
+pub struct Impairment {
+    /// chance that a packet is dropped
+    pub loss: f64,
+    /// chance that a packet is sent twice
+    pub duplicate: f64,
+    /// chance that a single bit of a packet is flipped
+    pub corrupt: f64,
+    /// how long every packet is held back
+    pub delay: Duration,
+    /// up to this much is added to or taken off `delay`, at random, which reorders packets
+    pub jitter: Duration,
+    /// chance that a packet skips `delay` and `jitter`, overtaking those held back
+    pub reorder: f64,
+    /// bandwidth of the link in bytes per second, or `None` for no limit
+    pub rate: Option<u64>,
+    /// seeds the random choices above
+    pub seed: u64,
+}
+
+/// Wraps another device, and applies an `Impairment` to every packet sent through it.
+///
+/// Only outgoing packets are affected; to impair both directions of a `PipeDevice` pair, wrap
+/// both ends.
+pub struct ImpairedDevice<D> {
+    inner: D,
+    impairment: Impairment,
+    rng: Rng,
+    /// packets waiting for their release time, in release order (ties in the order sent)
+    queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    sent: u64,
+    /// when the rate-limited link finishes sending what it already has
+    link_free_at: Instant,
+}
+
+impl<D: NetDevice> ImpairedDevice<D> {
+    pub fn new(inner: D, impairment: Impairment) -> Self {
+        ImpairedDevice {
+            inner,
+            rng: Rng::new(impairment.seed),
+            impairment,
+            queue: BinaryHeap::new(),
+            sent: 0,
+            link_free_at: clock::now(),
+        }
+    }
+
+    /// Works out when `packet` should go out, and queues it.
+    fn enqueue(&mut self, packet: Vec<u8>) {
+        let now = clock::now();
+        let mut release = now;
+        if let Some(rate) = self.impairment.rate {
+            let start = std::cmp::max(now, self.link_free_at);
+            self.link_free_at =
+                start + Duration::from_secs_f64(packet.len() as f64 / rate.max(1) as f64);
+            release = self.link_free_at;
+        }
+        if !self.rng.chance(self.impairment.reorder) {
+            release += self.impairment.delay;
+            let jitter = self.impairment.jitter.as_nanos() as u64;
+            if jitter > 0 {
+                let offset = self.rng.below(2 * jitter + 1);
+                if offset >= jitter {
+                    release += Duration::from_nanos(offset - jitter);
+                } else {
+                    release = release
+                        .checked_sub(Duration::from_nanos(jitter - offset))
+                        .map_or(now, |r| std::cmp::max(r, now));
+                }
+            }
+        }
+
+        self.sent += 1;
+        self.queue.push(Reverse((release, self.sent, packet)));
+    }
+
+    /// Sends every queued packet whose time has come.
+    fn release(&mut self) -> io::Result<()> {
+        let now = clock::now();
+        while let Some(Reverse((at, _, _))) = self.queue.peek() {
+            if *at > now {
+                break;
+            }
+            let Reverse((_, _, packet)) = self.queue.pop().expect("just peeked");
+            self.inner.send(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: NetDevice> NetDevice for ImpairedDevice<D> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let deadline = clock::now() + timeout;
+        loop {
+            self.release()?;
+
+            // wake up in time for the next queued packet
+            let now = clock::now();
+            let until = match self.queue.peek() {
+                Some(Reverse((at, _, _))) => std::cmp::min(*at, deadline),
+                None => deadline,
+            };
+            let r = self.inner.recv(buf, until.saturating_duration_since(now))?;
+            if r.is_some() || clock::now() >= deadline {
+                return Ok(r);
+            }
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        if self.rng.chance(self.impairment.loss) {
+            return Ok(());
+        }
+
+        let mut packet = packet.to_vec();
+        if self.rng.chance(self.impairment.corrupt) && !packet.is_empty() {
+            let bit = self.rng.below(packet.len() as u64 * 8);
+            packet[(bit / 8) as usize] ^= 1 << (bit % 8);
+        }
+        if self.rng.chance(self.impairment.duplicate) {
+            self.enqueue(packet.clone());
+        }
+        self.enqueue(packet);
+        self.release()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn checksums_verified(&self) -> bool {
+        self.inner.checksums_verified()
+    }
+
+    fn ethertype(&self) -> Option<u16> {
+        self.inner.ethertype()
+    }
+}
@@ -0,0 +1,288 @@
+//! The IPv4 and IPv6 headers around TCP segments.
+
+use crate::device::{ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+use std::io;
+use std::net::IpAddr;
+
+/// Protocol numbers, as found in the IPv4 protocol and IPv6 next header fields.
+pub(crate) const ICMP: u8 = 1;
+pub(crate) const TCP: u8 = 6;
+pub(crate) const UDP: u8 = 17;
+pub(crate) const IPV6_FRAGMENT: u8 = 44;
+pub(crate) const ICMPV6: u8 = 58;
+
+/// IPv6 extension headers we step over to get to the payload (RFC 8200 S4): hop-by-hop
+/// options, routing and destination options. A fragment header is left as the payload, which
+/// is then of a protocol we do not handle.
+const SKIPPABLE_EXTENSIONS: [u8; 3] = [0, 43, 60];
+
+/// The payload of an IP packet and the addresses it travelled between.
+pub(crate) struct Packet<'a> {
+    pub(crate) src: IpAddr,
+    pub(crate) dst: IpAddr,
+    /// what the payload is, e.g. `TCP`
+    pub(crate) protocol: u8,
+    /// offset of the field that gave `protocol`, from the start of the packet
+    pub(crate) protocol_at: usize,
+    pub(crate) payload: &'a [u8],
+    /// where the payload goes, if the packet is an IPv4 fragment of a larger one
+    pub(crate) fragment: Option<Fragment>,
+}
+
+/// Where a fragment goes in the datagram it is part of (RFC 791 S2.3).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Fragment {
+    /// identifies the datagram, together with the addresses and protocol
+    pub(crate) id: u16,
+    /// offset of the fragment's payload in the datagram's, in bytes
+    pub(crate) offset: usize,
+    /// there are more fragments after this one
+    pub(crate) more: bool,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Finds the payload of an IPv4 or IPv6 packet.
+///
+/// Fails with `io::ErrorKind::InvalidData` if the packet is malformed.
+pub(crate) fn parse(packet: &[u8]) -> io::Result<Packet<'_>> {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => parse_v4(packet, true),
+        Some(6) => parse_v6(packet, true),
+        _ => Err(invalid("neither IPv4 nor IPv6".into())),
+    }
+}
+
+/// Like `parse`, for a packet the device said is of the given EtherType, rather than going by
+/// the version in its header.
+pub(crate) fn parse_as(packet: &[u8], ethertype: u16) -> io::Result<Packet<'_>> {
+    match ethertype {
+        ETHERTYPE_IPV4 => parse_v4(packet, true),
+        ETHERTYPE_IPV6 => parse_v6(packet, true),
+        _ => Err(invalid(format!(
+            "EtherType {:#06x} is neither IPv4 nor IPv6",
+            ethertype
+        ))),
+    }
+}
+
+/// Like `parse`, for the start of a packet quoted in an ICMP error, where the payload may have
+/// been cut short.
+pub(crate) fn parse_quoted(packet: &[u8]) -> io::Result<Packet<'_>> {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => parse_v4(packet, false),
+        Some(6) => parse_v6(packet, false),
+        _ => Err(invalid("neither IPv4 nor IPv6".into())),
+    }
+}
+
+fn parse_v4(packet: &[u8], whole: bool) -> io::Result<Packet<'_>> {
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(packet)
+        .map_err(|e| invalid(format!("bad IPv4 header: {:?}", e)))?;
+
+    // anything past the length the header gives is link-layer padding, and anything short of
+    // it means the packet was cut off
+    let mut len = iph.total_len() as usize;
+    if !whole {
+        len = len.min(packet.len());
+    }
+    if len < iph.slice().len() || len > packet.len() {
+        return Err(invalid(format!(
+            "IPv4 packet of {} bytes claims {}",
+            packet.len(),
+            len
+        )));
+    }
+    let fragment = (iph.more_fragments() || iph.fragments_offset() != 0).then(|| Fragment {
+        id: iph.identification(),
+        offset: iph.fragments_offset() as usize * 8,
+        more: iph.more_fragments(),
+    });
+    Ok(Packet {
+        src: iph.source_addr().into(),
+        dst: iph.destination_addr().into(),
+        protocol: iph.protocol(),
+        protocol_at: 9,
+        payload: &packet[iph.slice().len()..len],
+        fragment,
+    })
+}
+
+fn parse_v6(packet: &[u8], whole: bool) -> io::Result<Packet<'_>> {
+    let iph = etherparse::Ipv6HeaderSlice::from_slice(packet)
+        .map_err(|e| invalid(format!("bad IPv6 header: {:?}", e)))?;
+
+    // as for IPv4; a payload length of zero means a jumbogram, which we cannot have asked for
+    let mut len = iph.slice().len() + iph.payload_length() as usize;
+    if !whole {
+        len = len.min(packet.len());
+    }
+    if (whole && len == iph.slice().len()) || len > packet.len() {
+        return Err(invalid(format!(
+            "IPv6 packet of {} bytes claims {}",
+            packet.len(),
+            len
+        )));
+    }
+
+    let mut next = iph.next_header();
+    let mut next_at = 6;
+    let mut at = iph.slice().len();
+    while SKIPPABLE_EXTENSIONS.contains(&next) {
+        let ext_len = match packet[..len].get(at + 1) {
+            Some(&n) => (n as usize + 1) * 8,
+            None => return Err(invalid("truncated IPv6 extension header".into())),
+        };
+        if len - at < ext_len {
+            return Err(invalid("truncated IPv6 extension header".into()));
+        }
+        next = packet[at];
+        next_at = at;
+        at += ext_len;
+    }
+    Ok(Packet {
+        src: iph.source_addr().into(),
+        dst: iph.destination_addr().into(),
+        protocol: next,
+        protocol_at: next_at,
+        payload: &packet[at..len],
+        fragment: None,
+    })
+}
+
+/// The Internet checksum (RFC 1071) over `parts`, all but the last of which must be of even
+/// length. Data with the right checksum in it sums to zero.
+pub(crate) fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for pair in parts.iter().flat_map(|p| p.chunks(2)) {
+        sum += u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The checksum of a `protocol` payload sent from `src` to `dst`, over the pseudo-header as well
+/// (RFC 9293 S3.1, RFC 8200 S8.1). Addresses of different families make no pseudo-header.
+pub(crate) fn pseudo_checksum(src: IpAddr, dst: IpAddr, protocol: u8, payload: &[u8]) -> u16 {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => checksum(&[
+            &src.octets(),
+            &dst.octets(),
+            &[0, protocol],
+            &(payload.len() as u16).to_be_bytes(),
+            payload,
+        ]),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => checksum(&[
+            &src.octets(),
+            &dst.octets(),
+            &(payload.len() as u32).to_be_bytes(),
+            &[0, 0, 0, protocol],
+            payload,
+        ]),
+        _ => checksum(&[payload]),
+    }
+}
+
+/// Whether the IP header of `raw`, which parsed as `packet`, has the right checksum. IPv6
+/// headers have none.
+pub(crate) fn header_checksum_ok(raw: &[u8], packet: &Packet<'_>) -> bool {
+    match packet.src {
+        IpAddr::V4(_) => checksum(&[&raw[..(raw[0] & 0xf) as usize * 4]]) == 0,
+        IpAddr::V6(_) => true,
+    }
+}
+
+/// Whether the TCP segment (or other payload covered by the pseudo-header) in `packet` has the
+/// right checksum.
+pub(crate) fn payload_checksum_ok(packet: &Packet<'_>) -> bool {
+    pseudo_checksum(packet.src, packet.dst, packet.protocol, packet.payload) == 0
+}
+
+/// The IP header of the packets sent from one address to another.
+pub(crate) enum Header {
+    V4(etherparse::Ipv4Header),
+    V6(etherparse::Ipv6Header),
+}
+
+impl Header {
+    /// Fails with `io::ErrorKind::InvalidInput` if the addresses are of different families.
+    pub(crate) fn new(src: IpAddr, dst: IpAddr, protocol: u8) -> io::Result<Self> {
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let mut ip = etherparse::Ipv4Header::new(
+                    0,
+                    64,
+                    etherparse::IpTrafficClass::Tcp,
+                    src.octets(),
+                    dst.octets(),
+                );
+                ip.protocol = protocol;
+                // routers tell us when a packet is too big for them, rather than fragment it
+                // (RFC 1191)
+                ip.dont_fragment = true;
+                Ok(Header::V4(ip))
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => Ok(Header::V6(etherparse::Ipv6Header {
+                next_header: protocol,
+                hop_limit: 64,
+                source: src.octets(),
+                destination: dst.octets(),
+                ..Default::default()
+            })),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "local and remote addresses are of different families",
+            )),
+        }
+    }
+
+    /// Length of the header (we never add options or extensions).
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Header::V4(ip) => ip.header_len(),
+            Header::V6(_) => 40,
+        }
+    }
+
+    /// Fills in this header at the front of `buf`, which has room for it followed by the
+    /// payload.
+    pub(crate) fn write_header(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let payload_len = buf.len() - self.len();
+        let e = |e| io::Error::other(format!("{:?}", e));
+        match self {
+            Header::V4(ip) => {
+                ip.set_payload_len(payload_len).map_err(e)?;
+                ip.write(&mut &mut buf[..])
+                    .map_err(|e| io::Error::other(format!("{:?}", e)))
+            }
+            Header::V6(ip) => {
+                ip.set_payload_length(payload_len).map_err(e)?;
+                ip.write(&mut &mut buf[..])
+                    .map_err(|e| io::Error::other(format!("{:?}", e)))
+            }
+        }
+    }
+
+    /// Fills in this header and `tcp` at the front of `buf`, which has room for both followed
+    /// by the segment's data. `tcp` gets the checksum over the data and pseudo-header.
+    pub(crate) fn write(
+        &mut self,
+        tcp: &mut etherparse::TcpHeader,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let tcp_at = self.len();
+        let data_at = tcp_at + tcp.header_len() as usize;
+        let data = &buf[data_at..];
+        let e = |e| io::Error::other(format!("{:?}", e));
+        tcp.checksum = match self {
+            Header::V4(ip) => tcp.calc_checksum_ipv4(ip, data).map_err(e)?,
+            Header::V6(ip) => tcp.calc_checksum_ipv6(ip, data).map_err(e)?,
+        };
+        self.write_header(buf)?;
+        tcp.write(&mut &mut buf[tcp_at..data_at])
+    }
+}
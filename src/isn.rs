@@ -0,0 +1,69 @@
+//! Initial sequence numbers that are hard to guess from off the path (RFC 6528).
+
+use crate::clock;
+use crate::seq::SeqNum;
+use std::hash::Hasher;
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Picks the initial sequence number for each new connection from a secret key.
+#[derive(Clone)]
+pub(crate) struct Generator {
+    secret: [u8; 16],
+    /// when the generator was made, by `clock::now` and by how long after the Unix epoch that
+    /// was, so that the clock keeps going across restarts; under a simulation, the clock starts
+    /// from zero instead, so that runs reproduce
+    epoch: (Instant, Duration),
+}
+
+/// A secret for `Generator` from the OS's random number generator.
+pub(crate) fn random_secret() -> io::Result<[u8; 16]> {
+    let mut secret = [0u8; 16];
+    let n = unsafe { libc::getrandom(secret.as_mut_ptr().cast(), secret.len(), 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if n as usize != secret.len() {
+        return Err(io::Error::other("short read from getrandom"));
+    }
+    Ok(secret)
+}
+
+impl Generator {
+    pub(crate) fn new(secret: [u8; 16]) -> Self {
+        let since_unix = if clock::is_virtual() {
+            Duration::ZERO
+        } else {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+        };
+        Generator {
+            secret,
+            epoch: (clock::now(), since_unix),
+        }
+    }
+
+    /// The ISN for a connection from `local` to `remote` (RFC 6528 S3): a clock that ticks every
+    /// 4 microseconds, plus a keyed hash of the addresses and ports, so that each connection
+    /// counts from a different place that an attacker cannot work out.
+    pub(crate) fn isn(&self, local: (IpAddr, u16), remote: (IpAddr, u16)) -> SeqNum {
+        let elapsed = self.epoch.1 + clock::now().saturating_duration_since(self.epoch.0);
+        let m = (elapsed.as_micros() / 4) as u32;
+
+        let k0 = u64::from_le_bytes(self.secret[..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(self.secret[8..].try_into().unwrap());
+        // SipHash-2-4, which is what Linux uses for this too
+        #[allow(deprecated)]
+        let mut f = std::hash::SipHasher::new_with_keys(k0, k1);
+        for (addr, port) in [local, remote] {
+            match addr {
+                IpAddr::V4(a) => f.write(&a.octets()),
+                IpAddr::V6(a) => f.write(&a.octets()),
+            }
+            f.write(&port.to_be_bytes());
+        }
+        SeqNum::from(m.wrapping_add(f.finish() as u32))
+    }
+}
@@ -1,31 +1,147 @@
 use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::io::prelude::*;
-use std::net::Ipv4Addr;
-use std::sync::{Arc, Condvar, Mutex};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread;
+use std::time::{Duration, Instant};
 
+mod clock;
+mod congestion;
+mod device;
+mod ethernet;
+mod frag;
+mod icmp;
+mod impair;
+mod ip;
+mod isn;
+mod netlink;
+mod pcap;
+mod pmtu;
+mod poll;
+mod replay;
+mod rng;
+mod seq;
+mod sim;
 mod tcp;
+mod udp;
 
+pub use congestion::CongestionControl;
+pub use device::{NetDevice, PipeDevice, TapDevice, TunDevice};
+pub use ethernet::EthernetDevice;
+pub use impair::{ImpairedDevice, Impairment};
+pub use pcap::CaptureDevice;
+pub use poll::{Event, Interest, Poller, Token};
+pub use replay::{ReplayDevice, Transcript};
+pub use sim::Simulation;
+pub use udp::UdpSocket;
+
+#[cfg(feature = "async")]
+mod async_io;
+#[cfg(feature = "async")]
+pub use async_io::{Accept, Connect};
+
+/// Bytes a connection holds on to that the peer has yet to acknowledge, unless the interface
+/// was built with another number.
 const SENDQUEUE_SIZE: usize = 1024;
 
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+/// Smallest MTU an interface can be built with, as no IPv4 link may be smaller (RFC 791).
+const MIN_MTU: usize = 68;
+
+/// How often `packet_loop` drives the TCP timers, and how far a `Simulation` moves its clock
+/// in one step.
+const TICK: Duration = Duration::from_millis(1);
+
+/// Local ports handed out to outgoing connections (RFC 6335 S6).
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 struct Quad {
-    src: (Ipv4Addr, u16),
-    dst: (Ipv4Addr, u16),
+    src: (IpAddr, u16),
+    dst: (IpAddr, u16),
 }
 
-#[derive(Default)]
 struct Foobar {
     manager: Mutex<ConnectionManager>,
-    pending_var: Condvar,
-    rcv_var: Condvar,
 }
 
 type InterfaceHandle = Arc<Foobar>;
 
+/// Whether the `Interface` is gone, along with every stream and listener on it.
+fn is_dropped(ih: &InterfaceHandle) -> bool {
+    ih.manager.lock().unwrap().terminate && Arc::strong_count(ih) == 1
+}
+
+impl Foobar {
+    fn new(config: Config) -> InterfaceHandle {
+        Arc::new(Foobar {
+            manager: Mutex::new(ConnectionManager {
+                config,
+                ..Default::default()
+            }),
+        })
+    }
+}
+
+/// How an interface was set up, by an `InterfaceBuilder` or with the defaults.
+#[derive(Clone)]
+struct Config {
+    /// the addresses we take packets for, or any if there are none
+    addrs: Vec<IpAddr>,
+    /// largest packet we send
+    mtu: usize,
+    send_buffer: usize,
+    recv_buffer: usize,
+    /// picks initial sequence numbers, if they are not to start at zero
+    isn: Option<isn::Generator>,
+    congestion: CongestionControl,
+    /// most connections in the table at once, in whatever state
+    max_connections: Option<usize>,
+    /// most connections waiting on a listener to be accepted
+    backlog: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addrs: Vec::new(),
+            mtu: 0,
+            send_buffer: SENDQUEUE_SIZE,
+            recv_buffer: tcp::RECVQUEUE_SIZE,
+            isn: None,
+            congestion: CongestionControl::default(),
+            max_connections: None,
+            backlog: None,
+        }
+    }
+}
+
+impl Config {
+    /// Whether `addr` is one of ours.
+    fn is_local(&self, addr: IpAddr) -> bool {
+        self.addrs.is_empty() || self.addrs.contains(&addr)
+    }
+
+    /// What a new connection on `quad` starts out with.
+    fn params(&self, quad: &Quad) -> tcp::Params {
+        tcp::Params {
+            mtu: self.mtu,
+            iss: self
+                .isn
+                .as_ref()
+                .map_or_else(seq::SeqNum::default, |g| g.isn(quad.dst, quad.src)),
+            send_buffer: self.send_buffer,
+            recv_buffer: self.recv_buffer,
+            congestion: self.congestion,
+        }
+    }
+}
+
 pub struct Interface {
     ih: Option<InterfaceHandle>,
+    /// runs `packet_loop`, unless a `Simulation` drives the interface instead
     jh: Option<thread::JoinHandle<io::Result<()>>>,
 }
 
@@ -34,138 +150,949 @@ impl Drop for Interface {
         self.ih.as_mut().unwrap().manager.lock().unwrap().terminate = true;
 
         drop(self.ih.take());
-        self.jh
-            .take()
-            .expect("interface dropped more than once")
-            .join()
-            .unwrap()
-            .unwrap();
+        if let Some(jh) = self.jh.take() {
+            // packet_loop has already reported why it stopped, if it failed
+            let _ = jh.join().unwrap();
+        }
     }
 }
 
+/// The table of connections and listeners.
+///
+/// Its lock is only held to look up, add or remove entries. Each connection and listener has a
+/// lock of its own, so streams do not contend with each other while reading and writing.
 #[derive(Default)]
 struct ConnectionManager {
     terminate: bool,
-    connections: HashMap<Quad, tcp::Connection>,
-    pending: HashMap<u16, VecDeque<Quad>>,
+    connections: HashMap<Quad, Arc<Socket>>,
+    pending: HashMap<u16, Arc<Listener>>,
+    next_port: u16,
+    config: Config,
+    /// keeps down the ICMP errors the interface sends
+    icmp_limit: icmp::RateLimit,
+    /// IPv4 datagrams that arrived in fragments
+    fragments: frag::Reassembler,
+    stats: Stats,
+    /// UDP sockets, by the port they are bound to
+    udp: HashMap<u16, Arc<udp::Binding>>,
+    /// UDP datagrams waiting for `packet_loop` to send them
+    udp_out: Vec<Vec<u8>>,
+}
+
+/// Counts of packets an interface dropped, as returned by `Interface::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// IPv4 packets whose header checksum was wrong
+    pub bad_ip_checksums: u64,
+    /// TCP segments whose checksum was wrong
+    pub bad_tcp_checksums: u64,
+    /// UDP datagrams whose checksum was wrong
+    pub bad_udp_checksums: u64,
+    /// packets sent to none of the interface's addresses
+    pub misaddressed: u64,
+}
+
+/// A connection, shared between `packet_loop` and the `TcpStream` for it.
+struct Socket {
+    conn: Mutex<tcp::Connection>,
+    /// threads blocked reading from the stream wait here
+    rcv_var: Condvar,
+    /// threads blocked writing to (or connecting) the stream wait here
+    snd_var: Condvar,
+    waiters: Mutex<Waiters>,
+}
+
+/// Connections accepted on a bound port that no one has picked up yet.
+#[derive(Default)]
+struct Listener {
+    backlog: Mutex<VecDeque<(Quad, Arc<Socket>)>>,
+    /// threads blocked in `TcpListener::accept` wait here
+    var: Condvar,
+    waiters: Mutex<Waiters>,
+}
+
+/// Pollers and async tasks waiting on a socket or listener.
+///
+/// For a listener, a connection waiting to be accepted counts as `tcp::Available::READ`.
+#[derive(Default)]
+struct Waiters {
+    pollers: poll::Watchers,
+    #[cfg(feature = "async")]
+    tasks: async_io::Wakers,
+}
+
+impl Waiters {
+    /// Wakes up whoever in `waiters` cares that `a` became available.
+    #[cfg_attr(not(feature = "async"), allow(unused_variables))]
+    fn wake(waiters: &Mutex<Waiters>, a: tcp::Available) {
+        let waiters = waiters.lock().unwrap();
+        let pollers = waiters.pollers.get();
+        #[cfg(feature = "async")]
+        let woken = {
+            let mut waiters = waiters;
+            waiters.tasks.take(a)
+        };
+        #[cfg(not(feature = "async"))]
+        drop(waiters);
+
+        pollers.iter().for_each(|p| p.raise());
+        #[cfg(feature = "async")]
+        woken.into_iter().for_each(std::task::Waker::wake);
+    }
+}
+
+impl Socket {
+    fn new(c: tcp::Connection) -> Self {
+        Socket {
+            conn: Mutex::new(c),
+            rcv_var: Condvar::new(),
+            snd_var: Condvar::new(),
+            waiters: Mutex::default(),
+        }
+    }
+
+    /// Wakes up the threads, pollers and tasks waiting on this socket for what became available
+    /// in `a`. Must be called without holding `conn`.
+    fn notify(&self, a: tcp::Available) {
+        if a.is_empty() {
+            return;
+        }
+        if a.contains(tcp::Available::READ) {
+            self.rcv_var.notify_all();
+        }
+        if a.contains(tcp::Available::WRITE) {
+            self.snd_var.notify_all();
+        }
+        Waiters::wake(&self.waiters, a);
+    }
+}
+
+impl Listener {
+    /// Adds a freshly accepted connection to the backlog, and wakes up whoever is waiting for
+    /// one.
+    fn push(&self, quad: Quad, sock: Arc<Socket>) {
+        self.backlog.lock().unwrap().push_back((quad, sock));
+        self.var.notify_all();
+        Waiters::wake(&self.waiters, tcp::Available::READ);
+    }
+}
+
+impl ConnectionManager {
+    /// Moves on to the next ephemeral port, which may be in use.
+    fn next_ephemeral_port(&mut self) {
+        if !EPHEMERAL_PORTS.contains(&self.next_port) || self.next_port == u16::MAX {
+            self.next_port = *EPHEMERAL_PORTS.start();
+        } else {
+            self.next_port += 1;
+        }
+    }
+
+    /// Picks a local port for a new connection from `local` to `remote`.
+    fn ephemeral_port(&mut self, local: IpAddr, remote: SocketAddr) -> io::Result<u16> {
+        for _ in EPHEMERAL_PORTS {
+            self.next_ephemeral_port();
+            let quad = Quad {
+                src: (remote.ip(), remote.port()),
+                dst: (local, self.next_port),
+            };
+            if !self.pending.contains_key(&self.next_port) && !self.connections.contains_key(&quad)
+            {
+                return Ok(self.next_port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no ephemeral ports available",
+        ))
+    }
+
+    /// Picks a port for a UDP socket bound to port 0.
+    fn udp_ephemeral_port(&mut self) -> io::Result<u16> {
+        for _ in EPHEMERAL_PORTS {
+            self.next_ephemeral_port();
+            if !self.udp.contains_key(&self.next_port) {
+                return Ok(self.next_port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no ephemeral ports available",
+        ))
+    }
+
+    /// Whether there is room in the table for another connection.
+    fn has_room(&self) -> bool {
+        self.config
+            .max_connections
+            .is_none_or(|max| self.connections.len() < max)
+    }
+
+    /// Starts an active open from an ephemeral port on `local` to `addr`.
+    ///
+    /// Fails with `io::ErrorKind::AddrNotAvailable` if `local` is not one of the interface's
+    /// addresses.
+    fn connect(&mut self, local: IpAddr, addr: SocketAddr) -> io::Result<(Quad, Arc<Socket>)> {
+        if !self.config.is_local(local) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "not an address of the interface",
+            ));
+        }
+        if !self.has_room() {
+            return Err(io::Error::other("too many connections"));
+        }
+        let port = self.ephemeral_port(local, addr)?;
+        let quad = Quad {
+            src: (addr.ip(), addr.port()),
+            dst: (local, port),
+        };
+        let c = tcp::Connection::connect(quad.dst, quad.src, self.config.params(&quad))?;
+        let sock = Arc::new(Socket::new(c));
+        self.connections.insert(quad, sock.clone());
+        Ok((quad, sock))
+    }
+
+    /// Removes `sock` from the table, unless `quad` has since been reused for another connection.
+    fn forget(&mut self, quad: &Quad, sock: &Arc<Socket>) {
+        if self
+            .connections
+            .get(quad)
+            .is_some_and(|s| Arc::ptr_eq(s, sock))
+        {
+            self.connections.remove(quad);
+        }
+    }
+}
+
+/// Checks on a connection started with `ConnectionManager::connect`.
+///
+/// Returns `None` while the handshake is still in progress.
+fn poll_connect(c: &tcp::Connection) -> Option<io::Result<()>> {
+    if let Some(e) = c.error() {
+        return Some(Err(e));
+    }
+    if c.is_established() {
+        Some(Ok(()))
+    } else {
+        None
+    }
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
-    let mut buf = [0u8; 1504];
+fn packet_loop<D: NetDevice>(mut nic: D, ih: InterfaceHandle) -> io::Result<()> {
+    let mut out = tcp::Outbox::new();
+    let r = drive(&mut nic, &ih, &mut out);
+    if let Err(e) = &r {
+        eprintln!("interface stopped: {}", e);
+    }
+    // nothing drives the connections from here on, so rather than leave their peers and any
+    // blocked streams hanging, reset them
+    teardown(&ih, &mut out)?;
+    send_all(&mut nic, &mut out);
+    r
+}
+
+/// Sends and receives on `nic` until the interface is dropped, or `nic` fails.
+fn drive<D: NetDevice>(nic: &mut D, ih: &InterfaceHandle, out: &mut tcp::Outbox) -> io::Result<()> {
+    let mut buf = vec![0u8; nic.mtu()];
+    let mut next_tick = Instant::now();
 
     loop {
-        // TODO: set a timeout for this recv for TCP timers or ConnectionManager::terminate
-        let nbytes = nic.recv(&mut buf[..])?;
-
-        // TODO: if self.terminate && Arc::get_strong_refs(ih) == 1; then tear down all connections and return.
-
-        // if s/without_packet_info/new/:
-        //
-        // let _eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
-        // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
-        // if eth_proto != 0x0800 {
-        //     // not ipv4
-        //     continue;
-        // }
-        //
-        // and also include on send
-
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            Ok(iph) => {
-                let src = iph.source_addr();
-                let dst = iph.destination_addr();
-                if iph.protocol() != 0x06 {
-                    eprintln!("BAD PROTOCOL");
-                    // not tcp
-                    continue;
-                }
+        // segments are only sent once no connection is locked
+        send_all(nic, out);
+
+        // make sure we wake up in time to drive the TCP timers and to notice
+        // ConnectionManager::terminate
+        let now = Instant::now();
+        if now >= next_tick {
+            next_tick = now + TICK;
+
+            if is_dropped(ih) {
+                return Ok(());
+            }
+            on_tick(ih, out)?;
+            continue;
+        }
+
+        let nbytes = match nic.recv(&mut buf[..], next_tick.saturating_duration_since(now))? {
+            Some(nbytes) => nbytes,
+            None => continue,
+        };
+        let (verified, ethertype) = (nic.checksums_verified(), nic.ethertype());
+        on_packet(ih, &buf[..nbytes], verified, ethertype, out)?;
+    }
+}
+
+/// Sends the segments in `out`. One that cannot be sent is dropped, as if lost on the wire, and
+/// left to TCP to retransmit.
+fn send_all<D: NetDevice + ?Sized>(nic: &mut D, out: &mut tcp::Outbox) {
+    for segment in out.drain(..) {
+        if let Err(e) = nic.send(&segment) {
+            eprintln!("dropping outgoing packet: {}", e);
+        }
+    }
+}
+
+/// Resets every connection left on the interface, waking up whoever waits on them.
+fn teardown(ih: &Foobar, out: &mut tcp::Outbox) -> io::Result<()> {
+    let cm = ih.manager.lock().unwrap();
+    let mut socks: Vec<_> = cm
+        .connections
+        .iter()
+        .map(|(q, s)| (*q, s.clone()))
+        .collect();
+    drop(cm);
+    socks.sort_unstable_by_key(|(q, _)| *q);
+
+    for (_, sock) in socks {
+        let mut c = sock.conn.lock().unwrap();
+        let before = c.availability();
+        c.reset(out, io::ErrorKind::ConnectionAborted)?;
+        let new = c.availability() - before;
+        drop(c);
+        sock.notify(new);
+    }
+    Ok(())
+}
+
+/// Drives the TCP timers of every connection on the interface, and gives up on datagrams whose
+/// fragments are taking too long.
+fn on_tick(ih: &Foobar, out: &mut tcp::Outbox) -> io::Result<()> {
+    let mut cm = ih.manager.lock().unwrap();
+    out.append(&mut cm.udp_out);
+    let expired = cm.fragments.expire();
+    drop(cm);
+    for first in expired {
+        if let Ok(packet) = ip::parse(&first) {
+            report_dropped(ih, &first, &packet, icmp::Dropped::ReassemblyTimedOut, out)?;
+        }
+    }
+
+    let cm = ih.manager.lock().unwrap();
+    let mut socks: Vec<_> = cm
+        .connections
+        .iter()
+        .map(|(q, s)| (*q, s.clone()))
+        .collect();
+    drop(cm);
+    // in a fixed order, so that a simulation sends the same segments every time it is replayed
+    socks.sort_unstable_by_key(|(q, _)| *q);
+
+    let mut dead = Vec::new();
+    for (q, sock) in socks {
+        let mut c = sock.conn.lock().unwrap();
+        let before = c.availability();
+        c.on_tick(out)?;
+        let new = c.availability() - before;
+        if c.orphaned && c.is_terminated() {
+            dead.push((q, sock.clone()));
+        }
+        drop(c);
+        sock.notify(new);
+    }
+    if !dead.is_empty() {
+        let mut cm = ih.manager.lock().unwrap();
+        for (q, sock) in dead {
+            cm.forget(&q, &sock);
+        }
+    }
+    Ok(())
+}
+
+/// Hands a packet that arrived on the interface to the connection (or listener) it is for.
+///
+/// Packets with the wrong checksums are dropped, unless the device has `verified` them already.
+/// If the device gave the packet's `ethertype`, that decides whether it is IPv4 or IPv6.
+fn on_packet(
+    ih: &Foobar,
+    packet: &[u8],
+    verified: bool,
+    ethertype: Option<u16>,
+    out: &mut tcp::Outbox,
+) -> io::Result<()> {
+    let raw = packet;
+    let parsed = match ethertype {
+        Some(ethertype) => ip::parse_as(raw, ethertype),
+        None => ip::parse(raw),
+    };
+    let packet = match parsed {
+        Ok(packet) => packet,
+        Err(e) => {
+            eprintln!("ignoring packet: {}", e);
+            return Ok(());
+        }
+    };
+    if !verified && !ip::header_checksum_ok(raw, &packet) {
+        eprintln!("ignoring packet with bad header checksum");
+        ih.manager.lock().unwrap().stats.bad_ip_checksums += 1;
+        return Ok(());
+    }
+    let mut cm = ih.manager.lock().unwrap();
+    if !cm.config.is_local(packet.dst) {
+        // e.g. for another host the device hears, or one of the other end's own addresses
+        cm.stats.misaddressed += 1;
+        return Ok(());
+    }
+    drop(cm);
+
+    if let Some(fragment) = packet.fragment {
+        let whole = ih
+            .manager
+            .lock()
+            .unwrap()
+            .fragments
+            .add(raw, &packet, fragment);
+        return match whole {
+            // the device cannot have checked the checksum of what the fragments carried
+            Ok(Some(datagram)) => on_packet(ih, &datagram, false, None, out),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                eprintln!("ignoring fragment: {}", e);
+                Ok(())
+            }
+        };
+    }
+
+    match packet.protocol {
+        ip::TCP => on_segment(ih, &packet, verified, out),
+        p if p == icmp::protocol(packet.src) => on_icmp(ih, &packet, out),
+        ip::UDP => on_datagram(ih, raw, &packet, verified, out),
+        // we do not put IPv6 fragments back together
+        ip::IPV6_FRAGMENT if packet.src.is_ipv6() => {
+            eprintln!("ignoring IPv6 fragment");
+            Ok(())
+        }
+        _ => report_dropped(ih, raw, &packet, icmp::Dropped::UnknownProtocol, out),
+    }
+}
 
-                match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
-                    Ok(tcph) => {
-                        use std::collections::hash_map::Entry;
-                        let datai = iph.slice().len() + tcph.slice().len();
-                        let mut cmg = ih.manager.lock().unwrap();
-                        let mut cm = &mut *cmg;
-                        let q = Quad {
-                            src: (src, tcph.source_port()),
-                            dst: (dst, tcph.destination_port()),
-                        };
-
-                        match cm.connections.entry(q) {
-                            Entry::Occupied(mut c) => {
-                                // eprintln!("got packet for known quad {:?}", q);
-                                let a = c.get_mut().on_packet(
-                                    &mut nic,
-                                    iph,
-                                    tcph,
-                                    &buf[datai..nbytes],
-                                )?;
-
-                                // TODO: compare before/after
-                                drop(cmg);
-                                if a.contains(tcp::Available::READ) {
-                                    ih.rcv_var.notify_all()
-                                }
-                                if a.contains(tcp::Available::WRITE) {
-                                    // TODO: ih.snd_var.notify_all()
-                                }
+/// Hands a TCP segment to the connection (or listener) it is for.
+fn on_segment(
+    ih: &Foobar,
+    packet: &ip::Packet<'_>,
+    verified: bool,
+    out: &mut tcp::Outbox,
+) -> io::Result<()> {
+    if !verified && !ip::payload_checksum_ok(packet) {
+        eprintln!("ignoring tcp segment with bad checksum");
+        ih.manager.lock().unwrap().stats.bad_tcp_checksums += 1;
+        return Ok(());
+    }
+    match etherparse::TcpHeaderSlice::from_slice(packet.payload) {
+        Ok(tcph) => {
+            use std::collections::hash_map::Entry;
+            let data = &packet.payload[tcph.slice().len()..];
+            let mut cmg = ih.manager.lock().unwrap();
+            let cm = &mut *cmg;
+            let room = cm.has_room();
+            let q = Quad {
+                src: (packet.src, tcph.source_port()),
+                dst: (packet.dst, tcph.destination_port()),
+            };
+
+            match cm.connections.entry(q) {
+                Entry::Occupied(c) => {
+                    let sock = c.get().clone();
+                    drop(cmg);
+                    let a = sock.conn.lock().unwrap().on_packet(out, tcph, data)?;
+                    sock.notify(a);
+                }
+                Entry::Vacant(e) => {
+                    match cm.pending.get(&tcph.destination_port()) {
+                        Some(l) if !tcph.ack() => {
+                            let full = cm
+                                .config
+                                .backlog
+                                .is_some_and(|max| l.backlog.lock().unwrap().len() >= max);
+                            if full || !room {
+                                // as if the SYN was lost, so that the peer tries again later
+                                eprintln!("too many connections, ignoring SYN");
+                                return Ok(());
                             }
-                            Entry::Vacant(e) => {
-                                // eprintln!("got packet for unknown quad {:?}", q);
-                                if let Some(pending) = cm.pending.get_mut(&tcph.destination_port())
-                                {
-                                    eprintln!("listening, so accepting");
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &mut nic,
-                                        iph,
-                                        tcph,
-                                        &buf[datai..nbytes],
-                                    )? {
-                                        e.insert(c);
-                                        pending.push_back(q);
-                                        drop(cmg);
-                                        ih.pending_var.notify_all()
-                                    }
-                                }
+                            eprintln!("listening, so accepting");
+                            let params = cm.config.params(&q);
+                            if let Some(c) = tcp::Connection::accept(
+                                out, params, packet.dst, packet.src, tcph, data,
+                            )? {
+                                let sock = Arc::new(Socket::new(c));
+                                // still under the manager lock, so that the listener cannot go
+                                // away before it has seen the connection
+                                l.push(q, sock.clone());
+                                e.insert(sock);
                             }
                         }
-                    }
-                    Err(e) => {
-                        eprintln!("ignoring weird tcp packet {:?}", e);
+                        // nothing is listening, or nothing can have been sent for this ACK to
+                        // acknowledge
+                        _ => tcp::reset(out, packet.dst, packet.src, tcph, data)?,
                     }
                 }
             }
-            Err(e) => {
-                eprintln!("ignoring weird packet {:?}", e);
+        }
+        Err(e) => {
+            eprintln!("ignoring weird tcp packet {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Hands a UDP datagram to the socket bound to its port.
+fn on_datagram(
+    ih: &Foobar,
+    raw: &[u8],
+    packet: &ip::Packet<'_>,
+    verified: bool,
+    out: &mut tcp::Outbox,
+) -> io::Result<()> {
+    let d = match udp::parse(packet) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("ignoring udp datagram: {}", e);
+            return Ok(());
+        }
+    };
+    if !verified && !udp::checksum_ok(packet) {
+        eprintln!("ignoring udp datagram with bad checksum");
+        ih.manager.lock().unwrap().stats.bad_udp_checksums += 1;
+        return Ok(());
+    }
+
+    let src = SocketAddr::new(packet.src, d.src_port);
+    let binding = ih.manager.lock().unwrap().udp.get(&d.dst_port).cloned();
+    match binding {
+        Some(b) if b.accepts(src, packet.dst) => {
+            b.deliver(src, d.data);
+            Ok(())
+        }
+        _ => report_dropped(ih, raw, packet, icmp::Dropped::ClosedPort, out),
+    }
+}
+
+/// Answers pings, and passes on errors about our segments and datagrams to the connections and
+/// sockets that sent them.
+fn on_icmp(ih: &Foobar, packet: &ip::Packet<'_>, out: &mut tcp::Outbox) -> io::Result<()> {
+    let msg = match icmp::parse(packet) {
+        Ok(msg) => msg,
+        Err(e) => {
+            eprintln!("ignoring icmp message: {}", e);
+            return Ok(());
+        }
+    };
+
+    match msg {
+        icmp::Message::EchoRequest => {
+            if let Some(reply) = icmp::echo_reply(packet)? {
+                // a request that arrived in fragments can be too large to answer in one piece,
+                // and we do not fragment
+                if reply.len() <= ih.manager.lock().unwrap().config.mtu {
+                    out.push(reply);
+                } else {
+                    eprintln!("ignoring echo request too large to answer");
+                }
+            }
+        }
+        icmp::Message::Error { error, packet } => {
+            if let Some((sock, seq)) = sender_of(ih, &packet) {
+                let a = sock.conn.lock().unwrap().on_icmp_error(seq, error);
+                sock.notify(a);
+            } else if let Some((binding, dst)) = udp_sender_of(ih, &packet) {
+                binding.on_icmp_error(dst, error);
+            }
+        }
+        icmp::Message::TooBig { mtu, packet } => {
+            if let Some((sock, seq)) = sender_of(ih, &packet) {
+                let a = sock.conn.lock().unwrap().on_packet_too_big(out, seq, mtu)?;
+                sock.notify(a);
+            }
+        }
+        icmp::Message::Other => {}
+    }
+    Ok(())
+}
+
+/// The connection that sent the TCP segment quoted in an ICMP error, and the segment's sequence
+/// number.
+fn sender_of(ih: &Foobar, sent: &ip::Packet<'_>) -> Option<(Arc<Socket>, seq::SeqNum)> {
+    // the first eight bytes of a TCP header are always quoted, which is up to the sequence
+    // number
+    let h = sent.payload;
+    if sent.protocol != ip::TCP || h.len() < 8 {
+        return None;
+    }
+    let q = Quad {
+        src: (sent.dst, u16::from_be_bytes([h[2], h[3]])),
+        dst: (sent.src, u16::from_be_bytes([h[0], h[1]])),
+    };
+    let seq = u32::from_be_bytes([h[4], h[5], h[6], h[7]]);
+    let sock = ih.manager.lock().unwrap().connections.get(&q).cloned()?;
+    Some((sock, seq.into()))
+}
+
+/// The UDP socket that sent the datagram quoted in an ICMP error, and where it sent it.
+fn udp_sender_of(ih: &Foobar, sent: &ip::Packet<'_>) -> Option<(Arc<udp::Binding>, SocketAddr)> {
+    let h = sent.payload;
+    if sent.protocol != ip::UDP || h.len() < 4 {
+        return None;
+    }
+    let port = u16::from_be_bytes([h[0], h[1]]);
+    let dst = SocketAddr::new(sent.dst, u16::from_be_bytes([h[2], h[3]]));
+    let binding = ih.manager.lock().unwrap().udp.get(&port).cloned()?;
+    binding.accepts(dst, sent.src).then_some((binding, dst))
+}
+
+/// Tells whoever sent `raw` why we dropped it.
+fn report_dropped(
+    ih: &Foobar,
+    raw: &[u8],
+    packet: &ip::Packet<'_>,
+    why: icmp::Dropped,
+    out: &mut tcp::Outbox,
+) -> io::Result<()> {
+    if let Some(error) = icmp::dropped(raw, packet, why)? {
+        if ih.manager.lock().unwrap().icmp_limit.allow() {
+            out.push(error);
+        }
+    }
+    Ok(())
+}
+
+/// Blocks on `var` until it is notified, or until `deadline` (if any) passes.
+fn wait<'a, T>(
+    var: &Condvar,
+    guard: MutexGuard<'a, T>,
+    deadline: Option<Instant>,
+) -> MutexGuard<'a, T> {
+    match deadline {
+        Some(deadline) => {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            var.wait_timeout(guard, timeout).unwrap().0
+        }
+        None => var.wait(guard).unwrap(),
+    }
+}
+
+fn has_passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Turns a user-provided timeout into a deadline, rejecting zero like `std::net` does.
+fn deadline_after(timeout: Option<Duration>) -> io::Result<Option<Instant>> {
+    match timeout {
+        Some(timeout) if timeout.is_zero() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        )),
+        Some(timeout) => Ok(Some(Instant::now() + timeout)),
+        None => Ok(None),
+    }
+}
+
+/// Sets up an `Interface`, for when the defaults of `Interface::new` and
+/// `Interface::with_device` will not do.
+///
+/// Given an MTU, a queue length, or addresses or routes for the kernel's end of the tun device,
+/// `build` also configures the device itself, so nothing else has to run `ip` once it is open.
+///
+/// ```no_run
+/// use std::net::Ipv4Addr;
+/// use trust::{CongestionControl, InterfaceBuilder};
+///
+/// let iface = InterfaceBuilder::new()
+///     .name("tun1")
+///     .address(Ipv4Addr::new(192, 168, 0, 2))
+///     .kernel_address(Ipv4Addr::new(192, 168, 0, 1), 24)
+///     .route(Ipv4Addr::new(10, 1, 0, 0), 16)
+///     .mtu(1400)
+///     .congestion_control(CongestionControl::Reno)
+///     .max_connections(1000)
+///     .build()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct InterfaceBuilder {
+    name: String,
+    mtu: Option<usize>,
+    txqueuelen: Option<u32>,
+    isn_secret: Option<[u8; 16]>,
+    /// addresses `build` gives the kernel's end of the device, with their prefix lengths
+    kernel_addrs: Vec<(IpAddr, u8)>,
+    /// prefixes `build` has the kernel route into the device
+    routes: Vec<(IpAddr, u8)>,
+    /// where to capture the interface's packets to, if anywhere
+    capture: Option<PathBuf>,
+    config: Config,
+}
+
+impl Default for InterfaceBuilder {
+    fn default() -> Self {
+        InterfaceBuilder::new()
+    }
+}
+
+impl InterfaceBuilder {
+    pub fn new() -> Self {
+        InterfaceBuilder {
+            name: "tun0".into(),
+            mtu: None,
+            txqueuelen: None,
+            isn_secret: None,
+            kernel_addrs: Vec::new(),
+            routes: Vec::new(),
+            capture: None,
+            config: Config::default(),
+        }
+    }
+
+    /// The tun device `build` opens; `tun0` by default.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Adds an address of the interface. Once it has any, packets sent to other addresses are
+    /// dropped, and sockets can only be bound (or connect from) one of them.
+    pub fn address(mut self, addr: impl Into<IpAddr>) -> Self {
+        self.config.addrs.push(addr.into());
+        self
+    }
+
+    /// Adds an address for the kernel's end of the tun device, on a subnet of `prefix_len`
+    /// bits, like `ip addr add` would. Only `build` applies it.
+    ///
+    /// Panics if `prefix_len` is longer than the address.
+    pub fn kernel_address(mut self, addr: impl Into<IpAddr>, prefix_len: u8) -> Self {
+        let addr = addr.into();
+        check_prefix(addr, prefix_len);
+        self.kernel_addrs.push((addr, prefix_len));
+        self
+    }
+
+    /// Has the kernel send packets for the prefix `dst`/`prefix_len` into the tun device, like
+    /// `ip route add` would. Only `build` applies it.
+    ///
+    /// Panics if `prefix_len` is longer than the address.
+    pub fn route(mut self, dst: impl Into<IpAddr>, prefix_len: u8) -> Self {
+        let dst = dst.into();
+        check_prefix(dst, prefix_len);
+        self.routes.push((dst, prefix_len));
+        self
+    }
+
+    /// Sends no packets larger than `mtu` bytes. `build` sets the tun device's MTU to this;
+    /// other devices must have an MTU at least this large.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Has the kernel queue up to `len` packets for the tun device before dropping them, like
+    /// `ip link set txqueuelen` would. Only `build` applies it.
+    pub fn txqueuelen(mut self, len: u32) -> Self {
+        self.txqueuelen = Some(len);
+        self
+    }
+
+    /// How many bytes a connection holds on to that the peer has yet to acknowledge, before
+    /// writes block.
+    pub fn send_buffer(mut self, bytes: usize) -> Self {
+        self.config.send_buffer = bytes;
+        self
+    }
+
+    /// How many received bytes a connection holds on to that the application has yet to read,
+    /// which is the largest window it advertises. At most 65535, as windows are not scaled.
+    pub fn recv_buffer(mut self, bytes: usize) -> Self {
+        self.config.recv_buffer = bytes;
+        self
+    }
+
+    /// Picks initial sequence numbers (RFC 6528) from `secret`, instead of from one the OS
+    /// draws at random for each interface. Either way, they cannot be guessed by someone who
+    /// cannot see the connection; a fixed secret makes them the same from one run to the next.
+    ///
+    /// Interfaces in a `Simulation` start every connection at zero without one, which keeps
+    /// captures replayable; with one, they start at the same place whenever the seed is the same.
+    pub fn isn_secret(mut self, secret: [u8; 16]) -> Self {
+        self.isn_secret = Some(secret);
+        self
+    }
+
+    /// The congestion control connections use; none by default.
+    pub fn congestion_control(mut self, cc: CongestionControl) -> Self {
+        self.config.congestion = cc;
+        self
+    }
+
+    /// Most connections the interface has at once, in whatever state. Past that, SYNs are
+    /// ignored and `Interface::connect` fails.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    /// Most connections a listener holds on to before they are accepted. Past that, SYNs are
+    /// ignored.
+    pub fn backlog(mut self, max: usize) -> Self {
+        self.config.backlog = Some(max);
+        self
+    }
+
+    /// Writes every packet the interface sends and receives to a pcapng capture at `path`, as
+    /// if its device were wrapped in a `CaptureDevice`. The file is created when the interface
+    /// is built, replacing any file already there.
+    pub fn capture<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.capture = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Opens the tun device and runs the stack over it.
+    ///
+    /// Given an MTU, a queue length, or kernel addresses or routes, first sets the device's MTU
+    /// and queue length (if given), adds the addresses, brings the link up and adds the routes,
+    /// all over rtnetlink. That needs CAP_NET_ADMIN, as does opening the device.
+    pub fn build(self) -> io::Result<Interface> {
+        self.build_with(TunDevice::new)
+    }
+
+    /// Like `build`, but opens the tun device with `open`, given its name, for when it should
+    /// have packet info or a vnet header:
+    ///
+    /// ```no_run
+    /// use trust::{InterfaceBuilder, TunDevice};
+    ///
+    /// let iface = InterfaceBuilder::new()
+    ///     .name("tun1")
+    ///     .mtu(9000)
+    ///     .build_with(TunDevice::with_vnet_header)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn build_with<F>(self, open: F) -> io::Result<Interface>
+    where
+        F: FnOnce(&str) -> io::Result<TunDevice>,
+    {
+        let mut dev = open(&self.name)?;
+        if self.mtu.is_some()
+            || self.txqueuelen.is_some()
+            || !self.kernel_addrs.is_empty()
+            || !self.routes.is_empty()
+        {
+            self.configure_device()?;
+            if let Some(mtu) = self.mtu {
+                dev.set_mtu(mtu);
             }
         }
+        self.build_with_device(dev)
+    }
+
+    /// Configures the kernel's end of the tun device.
+    fn configure_device(&self) -> io::Result<()> {
+        if self.mtu.is_some_and(|mtu| mtu < MIN_MTU) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "MTU too small"));
+        }
+        let index = netlink::index_of(&self.name)?;
+        let mut nl = netlink::Netlink::open()?;
+        for &(addr, prefix_len) in &self.kernel_addrs {
+            nl.add_address(index, addr, prefix_len)?;
+        }
+        nl.set_up(index, self.mtu, self.txqueuelen)?;
+        // routes through a device that is down are refused
+        for &(dst, prefix_len) in &self.routes {
+            nl.add_route(index, dst, prefix_len)?;
+        }
+        Ok(())
     }
+
+    /// Runs the stack over `dev`, whose name is then of no account.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if the settings do not make sense for it.
+    pub fn build_with_device<D: NetDevice>(mut self, dev: D) -> io::Result<Interface> {
+        if self.isn_secret.is_none() {
+            self.isn_secret = Some(isn::random_secret()?);
+        }
+        let capture = self.capture.take();
+        let config = self.config_for(&dev)?;
+        match capture {
+            Some(path) => Ok(Interface::start(CaptureDevice::create(dev, path)?, config)),
+            None => Ok(Interface::start(dev, config)),
+        }
+    }
+
+    /// Checks the settings against `dev`, and puts them together.
+    fn config_for<D: NetDevice>(self, dev: &D) -> io::Result<Config> {
+        let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        let mut config = self.config;
+        config.mtu = self.mtu.unwrap_or(dev.mtu());
+        if config.mtu > dev.mtu() {
+            return invalid("MTU larger than the device's");
+        }
+        if config.mtu < MIN_MTU {
+            return invalid("MTU too small");
+        }
+        if config.send_buffer == 0 {
+            return invalid("send buffer is empty");
+        }
+        if config.recv_buffer == 0 || config.recv_buffer > tcp::RECVQUEUE_SIZE {
+            return invalid("receive buffer must be between 1 and 65535 bytes");
+        }
+        if config
+            .addrs
+            .iter()
+            .any(|a| a.is_unspecified() || a.is_multicast())
+        {
+            return invalid("not a unicast address");
+        }
+        config.isn = self.isn_secret.map(isn::Generator::new);
+        Ok(config)
+    }
+}
+
+/// Panics if `prefix_len` is longer than `addr`.
+fn check_prefix(addr: IpAddr, prefix_len: u8) {
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    assert!(prefix_len <= max, "prefix of {} bits", prefix_len);
 }
 
 impl Interface {
+    /// Runs the stack over the tun device `tun0`.
     pub fn new() -> io::Result<Self> {
-        let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
+        InterfaceBuilder::new().build()
+    }
 
-        let ih: InterfaceHandle = Arc::default();
+    /// Runs the stack over `dev`.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if `dev` cannot carry IPv4 packets of 68 bytes.
+    pub fn with_device<D: NetDevice>(dev: D) -> io::Result<Self> {
+        InterfaceBuilder::new().build_with_device(dev)
+    }
 
+    fn start<D: NetDevice>(dev: D, config: Config) -> Self {
+        let ih = Foobar::new(config);
         let jh = {
             let ih = ih.clone();
-            thread::spawn(move || packet_loop(nic, ih))
+            thread::spawn(move || packet_loop(dev, ih))
         };
 
-        Ok(Interface {
+        Interface {
             ih: Some(ih),
             jh: Some(jh),
-        })
+        }
+    }
+
+    /// Counts of the packets dropped so far.
+    pub fn stats(&self) -> Stats {
+        self.ih.as_ref().unwrap().manager.lock().unwrap().stats
+    }
+
+    /// Binds a UDP socket to `local`, which must be one of the interface's addresses rather
+    /// than the unspecified one. Port 0 picks an ephemeral port.
+    pub fn bind_udp(&mut self, local: impl Into<SocketAddr>) -> io::Result<UdpSocket> {
+        UdpSocket::bind(self.ih.as_ref().unwrap().clone(), local.into())
     }
 
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
         use std::collections::hash_map::Entry;
         let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
-        match cm.pending.entry(port) {
-            Entry::Vacant(v) => {
-                v.insert(VecDeque::new());
-            }
+        let l = match cm.pending.entry(port) {
+            Entry::Vacant(v) => v.insert(Arc::default()).clone(),
             Entry::Occupied(_) => {
                 return Err(io::Error::new(
                     io::ErrorKind::AddrInUse,
@@ -176,152 +1103,304 @@ impl Interface {
         drop(cm);
         Ok(TcpListener {
             port,
+            l,
             h: self.ih.as_mut().unwrap().clone(),
+            nonblocking: AtomicBool::new(false),
         })
     }
+
+    /// Opens a TCP connection from `local` to the remote host at `addr`, which must both be
+    /// IPv4 or both be IPv6.
+    ///
+    /// Blocks until the connection is established, the remote host refuses it, or the SYN has
+    /// been retransmitted too many times.
+    pub fn connect(
+        &mut self,
+        local: impl Into<IpAddr>,
+        addr: impl Into<SocketAddr>,
+    ) -> io::Result<TcpStream> {
+        self.connect_until(local.into(), addr.into(), None)
+    }
+
+    /// Like `connect`, but gives up with `io::ErrorKind::TimedOut` after `timeout`.
+    ///
+    /// Unlike `std::net::TcpStream::connect_timeout`, this is a method on the interface rather
+    /// than an associated function of `TcpStream`: a connection needs an interface to run on and
+    /// one of its addresses to come from, which std gets from the OS. A zero `timeout` is rejected
+    /// with `io::ErrorKind::InvalidInput`, as in std.
+    pub fn connect_timeout(
+        &mut self,
+        local: impl Into<IpAddr>,
+        addr: impl Into<SocketAddr>,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        let deadline = deadline_after(Some(timeout))?;
+        self.connect_until(local.into(), addr.into(), deadline)
+    }
+
+    /// Like `connect`, but returns a nonblocking stream straight away, while the handshake is
+    /// still in progress.
+    ///
+    /// Data written in the meantime is sent once the connection is established. If it never is,
+    /// reads and writes report why.
+    pub fn connect_nonblocking(
+        &mut self,
+        local: impl Into<IpAddr>,
+        addr: impl Into<SocketAddr>,
+    ) -> io::Result<TcpStream> {
+        let h = self.ih.as_mut().unwrap().clone();
+        let (quad, sock) = h
+            .manager
+            .lock()
+            .unwrap()
+            .connect(local.into(), addr.into())?;
+        let s = TcpStream::new(quad, sock, h);
+        s.set_nonblocking(true)?;
+        Ok(s)
+    }
+
+    fn connect_until(
+        &mut self,
+        local: IpAddr,
+        addr: SocketAddr,
+        deadline: Option<Instant>,
+    ) -> io::Result<TcpStream> {
+        let h = self.ih.as_mut().unwrap().clone();
+        let (quad, sock) = h.manager.lock().unwrap().connect(local, addr)?;
+
+        let mut c = sock.conn.lock().unwrap();
+        let r = loop {
+            if let Some(r) = poll_connect(&c) {
+                break r;
+            }
+            if has_passed(deadline) {
+                break Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection timed out",
+                ));
+            }
+
+            c = wait(&sock.snd_var, c, deadline);
+        };
+        drop(c);
+
+        match r {
+            Ok(()) => Ok(TcpStream::new(quad, sock, h)),
+            Err(e) => {
+                h.manager.lock().unwrap().forget(&quad, &sock);
+                Err(e)
+            }
+        }
+    }
 }
 
 pub struct TcpListener {
     port: u16,
+    l: Arc<Listener>,
     h: InterfaceHandle,
+    nonblocking: AtomicBool,
 }
 
 impl Drop for TcpListener {
     fn drop(&mut self) {
         let mut cm = self.h.manager.lock().unwrap();
+        cm.pending.remove(&self.port);
+        let pending = std::mem::take(&mut *self.l.backlog.lock().unwrap());
+        drop(cm);
 
-        let pending = cm
-            .pending
-            .remove(&self.port)
-            .expect("port closed while listener still active");
-
-        for quad in pending {
-            // TODO: terminate cm.connections[quad]
-            unimplemented!();
+        // close connections no one accepted as if they had been accepted and dropped
+        for (quad, sock) in pending {
+            drop(TcpStream::new(quad, sock, self.h.clone()));
         }
     }
 }
 
 impl TcpListener {
+    /// Moves this listener into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, `accept` returns an error of kind `io::ErrorKind::WouldBlock` instead
+    /// of waiting for a connection to arrive.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
     pub fn accept(&mut self) -> io::Result<TcpStream> {
-        let mut cm = self.h.manager.lock().unwrap();
+        let mut backlog = self.l.backlog.lock().unwrap();
         loop {
-            if let Some(quad) = cm
-                .pending
-                .get_mut(&self.port)
-                .expect("port closed while listener still active")
-                .pop_front()
-            {
-                return Ok(TcpStream {
-                    quad,
-                    h: self.h.clone(),
-                });
+            if let Some((quad, sock)) = backlog.pop_front() {
+                return Ok(TcpStream::new(quad, sock, self.h.clone()));
+            }
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no pending connections",
+                ));
             }
 
-            cm = self.h.pending_var.wait(cm).unwrap();
+            backlog = self.l.var.wait(backlog).unwrap();
         }
     }
 }
 
 pub struct TcpStream {
     quad: Quad,
+    sock: Arc<Socket>,
     h: InterfaceHandle,
+    nonblocking: AtomicBool,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
 }
 
 impl Drop for TcpStream {
     fn drop(&mut self) {
-        let mut cm = self.h.manager.lock().unwrap();
-        // TODO: send FIN on cm.connections[quad]
-        // TODO: _eventually_ remove self.quad from cm.connections
+        #[cfg(feature = "async")]
+        {
+            self.sock.waiters.lock().unwrap().tasks = Default::default();
+        }
+        let mut c = self.sock.conn.lock().unwrap();
+        c.close();
+        c.orphaned = true;
+        let terminated = c.is_terminated();
+        drop(c);
+        if terminated {
+            self.h
+                .manager
+                .lock()
+                .unwrap()
+                .forget(&self.quad, &self.sock);
+        }
     }
 }
 
 impl Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut cm = self.h.manager.lock().unwrap();
+        let deadline = deadline_after(*self.read_timeout.lock().unwrap())?;
+        let mut c = self.sock.conn.lock().unwrap();
         loop {
-            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "stream was terminated unexpectedly",
-                )
-            })?;
-            // eprintln!("trying");
-            if c.is_rcv_closed() && c.incoming.is_empty() {
-                // no more data to read, and no need to block, because there won't be any more
-                // eprintln!("connection goes away");
-                return Ok(0);
+            if let Some(r) = c.try_read(buf) {
+                return r;
             }
-            // eprintln!("connection still active");
-
-            if !c.incoming.is_empty() {
-                let mut nread = 0;
-                let (head, tail) = c.incoming.as_slices();
-                let hread = std::cmp::min(buf.len(), head.len());
-                buf[..hread].copy_from_slice(&head[..hread]);
-                nread += hread;
-                let tread = std::cmp::min(buf.len() - nread, tail.len());
-                buf[hread..(hread +tread)].copy_from_slice(&tail[..tread]);
-                nread += tread;
-                drop(c.incoming.drain(..nread));
-                return Ok(nread);
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no data available",
+                ));
+            }
+            if has_passed(deadline) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
             }
 
-            cm = self.h.rcv_var.wait(cm).unwrap();
+            c = wait(&self.sock.rcv_var, c, deadline);
         }
     }
 }
 
 impl Write for TcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "stream was terminated unexpectedly",
-            )
-        })?;
-
-        if c.unacked.len() >= SENDQUEUE_SIZE {
-            // TODO: block
-            return Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
-            ));
-        }
-
-        let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
-        c.unacked.extend(buf[..nwrite].iter());
+        let deadline = deadline_after(*self.write_timeout.lock().unwrap())?;
+        let mut c = self.sock.conn.lock().unwrap();
+        loop {
+            if let Some(r) = c.try_write(buf) {
+                return r;
+            }
 
-        // TODO: wake up writer
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "too many bytes buffered",
+                ));
+            }
+            if has_passed(deadline) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "write timed out"));
+            }
 
-        Ok(nwrite)
+            c = wait(&self.sock.snd_var, c, deadline);
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "stream was terminated unexpectedly",
-            )
-        })?;
-
-        if c.unacked.is_empty() {
-            Ok(())
-        } else {
-            // TODO: block
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
-            ))
+        let deadline = deadline_after(*self.write_timeout.lock().unwrap())?;
+        let mut c = self.sock.conn.lock().unwrap();
+        loop {
+            if let Some(r) = c.try_flush() {
+                return r;
+            }
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "too many bytes buffered",
+                ));
+            }
+            if has_passed(deadline) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "flush timed out"));
+            }
+
+            c = wait(&self.sock.snd_var, c, deadline);
         }
     }
 }
 
 impl TcpStream {
+    fn new(quad: Quad, sock: Arc<Socket>, h: InterfaceHandle) -> Self {
+        TcpStream {
+            quad,
+            sock,
+            h,
+            nonblocking: AtomicBool::new(false),
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+        }
+    }
+
+    /// Moves this stream into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, `read`, `write` and `flush` return an error of kind
+    /// `io::ErrorKind::WouldBlock` instead of waiting for data or buffer space.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sets how long `read` may block before failing with `io::ErrorKind::WouldBlock`.
+    ///
+    /// `None` means reads block indefinitely. Passing a zero `Duration` is an error.
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        deadline_after(dur)?;
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    /// Sets how long `write` and `flush` may block before failing with
+    /// `io::ErrorKind::WouldBlock`.
+    ///
+    /// `None` means writes block indefinitely. Passing a zero `Duration` is an error.
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        deadline_after(dur)?;
+        *self.write_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.write_timeout.lock().unwrap())
+    }
+
     pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
-        // TODO: send FIN on cm.connections[quad]
-        unimplemented!()
+        if let std::net::Shutdown::Read = how {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "shutting down the read half is not supported",
+            ));
+        }
+
+        self.sock.conn.lock().unwrap().close();
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -3,7 +3,10 @@ use std::{io, thread};
 use std::io::prelude::*;
 fn main() -> io::Result<()>{
     let x = 1000;
-    let mut i = trust::Interface::new()?;
+    // the kernel's end of tun0 is 192.168.0.1; anything else on the subnet reaches us
+    let mut i = trust::InterfaceBuilder::new()
+        .kernel_address(std::net::Ipv4Addr::new(192, 168, 0, 1), 24)
+        .build()?;
     let mut l1 = i.bind(x)?;
     // let mut l2 = i.bind(9001)?;
 
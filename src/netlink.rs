@@ -0,0 +1,341 @@
+//! Configuring the kernel's side of a tun device over rtnetlink (RFC 3549), like `ip addr add`,
+//! `ip link set up` and `ip route add` would, without shelling out to them.
+//!
+//! Needs CAP_NET_ADMIN.
+
+use std::ffi::CString;
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/// Length of a `nlmsghdr`: length, type, flags, sequence number and port.
+const HEADER_LEN: usize = 16;
+
+/// Netlink messages and route attributes start on a multiple of this.
+const ALIGN: usize = 4;
+
+fn align(n: usize) -> usize {
+    n.div_ceil(ALIGN) * ALIGN
+}
+
+/// An rtnetlink socket, which sends one request at a time and waits for the kernel to
+/// acknowledge it.
+pub(crate) struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub(crate) fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Netlink {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    /// Gives the interface with index `index` the address `addr`, on a subnet of `prefix_len`
+    /// bits. Having it already is fine.
+    pub(crate) fn add_address(
+        &mut self,
+        index: u32,
+        addr: IpAddr,
+        prefix_len: u8,
+    ) -> io::Result<()> {
+        self.create(libc::RTM_NEWADDR, &address_msg(index, addr, prefix_len))
+    }
+
+    /// Brings the interface with index `index` up, with an MTU of `mtu` and a transmit queue of
+    /// `txqueuelen` packets if given.
+    pub(crate) fn set_up(
+        &mut self,
+        index: u32,
+        mtu: Option<usize>,
+        txqueuelen: Option<u32>,
+    ) -> io::Result<()> {
+        let msg = link_msg(index, mtu, txqueuelen)?;
+        self.request(libc::RTM_NEWLINK, 0, &msg)
+    }
+
+    /// Routes `dst`, a prefix of `prefix_len` bits, out of the interface with index `index`.
+    /// Having the route already is fine.
+    pub(crate) fn add_route(&mut self, index: u32, dst: IpAddr, prefix_len: u8) -> io::Result<()> {
+        self.create(libc::RTM_NEWROUTE, &route_msg(index, dst, prefix_len))
+    }
+
+    /// Sends a request to create something, which may exist already.
+    fn create(&mut self, kind: u16, msg: &[u8]) -> io::Result<()> {
+        let flags = (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16;
+        match self.request(kind, flags, msg) {
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            r => r,
+        }
+    }
+
+    /// Sends a request of type `kind`, and waits for the kernel to say how it went.
+    fn request(&mut self, kind: u16, flags: u16, msg: &[u8]) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let buf = encode(kind, flags, self.seq, msg);
+
+        let fd = self.fd.as_raw_fd();
+        let n = unsafe { libc::send(fd, buf.as_ptr().cast(), buf.len(), 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut reply = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::recv(fd, reply.as_mut_ptr().cast(), reply.len(), 0) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut at = 0;
+            let reply = &reply[..n as usize];
+            while reply.len() - at >= HEADER_LEN {
+                let u32_at = |i: usize| u32::from_ne_bytes(reply[i..i + 4].try_into().unwrap());
+                let len = u32_at(at) as usize;
+                let kind = u16::from_ne_bytes([reply[at + 4], reply[at + 5]]);
+                if len < HEADER_LEN || len > reply.len() - at {
+                    break;
+                }
+                // an acknowledgment is an error message with an error of zero
+                if kind == libc::NLMSG_ERROR as u16 && u32_at(at + 8) == self.seq && len >= 20 {
+                    return match u32_at(at + HEADER_LEN) as i32 {
+                        0 => Ok(()),
+                        errno => Err(io::Error::from_raw_os_error(-errno)),
+                    };
+                }
+                at += align(len);
+            }
+        }
+    }
+}
+
+/// A request of type `kind` carrying `msg`, with its `nlmsghdr` in front, asking the kernel to
+/// acknowledge it.
+fn encode(kind: u16, flags: u16, seq: u32, msg: &[u8]) -> Vec<u8> {
+    let flags = flags | (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16;
+    let mut buf = Vec::with_capacity(HEADER_LEN + msg.len());
+    buf.extend_from_slice(&((HEADER_LEN + msg.len()) as u32).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(&flags.to_ne_bytes());
+    buf.extend_from_slice(&seq.to_ne_bytes());
+    // the kernel fills in our port
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    buf.extend_from_slice(msg);
+    buf
+}
+
+/// The body of an RTM_NEWADDR request, for `Netlink::add_address`.
+fn address_msg(index: u32, addr: IpAddr, prefix_len: u8) -> Vec<u8> {
+    // struct ifaddrmsg: family, prefix length, flags, scope and index
+    let mut msg = vec![family(addr), prefix_len, 0, libc::RT_SCOPE_UNIVERSE];
+    msg.extend_from_slice(&index.to_ne_bytes());
+    push_attr(&mut msg, libc::IFA_LOCAL, &octets(addr));
+    push_attr(&mut msg, libc::IFA_ADDRESS, &octets(addr));
+    msg
+}
+
+/// The body of an RTM_NEWLINK request, for `Netlink::set_up`.
+fn link_msg(index: u32, mtu: Option<usize>, txqueuelen: Option<u32>) -> io::Result<Vec<u8>> {
+    // struct ifinfomsg: family, padding, type, index, flags and which flags to change
+    let mut msg = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+    msg.extend_from_slice(&index.to_ne_bytes());
+    msg.extend_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+    msg.extend_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+    if let Some(mtu) = mtu {
+        let mtu = u32::try_from(mtu).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        push_attr(&mut msg, libc::IFLA_MTU, &mtu.to_ne_bytes());
+    }
+    if let Some(len) = txqueuelen {
+        push_attr(&mut msg, libc::IFLA_TXQLEN, &len.to_ne_bytes());
+    }
+    Ok(msg)
+}
+
+/// The body of an RTM_NEWROUTE request, for `Netlink::add_route`.
+fn route_msg(index: u32, dst: IpAddr, prefix_len: u8) -> Vec<u8> {
+    // struct rtmsg: family, destination and source lengths, TOS, table, protocol, scope,
+    // type and flags
+    let mut msg = vec![
+        family(dst),
+        prefix_len,
+        0,
+        0,
+        libc::RT_TABLE_MAIN,
+        libc::RTPROT_BOOT,
+        libc::RT_SCOPE_LINK,
+        libc::RTN_UNICAST,
+    ];
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    push_attr(&mut msg, libc::RTA_DST, &octets(dst));
+    push_attr(&mut msg, libc::RTA_OIF, &index.to_ne_bytes());
+    msg
+}
+
+/// The index of the interface called `name`.
+pub(crate) fn index_of(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+fn family(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+/// Appends a route attribute of type `kind` to `msg`.
+fn push_attr(msg: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    let len = 4 + data.len();
+    msg.extend_from_slice(&(len as u16).to_ne_bytes());
+    msg.extend_from_slice(&kind.to_ne_bytes());
+    msg.extend_from_slice(data);
+    msg.resize(align(msg.len()), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// A route attribute, as the kernel expects it: length, type, then the data, padded.
+    fn attr(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut a = [
+            &(4 + data.len() as u16).to_ne_bytes()[..],
+            &kind.to_ne_bytes(),
+            data,
+        ]
+        .concat();
+        a.resize(align(a.len()), 0);
+        a
+    }
+
+    #[test]
+    fn header() {
+        let flags = (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16;
+        let buf = encode(libc::RTM_NEWADDR, flags, 7, &[1, 2, 3, 4]);
+        let expected = [
+            &20u32.to_ne_bytes()[..],
+            &libc::RTM_NEWADDR.to_ne_bytes(),
+            &((libc::NLM_F_REQUEST | libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL)
+                as u16)
+                .to_ne_bytes(),
+            &7u32.to_ne_bytes(),
+            &0u32.to_ne_bytes(),
+            &[1, 2, 3, 4],
+        ]
+        .concat();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn new_address() {
+        let addr = Ipv4Addr::new(192, 168, 0, 1);
+        let expected = [
+            &[libc::AF_INET as u8, 24, 0, libc::RT_SCOPE_UNIVERSE][..],
+            &3u32.to_ne_bytes(),
+            &attr(libc::IFA_LOCAL, &addr.octets()),
+            &attr(libc::IFA_ADDRESS, &addr.octets()),
+        ]
+        .concat();
+        assert_eq!(address_msg(3, addr.into(), 24), expected);
+
+        let addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let msg = address_msg(3, addr.into(), 64);
+        assert_eq!(&msg[..2], &[libc::AF_INET6 as u8, 64]);
+        assert_eq!(
+            &msg[8..],
+            [
+                attr(libc::IFA_LOCAL, &addr.octets()),
+                attr(libc::IFA_ADDRESS, &addr.octets())
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn new_link() {
+        let up = (libc::IFF_UP as u32).to_ne_bytes();
+        let ifinfomsg = [
+            &[libc::AF_UNSPEC as u8, 0, 0, 0][..],
+            &3u32.to_ne_bytes(),
+            &up,
+            &up,
+        ]
+        .concat();
+        assert_eq!(link_msg(3, None, None).unwrap(), ifinfomsg);
+
+        let expected = [
+            &ifinfomsg[..],
+            &attr(libc::IFLA_MTU, &9000u32.to_ne_bytes()),
+            &attr(libc::IFLA_TXQLEN, &5000u32.to_ne_bytes()),
+        ]
+        .concat();
+        assert_eq!(link_msg(3, Some(9000), Some(5000)).unwrap(), expected);
+
+        assert_eq!(
+            link_msg(3, Some(usize::MAX), None).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn new_route() {
+        let dst = Ipv4Addr::new(10, 1, 0, 0);
+        let expected = [
+            &[
+                libc::AF_INET as u8,
+                16,
+                0,
+                0,
+                libc::RT_TABLE_MAIN,
+                libc::RTPROT_BOOT,
+                libc::RT_SCOPE_LINK,
+                libc::RTN_UNICAST,
+            ][..],
+            &0u32.to_ne_bytes(),
+            &attr(libc::RTA_DST, &dst.octets()),
+            &attr(libc::RTA_OIF, &3u32.to_ne_bytes()),
+        ]
+        .concat();
+        assert_eq!(route_msg(3, dst.into(), 16), expected);
+    }
+
+    #[test]
+    fn attributes_are_padded() {
+        let mut msg = Vec::new();
+        push_attr(&mut msg, 9, &[1, 2, 3, 4, 5]);
+        push_attr(&mut msg, 10, &[6]);
+        let expected = [
+            &9u16.to_ne_bytes()[..],
+            &9u16.to_ne_bytes(),
+            &[1, 2, 3, 4, 5, 0, 0, 0],
+            &5u16.to_ne_bytes(),
+            &10u16.to_ne_bytes(),
+            &[6, 0, 0, 0],
+        ]
+        .concat();
+        assert_eq!(msg, expected);
+    }
+}
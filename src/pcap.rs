@@ -0,0 +1,343 @@
+//! Packet capture in the pcapng format, for reading in Wireshark or tcpdump.
+//!
+//! See draft-ietf-opsawg-pcapng for the layout of the blocks written here. Captures can also be
+//! read back, from pcapng or the older pcap format, for `ReplayDevice`.
+
+use crate::clock;
+use crate::device::NetDevice;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const SIMPLE_PACKET: u32 = 3;
+const ENHANCED_PACKET: u32 = 6;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+/// Packets start with an IPv4 or IPv6 header, with no link-layer header in front.
+const LINKTYPE_RAW: u16 = 101;
+/// Like `LINKTYPE_RAW`, but for IPv4 only.
+const LINKTYPE_IPV4: u16 = 228;
+
+/// Magic numbers of the pcap file header, for microsecond and nanosecond timestamps.
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+/// Whether a packet came in or went out, as encoded in the low bits of `epb_flags`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Inbound = 1,
+    Outbound = 2,
+}
+
+/// Wraps another device, and writes every packet sent or received through it to a pcapng
+/// capture.
+///
+/// Each packet is timestamped with nanosecond resolution, and marked as inbound or outbound both
+/// in its flags and in a comment. Under a `Simulation`, timestamps follow the virtual clock.
+pub struct CaptureDevice<D, W> {
+    inner: D,
+    out: W,
+    /// the wall-clock time corresponding to `started`, which timestamps are counted from
+    epoch: SystemTime,
+    started: Instant,
+}
+
+impl<D: NetDevice> CaptureDevice<D, File> {
+    /// Captures to a new file at `path`, replacing any file already there.
+    pub fn create<P: AsRef<Path>>(inner: D, path: P) -> io::Result<Self> {
+        CaptureDevice::new(inner, File::create(path)?)
+    }
+}
+
+impl<D: NetDevice, W: Write + Send + 'static> CaptureDevice<D, W> {
+    /// Captures to `out`, starting with the section and interface headers.
+    ///
+    /// Each block goes to `out` in a single `write_all`, so that a capture being written to an
+    /// unbuffered file can be followed live.
+    pub fn new(inner: D, mut out: W) -> io::Result<Self> {
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes()); // major version
+        shb.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        shb.extend_from_slice(&(-1i64).to_le_bytes()); // section length not known
+        out.write_all(&block(SECTION_HEADER, shb))?;
+
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        idb.extend_from_slice(&0u32.to_le_bytes()); // no snap length
+        push_option(&mut idb, IF_TSRESOL, &[9]); // timestamps in nanoseconds
+        push_option(&mut idb, OPT_ENDOFOPT, &[]);
+        out.write_all(&block(INTERFACE_DESCRIPTION, idb))?;
+
+        Ok(CaptureDevice {
+            inner,
+            out,
+            epoch: SystemTime::now(),
+            started: clock::now(),
+        })
+    }
+
+    /// Gives back the device and the writer the capture went to.
+    pub fn into_inner(self) -> (D, W) {
+        (self.inner, self.out)
+    }
+
+    fn record(&mut self, packet: &[u8], direction: Direction) -> io::Result<()> {
+        let since_epoch = self
+            .epoch
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            + clock::now().saturating_duration_since(self.started);
+        let ts = since_epoch.as_nanos() as u64;
+
+        let mut epb = Vec::with_capacity(packet.len() + 64);
+        epb.extend_from_slice(&0u32.to_le_bytes()); // interface id
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+        epb.extend_from_slice(packet);
+        pad(&mut epb);
+        push_option(&mut epb, EPB_FLAGS, &(direction as u32).to_le_bytes());
+        let comment: &[u8] = match direction {
+            Direction::Inbound => b"received",
+            Direction::Outbound => b"sent",
+        };
+        push_option(&mut epb, OPT_COMMENT, comment);
+        push_option(&mut epb, OPT_ENDOFOPT, &[]);
+        self.out.write_all(&block(ENHANCED_PACKET, epb))
+    }
+}
+
+impl<D: NetDevice, W: Write + Send + 'static> NetDevice for CaptureDevice<D, W> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let r = self.inner.recv(buf, timeout)?;
+        if let Some(nbytes) = r {
+            self.record(&buf[..nbytes], Direction::Inbound)?;
+        }
+        Ok(r)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.record(packet, Direction::Outbound)?;
+        self.inner.send(packet)
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn checksums_verified(&self) -> bool {
+        self.inner.checksums_verified()
+    }
+
+    fn ethertype(&self) -> Option<u16> {
+        self.inner.ethertype()
+    }
+}
+
+/// Frames `body` as a block of type `kind`, with its total length before and after.
+fn block(kind: u32, body: Vec<u8>) -> Vec<u8> {
+    let len = (body.len() + 12) as u32;
+    let mut b = Vec::with_capacity(len as usize);
+    b.extend_from_slice(&kind.to_le_bytes());
+    b.extend_from_slice(&len.to_le_bytes());
+    b.extend_from_slice(&body);
+    b.extend_from_slice(&len.to_le_bytes());
+    b
+}
+
+fn push_option(b: &mut Vec<u8>, code: u16, value: &[u8]) {
+    b.extend_from_slice(&code.to_le_bytes());
+    b.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    b.extend_from_slice(value);
+    pad(b);
+}
+
+/// Pads `b` with zeros to a multiple of 32 bits.
+fn pad(b: &mut Vec<u8>) {
+    b.resize(b.len().next_multiple_of(4), 0);
+}
+
+/// Reads every packet in a pcap or pcapng capture, along with when it was captured.
+///
+/// Only captures of raw IP packets are understood, which is what tcpdump writes for a tun
+/// device, and what `CaptureDevice` writes.
+pub(crate) fn read<R: Read>(mut capture: R) -> io::Result<Vec<(Duration, Vec<u8>)>> {
+    let mut b = Vec::new();
+    capture.read_to_end(&mut b)?;
+    if b.len() < 4 {
+        return Err(invalid("capture is too short"));
+    }
+    let magic = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    if magic == SECTION_HEADER {
+        read_pcapng(&b)
+    } else {
+        read_pcap(&b)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads integers of either byte order from a capture.
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(self, b: &[u8], at: usize) -> io::Result<u16> {
+        let b: [u8; 2] = b
+            .get(at..at + 2)
+            .ok_or_else(|| invalid("capture is truncated"))?
+            .try_into()
+            .unwrap();
+        Ok(if self.big {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    }
+
+    fn u32(self, b: &[u8], at: usize) -> io::Result<u32> {
+        let b: [u8; 4] = b
+            .get(at..at + 4)
+            .ok_or_else(|| invalid("capture is truncated"))?
+            .try_into()
+            .unwrap();
+        Ok(if self.big {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+}
+
+fn check_linktype(linktype: u16) -> io::Result<()> {
+    match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV4 => Ok(()),
+        _ => Err(invalid("capture is not of raw IP packets")),
+    }
+}
+
+fn packet_data(b: &[u8], at: usize, len: usize) -> io::Result<Vec<u8>> {
+    b.get(at..at + len)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| invalid("capture is truncated"))
+}
+
+fn read_pcap(b: &[u8]) -> io::Result<Vec<(Duration, Vec<u8>)>> {
+    let magic = [b[0], b[1], b[2], b[3]];
+    let (e, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+        (PCAP_MAGIC_MICROS, _) => (Endian { big: false }, false),
+        (PCAP_MAGIC_NANOS, _) => (Endian { big: false }, true),
+        (_, PCAP_MAGIC_MICROS) => (Endian { big: true }, false),
+        (_, PCAP_MAGIC_NANOS) => (Endian { big: true }, true),
+        _ => return Err(invalid("not a pcap or pcapng capture")),
+    };
+    check_linktype(e.u32(b, 20)? as u16)?;
+
+    let mut packets = Vec::new();
+    let mut at = 24;
+    while at < b.len() {
+        let secs = e.u32(b, at)? as u64;
+        let frac = e.u32(b, at + 4)?;
+        let caplen = e.u32(b, at + 8)? as usize;
+        let ts = if nanos {
+            Duration::new(secs, frac)
+        } else {
+            Duration::new(secs, 0) + Duration::from_micros(frac as u64)
+        };
+        packets.push((ts, packet_data(b, at + 16, caplen)?));
+        at += 16 + caplen;
+    }
+    Ok(packets)
+}
+
+fn read_pcapng(b: &[u8]) -> io::Result<Vec<(Duration, Vec<u8>)>> {
+    let mut e = Endian { big: false };
+    // timestamp units per second, for each interface in the current section
+    let mut resolutions: Vec<u64> = Vec::new();
+    let mut packets = Vec::new();
+    let mut at = 0;
+    while at < b.len() {
+        let kind = e.u32(b, at)?;
+        if kind == SECTION_HEADER {
+            // the byte order magic decides how everything up to the next section is read
+            e.big = false;
+            if e.u32(b, at + 8)? != BYTE_ORDER_MAGIC {
+                e.big = true;
+            }
+            if e.u32(b, at + 8)? != BYTE_ORDER_MAGIC {
+                return Err(invalid("bad pcapng byte order magic"));
+            }
+            resolutions.clear();
+        }
+        let len = e.u32(b, at + 4)? as usize;
+        if len < 12 || !len.is_multiple_of(4) || at + len > b.len() {
+            return Err(invalid("bad pcapng block length"));
+        }
+        let body = &b[at + 8..at + len - 4];
+
+        match kind {
+            INTERFACE_DESCRIPTION => {
+                check_linktype(e.u16(body, 0)?)?;
+                let mut resolution = 1_000_000;
+                let mut opt = 8;
+                while opt + 4 <= body.len() {
+                    let code = e.u16(body, opt)?;
+                    let olen = e.u16(body, opt + 2)? as usize;
+                    if code == OPT_ENDOFOPT {
+                        break;
+                    }
+                    if code == IF_TSRESOL && olen == 1 {
+                        let r = *body
+                            .get(opt + 4)
+                            .ok_or_else(|| invalid("capture is truncated"))?;
+                        resolution = if r & 0x80 == 0 {
+                            10u64.checked_pow(r as u32)
+                        } else {
+                            1u64.checked_shl((r & 0x7f) as u32)
+                        }
+                        .ok_or_else(|| invalid("unsupported timestamp resolution"))?;
+                    }
+                    opt += 4 + olen.next_multiple_of(4);
+                }
+                resolutions.push(resolution);
+            }
+            ENHANCED_PACKET => {
+                let resolution = *resolutions
+                    .get(e.u32(body, 0)? as usize)
+                    .ok_or_else(|| invalid("packet from an undescribed interface"))?;
+                let ts = (e.u32(body, 4)? as u64) << 32 | e.u32(body, 8)? as u64;
+                let ts = Duration::from_secs(ts / resolution)
+                    + Duration::from_nanos(
+                        ((ts % resolution) as u128 * 1_000_000_000 / resolution as u128) as u64,
+                    );
+                let caplen = e.u32(body, 12)? as usize;
+                packets.push((ts, packet_data(body, 20, caplen)?));
+            }
+            SIMPLE_PACKET => {
+                // no timestamp, so it goes along with the packet before it
+                let ts = packets.last().map_or(Duration::ZERO, |(ts, _)| *ts);
+                let len = e.u32(body, 0)? as usize;
+                let caplen = std::cmp::min(len, body.len() - 4);
+                packets.push((ts, packet_data(body, 4, caplen)?));
+            }
+            _ => {}
+        }
+        at += len;
+    }
+    Ok(packets)
+}
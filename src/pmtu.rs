@@ -0,0 +1,164 @@
+//! Path MTU discovery: finding the largest packet that gets all the way to the other end.
+//!
+//! We start out assuming the path can take whatever our device can. Packets go out with DF
+//! set, so a router that cannot forward one tells us how large its next hop is, and we go
+//! down to that (RFC 1191, RFC 8201). Where those ICMP messages are filtered, a connection
+//! that keeps timing out falls back to a size that is safe almost everywhere, then probes its
+//! way back up with the occasional larger segment (RFC 4821).
+
+use crate::clock;
+use crate::seq::SeqNum;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Smallest path MTU we believe a router about. IPv4 links can be as small as 68 bytes, but
+/// then only a forged ICMP message would claim one is (Linux stops at 552).
+const MIN_MTU_V4: usize = 576;
+/// No IPv6 link may be smaller (RFC 8200 S5).
+const MIN_MTU_V6: usize = 1280;
+
+/// What we drop to when packets vanish without a word (RFC 4821 S7.2).
+const BASE_MTU_V4: usize = 1024;
+const BASE_MTU_V6: usize = 1280;
+
+/// Common MTUs (RFC 1191 S7), to guess the next hop's by when a router does not say.
+const PLATEAUS: [usize; 11] = [
+    65535, 32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68,
+];
+
+/// How long before we try larger packets again (RFC 1191 S6.3, RFC 4821 S7.7).
+const RAISE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Probing stops once the largest size that worked and the smallest that did not are this close.
+const SEARCH_DONE: usize = 32;
+
+/// Back-to-back retransmission timeouts after which (and after every so many more) we suspect
+/// the path swallows our packets.
+pub(crate) const BLACK_HOLE_RETRIES: u32 = 2;
+
+/// The path MTU of one connection, and the search for a larger one.
+pub(crate) struct PathMtu {
+    /// largest packet we currently send
+    mtu: usize,
+    /// the device's MTU, which no packet can exceed
+    max: usize,
+    min: usize,
+    base: usize,
+    /// smallest packet size known not to get through
+    ceiling: usize,
+    /// the probe in flight: the sequence number just past it, and its size
+    probe: Option<(SeqNum, usize)>,
+    /// when to forget `ceiling`, and try larger packets again
+    raise_at: Option<Instant>,
+}
+
+impl PathMtu {
+    /// Starts out at `max`, the MTU of the device that packets to `remote` leave by.
+    pub(crate) fn new(remote: IpAddr, max: usize) -> Self {
+        let (min, base) = match remote {
+            IpAddr::V4(_) => (MIN_MTU_V4, BASE_MTU_V4),
+            IpAddr::V6(_) => (MIN_MTU_V6, BASE_MTU_V6),
+        };
+        PathMtu {
+            mtu: max,
+            max,
+            min: min.min(max),
+            base: base.min(max),
+            ceiling: max + 1,
+            probe: None,
+            raise_at: None,
+        }
+    }
+
+    pub(crate) fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Takes in a router's word that it could not forward a packet of ours, because its next
+    /// hop only takes packets up to `next_hop` bytes (or some smaller size, if it is zero).
+    pub(crate) fn on_too_big(&mut self, next_hop: usize) {
+        let next_hop = if next_hop == 0 {
+            // an old router, which does not say (RFC 1191 S5)
+            PLATEAUS.into_iter().find(|&p| p < self.mtu).unwrap_or(0)
+        } else {
+            next_hop
+        };
+        let next_hop = next_hop.max(self.min);
+        self.probe = None;
+        self.ceiling = self.ceiling.min(next_hop + 1);
+        // if not, it was one of our probes that did not fit
+        self.mtu = self.mtu.min(next_hop);
+        self.settle();
+    }
+
+    /// Takes in that packets keep disappearing, which may be because they are too large and
+    /// whatever drops them does not tell us.
+    ///
+    /// The first time, we drop to a size that works almost everywhere; if even that does not
+    /// get through, to the smallest we would ever use.
+    pub(crate) fn on_black_hole(&mut self) {
+        self.probe = None;
+        let fallback = if self.mtu > self.base {
+            self.base
+        } else {
+            self.min
+        };
+        if fallback < self.mtu {
+            self.ceiling = self.mtu;
+            self.mtu = fallback;
+            self.raise_at = None;
+        }
+    }
+
+    /// The size of probe to send next, if it is time for one.
+    pub(crate) fn probe_size(&mut self) -> Option<usize> {
+        if self.probe.is_some() {
+            return None;
+        }
+        if self.raise_at.is_some_and(|at| clock::now() >= at) {
+            self.raise_at = None;
+            self.ceiling = self.max + 1;
+        }
+        if self.ceiling - self.mtu <= SEARCH_DONE {
+            return None;
+        }
+        Some((self.mtu + self.ceiling) / 2)
+    }
+
+    /// Notes that a probe of `size` bytes went out, ending just before `end`.
+    pub(crate) fn on_probe_sent(&mut self, end: SeqNum, size: usize) {
+        self.probe = Some((end, size));
+    }
+
+    /// Takes in an acknowledgment of everything before `ackn`, which may cover a probe.
+    pub(crate) fn on_ack(&mut self, ackn: SeqNum) {
+        if let Some((end, size)) = self.probe {
+            if !ackn.before(end) {
+                self.probe = None;
+                self.mtu = size;
+                self.settle();
+            }
+        }
+    }
+
+    /// Takes in a retransmission timeout.
+    ///
+    /// Returns true if it was our probe that was lost, which says nothing about congestion.
+    pub(crate) fn on_timeout(&mut self) -> bool {
+        match self.probe.take() {
+            Some((_, size)) => {
+                self.ceiling = size;
+                self.settle();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Once the search is over, schedules the next one.
+    fn settle(&mut self) {
+        if self.ceiling - self.mtu <= SEARCH_DONE && self.raise_at.is_none() {
+            self.raise_at = Some(clock::now() + RAISE_AFTER);
+        }
+    }
+}
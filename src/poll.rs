@@ -0,0 +1,297 @@
+//! Readiness polling across many streams and listeners.
+//!
+//! A `Poller` lets one thread wait on a whole set of `TcpStream`s and `TcpListener`s at once,
+//! much like `poll(2)`. Readiness is level-triggered: `Poller::poll` reports every registered
+//! source that is ready at the time of the call, and only blocks if none of them are.
+
+use crate::{tcp, Interface, InterfaceHandle, Listener, Socket, TcpListener, TcpStream, Waiters};
+use bitflags::bitflags;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+bitflags! {
+    /// The kinds of readiness a `Poller` can wait for.
+    pub struct Interest: u8 {
+        /// A stream has data to read, or will never have any more.
+        const READABLE = 0b0001;
+        /// A stream has room in its send queue.
+        const WRITABLE = 0b0010;
+        /// A listener has a connection waiting to be accepted.
+        const ACCEPTABLE = 0b0100;
+        /// The other side of a stream has closed its half, or the connection was torn down.
+        const HUP = 0b1000;
+    }
+}
+
+/// Identifies a registered stream or listener in the events returned by `Poller::poll`.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Token(pub usize);
+
+/// A registered source that was found to be ready.
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    token: Token,
+    readiness: Interest,
+}
+
+impl Event {
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    pub fn readiness(&self) -> Interest {
+        self.readiness
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.readiness.contains(Interest::READABLE)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.readiness.contains(Interest::WRITABLE)
+    }
+
+    pub fn is_acceptable(&self) -> bool {
+        self.readiness.contains(Interest::ACCEPTABLE)
+    }
+
+    pub fn is_hup(&self) -> bool {
+        self.readiness.contains(Interest::HUP)
+    }
+}
+
+/// Something a `Poller` can watch.
+#[derive(Clone)]
+enum Source {
+    Stream(Arc<Socket>),
+    Listener(Arc<Listener>),
+}
+
+impl Source {
+    fn readiness(&self) -> Interest {
+        match self {
+            Source::Stream(sock) => {
+                let c = sock.conn.lock().unwrap();
+                let a = c.availability();
+                let mut r = Interest::empty();
+                if a.contains(tcp::Available::READ) {
+                    r |= Interest::READABLE;
+                }
+                if a.contains(tcp::Available::WRITE) {
+                    r |= Interest::WRITABLE;
+                }
+                if c.is_rcv_closed() || c.error().is_some() {
+                    r |= Interest::HUP;
+                }
+                r
+            }
+            Source::Listener(l) => {
+                if l.backlog.lock().unwrap().is_empty() {
+                    Interest::empty()
+                } else {
+                    Interest::ACCEPTABLE
+                }
+            }
+        }
+    }
+
+    fn waiters(&self) -> &Mutex<Waiters> {
+        match self {
+            Source::Stream(sock) => &sock.waiters,
+            Source::Listener(l) => &l.waiters,
+        }
+    }
+}
+
+/// Raised by `packet_loop` when one of a `Poller`'s sources may have become ready.
+#[derive(Default)]
+pub(crate) struct Signal {
+    raised: Mutex<bool>,
+    var: Condvar,
+}
+
+impl Signal {
+    pub(crate) fn raise(&self) {
+        *self.raised.lock().unwrap() = true;
+        self.var.notify_all();
+    }
+
+    fn lower(&self) {
+        *self.raised.lock().unwrap() = false;
+    }
+
+    /// Blocks until the signal is raised, or until `deadline` (if any) passes.
+    fn wait(&self, deadline: Option<Instant>) {
+        let mut raised = self.raised.lock().unwrap();
+        while !*raised && !crate::has_passed(deadline) {
+            raised = crate::wait(&self.var, raised, deadline);
+        }
+    }
+}
+
+/// The pollers watching a socket or listener.
+#[derive(Default)]
+pub(crate) struct Watchers(Vec<Arc<Signal>>);
+
+impl Watchers {
+    pub(crate) fn get(&self) -> Vec<Arc<Signal>> {
+        self.0.clone()
+    }
+
+    fn add(&mut self, signal: &Arc<Signal>) {
+        self.0.push(signal.clone());
+    }
+
+    fn remove(&mut self, signal: &Arc<Signal>) {
+        if let Some(i) = self.0.iter().position(|s| Arc::ptr_eq(s, signal)) {
+            self.0.swap_remove(i);
+        }
+    }
+}
+
+/// Waits for readiness on a set of streams and listeners belonging to one `Interface`.
+pub struct Poller {
+    h: InterfaceHandle,
+    signal: Arc<Signal>,
+    registrations: HashMap<Token, (Source, Interest)>,
+}
+
+impl Interface {
+    /// Creates a `Poller` for streams and listeners on this interface.
+    pub fn poller(&mut self) -> Poller {
+        Poller {
+            h: self.ih.as_mut().unwrap().clone(),
+            signal: Arc::default(),
+            registrations: HashMap::new(),
+        }
+    }
+}
+
+impl Poller {
+    /// Starts watching `stream` for `interest`, reporting it as `token`.
+    pub fn register_stream(
+        &mut self,
+        stream: &TcpStream,
+        token: Token,
+        interest: Interest,
+    ) -> io::Result<()> {
+        self.register(
+            &stream.h,
+            Source::Stream(stream.sock.clone()),
+            token,
+            interest,
+        )
+    }
+
+    /// Starts watching `listener` for `interest`, reporting it as `token`.
+    pub fn register_listener(
+        &mut self,
+        listener: &TcpListener,
+        token: Token,
+        interest: Interest,
+    ) -> io::Result<()> {
+        self.register(
+            &listener.h,
+            Source::Listener(listener.l.clone()),
+            token,
+            interest,
+        )
+    }
+
+    fn register(
+        &mut self,
+        h: &InterfaceHandle,
+        source: Source,
+        token: Token,
+        interest: Interest,
+    ) -> io::Result<()> {
+        if !Arc::ptr_eq(h, &self.h) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source belongs to a different interface",
+            ));
+        }
+        if self.registrations.contains_key(&token) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "token already registered",
+            ));
+        }
+
+        source.waiters().lock().unwrap().pollers.add(&self.signal);
+        self.registrations.insert(token, (source, interest));
+        Ok(())
+    }
+
+    /// Changes the interest of the source registered as `token`.
+    pub fn reregister(&mut self, token: Token, interest: Interest) -> io::Result<()> {
+        match self.registrations.get_mut(&token) {
+            Some((_, i)) => {
+                *i = interest;
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "token not registered",
+            )),
+        }
+    }
+
+    /// Stops watching the source registered as `token`.
+    pub fn deregister(&mut self, token: Token) -> io::Result<()> {
+        let (source, _) = self
+            .registrations
+            .remove(&token)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "token not registered"))?;
+        source
+            .waiters()
+            .lock()
+            .unwrap()
+            .pollers
+            .remove(&self.signal);
+        Ok(())
+    }
+
+    /// Waits until at least one registered source is ready, or until `timeout` passes.
+    ///
+    /// `events` is cleared and then filled with one entry per ready source. Returns the number of
+    /// events, which is zero if the timeout passed first.
+    pub fn poll(
+        &mut self,
+        events: &mut Vec<Event>,
+        timeout: Option<Duration>,
+    ) -> io::Result<usize> {
+        events.clear();
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            // lowered before looking, so that anything becoming ready from here on is noticed
+            self.signal.lower();
+            for (&token, (source, interest)) in &self.registrations {
+                let readiness = source.readiness() & *interest;
+                if !readiness.is_empty() {
+                    events.push(Event { token, readiness });
+                }
+            }
+            if !events.is_empty() || crate::has_passed(deadline) {
+                return Ok(events.len());
+            }
+
+            self.signal.wait(deadline);
+        }
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        for (source, _) in self.registrations.values() {
+            source
+                .waiters()
+                .lock()
+                .unwrap()
+                .pollers
+                .remove(&self.signal);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Feeding captured traffic back into the stack, to turn bug reports into regression tests.
+
+use crate::clock;
+use crate::device::NetDevice;
+use crate::ip;
+use crate::pcap::{self, Direction};
+use crate::udp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A device that plays back the packets a capture shows arriving at one address, and keeps a
+/// `Transcript` of what the stack sends in reply.
+///
+/// Packets are delivered as far apart as they were captured, starting as soon as the interface
+/// first asks for one. Run under a `Simulation`, a replay does the same thing every time, so its
+/// transcript can be compared against a golden file.
+pub struct ReplayDevice {
+    /// packets yet to be delivered, with when they are due relative to `started`
+    inbound: VecDeque<(Duration, Vec<u8>)>,
+    /// when the interface first asked for a packet
+    started: Option<Instant>,
+    mtu: usize,
+    transcript: Transcript,
+}
+
+impl ReplayDevice {
+    /// Replays the pcap or pcapng capture at `path`.
+    pub fn open<P: AsRef<Path>>(path: P, local: impl Into<IpAddr>, mtu: usize) -> io::Result<Self> {
+        ReplayDevice::new(File::open(path)?, local, mtu)
+    }
+
+    /// Replays the packets in `capture` whose destination is `local`, to an interface with the
+    /// given MTU. They are replayed whatever their protocol, fragments and ICMP included, as
+    /// the stack may have to answer any of them.
+    ///
+    /// Packets from `local` in the capture are what the stack sent when it was recorded, and are
+    /// left out.
+    pub fn new<R: Read>(capture: R, local: impl Into<IpAddr>, mtu: usize) -> io::Result<Self> {
+        let local = local.into();
+        let packets = pcap::read(capture)?;
+        let first = packets.first().map_or(Duration::ZERO, |(ts, _)| *ts);
+        let inbound = packets
+            .into_iter()
+            .filter(|(_, p)| ip::parse(p).is_ok_and(|p| p.dst == local))
+            .map(|(ts, p)| (ts.saturating_sub(first), p))
+            .collect();
+
+        Ok(ReplayDevice {
+            inbound,
+            started: None,
+            mtu,
+            transcript: Transcript {
+                started: clock::now(),
+                packets: Default::default(),
+            },
+        })
+    }
+
+    /// The record of this device's traffic, which stays up to date after the device is handed
+    /// to an `Interface`.
+    pub fn transcript(&self) -> Transcript {
+        self.transcript.clone()
+    }
+}
+
+impl NetDevice for ReplayDevice {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let deadline = clock::now() + timeout;
+        let started = *self.started.get_or_insert_with(clock::now);
+        loop {
+            let now = clock::now();
+            let due = self.inbound.front().map(|(at, _)| started + *at);
+            if due.is_some_and(|due| due <= now) {
+                let (_, packet) = self.inbound.pop_front().expect("just peeked");
+                if packet.len() > buf.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "captured packet is larger than the MTU",
+                    ));
+                }
+                buf[..packet.len()].copy_from_slice(&packet);
+                self.transcript.push(Direction::Inbound, &packet);
+                return Ok(Some(packet.len()));
+            }
+
+            if now >= deadline {
+                return Ok(None);
+            }
+            let until = due.map_or(deadline, |due| std::cmp::min(due, deadline));
+            std::thread::sleep(until - now);
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.transcript.push(Direction::Outbound, packet);
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+/// The packets a `ReplayDevice` has delivered and been sent, in order.
+///
+/// Its `Display` form has one line per packet, in the spirit of tcpdump, suitable for golden
+/// files:
+///
+/// ```text
+///    0.001 in  10.0.0.1:49152 > 10.0.0.2:7 [S] seq=0 ack=0 win=65535 len=0
+///    0.001 out 10.0.0.2:7 > 10.0.0.1:49152 [S.] seq=0 ack=1 win=65535 len=0
+/// ```
+#[derive(Clone)]
+pub struct Transcript {
+    started: Instant,
+    packets: Arc<Mutex<Vec<Record>>>,
+}
+
+struct Record {
+    /// when the packet went through, since the device was created
+    at: Duration,
+    direction: Direction,
+    packet: Vec<u8>,
+}
+
+impl Transcript {
+    fn push(&self, direction: Direction, packet: &[u8]) {
+        let at = clock::now().saturating_duration_since(self.started);
+        self.packets.lock().unwrap().push(Record {
+            at,
+            direction,
+            packet: packet.to_vec(),
+        });
+    }
+}
+
+impl fmt::Display for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for r in self.packets.lock().unwrap().iter() {
+            let direction = match r.direction {
+                Direction::Inbound => "in ",
+                Direction::Outbound => "out",
+            };
+            write!(f, "{:>8.3} {} ", r.at.as_secs_f64(), direction)?;
+            summarize(f, &r.packet)?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describes a packet the way tcpdump would, more or less.
+fn summarize(f: &mut fmt::Formatter<'_>, packet: &[u8]) -> fmt::Result {
+    let p = match ip::parse(packet) {
+        Ok(p) => p,
+        Err(e) => return write!(f, "{} bytes, {}", packet.len(), e),
+    };
+    if let Some(frag) = p.fragment {
+        return write!(
+            f,
+            "{} > {} frag id={} offset={}{} len={}",
+            p.src,
+            p.dst,
+            frag.id,
+            frag.offset,
+            if frag.more { "+" } else { "" },
+            p.payload.len()
+        );
+    }
+    if p.protocol == ip::UDP {
+        if let Ok(d) = udp::parse(&p) {
+            return write!(
+                f,
+                "{} > {} udp len={}",
+                SocketAddr::new(p.src, d.src_port),
+                SocketAddr::new(p.dst, d.dst_port),
+                d.data.len()
+            );
+        }
+    }
+    if p.protocol != ip::TCP {
+        return write!(
+            f,
+            "{} > {} proto {} len={}",
+            p.src,
+            p.dst,
+            p.protocol,
+            p.payload.len()
+        );
+    }
+    let tcph = match etherparse::TcpHeaderSlice::from_slice(p.payload) {
+        Ok(tcph) => tcph,
+        Err(_) => {
+            return write!(
+                f,
+                "{} > {} bad tcp header len={}",
+                p.src,
+                p.dst,
+                p.payload.len()
+            )
+        }
+    };
+
+    let mut flags = String::new();
+    for (set, c) in [
+        (tcph.syn(), 'S'),
+        (tcph.fin(), 'F'),
+        (tcph.rst(), 'R'),
+        (tcph.psh(), 'P'),
+        (tcph.ack(), '.'),
+    ] {
+        if set {
+            flags.push(c);
+        }
+    }
+    write!(
+        f,
+        "{} > {} [{}] seq={} ack={} win={} len={}",
+        SocketAddr::new(p.src, tcph.source_port()),
+        SocketAddr::new(p.dst, tcph.destination_port()),
+        flags,
+        tcph.sequence_number(),
+        tcph.acknowledgment_number(),
+        tcph.window_size(),
+        p.payload.len() - tcph.slice().len()
+    )
+}
@@ -0,0 +1,150 @@
+//! TCP sequence numbers, which count modulo 2^32.
+
+use std::ops::{Add, AddAssign, Sub};
+
+/// A sequence (or acknowledgment) number.
+///
+/// Sequence numbers wrap around, so whether one comes before another depends on which way
+/// round the circle is shorter (RFC 9293 S3.4, RFC 1982): `a` is before `b` if `b` is less than
+/// 2^31 ahead of `a`. That is no total order, so there is `before` instead of `PartialOrd`.
+/// Numbers exactly 2^31 apart are neither before nor after each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct SeqNum(u32);
+
+impl SeqNum {
+    /// True if `self` comes strictly before `other`.
+    pub(crate) fn before(self, other: SeqNum) -> bool {
+        // From RFC1323:
+        //     TCP determines if a data segment is "old" or "new" by testing
+        //     whether its sequence number is within 2**31 bytes of the left edge
+        //     of the window, and if it is not, discarding the data as "old".  To
+        //     insure that new data is never mistakenly considered old and vice-
+        //     versa, the left edge of the sender's window has to be at most
+        //     2**31 away from the right edge of the receiver's window.
+        self.0.wrapping_sub(other.0) > (1 << 31)
+    }
+
+    /// True if `self` comes strictly after `start` and strictly before `end`, counting forward
+    /// from `start`.
+    pub(crate) fn between(self, start: SeqNum, end: SeqNum) -> bool {
+        let offset = self - start;
+        offset != 0 && offset < end - start
+    }
+}
+
+impl From<u32> for SeqNum {
+    fn from(n: u32) -> Self {
+        SeqNum(n)
+    }
+}
+
+impl From<SeqNum> for u32 {
+    fn from(n: SeqNum) -> Self {
+        n.0
+    }
+}
+
+impl Add<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn add(self, n: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_add(n))
+    }
+}
+
+impl AddAssign<u32> for SeqNum {
+    fn add_assign(&mut self, n: u32) {
+        *self = *self + n;
+    }
+}
+
+impl Sub<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn sub(self, n: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_sub(n))
+    }
+}
+
+/// How far `self` is ahead of `rhs`, counting forward.
+impl Sub for SeqNum {
+    type Output = u32;
+
+    fn sub(self, rhs: SeqNum) -> u32 {
+        self.0.wrapping_sub(rhs.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeqNum;
+    use proptest::prelude::*;
+
+    const HALF: u32 = 1 << 31;
+
+    /// Sequence numbers close to where they wrap around, where mistakes show.
+    fn near_wrap() -> impl Strategy<Value = SeqNum> {
+        prop_oneof![
+            (u32::MAX - 0xffff..=u32::MAX).prop_map(SeqNum::from),
+            (0..=0xffffu32).prop_map(SeqNum::from),
+            (HALF - 0xffff..=HALF + 0xffff).prop_map(SeqNum::from),
+            any::<u32>().prop_map(SeqNum::from),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn ahead_by_less_than_half_is_after(a in near_wrap(), n in 1..HALF) {
+            prop_assert!(a.before(a + n));
+            prop_assert!(!(a + n).before(a));
+        }
+
+        #[test]
+        fn ahead_by_more_than_half_is_before(a in near_wrap(), n in HALF + 1..=u32::MAX) {
+            prop_assert!((a + n).before(a));
+            prop_assert!(!a.before(a + n));
+        }
+
+        #[test]
+        fn half_way_round_is_neither(a in near_wrap()) {
+            prop_assert!(!a.before(a + HALF));
+            prop_assert!(!(a + HALF).before(a));
+        }
+
+        #[test]
+        fn nothing_is_before_itself(a in near_wrap()) {
+            prop_assert!(!a.before(a));
+        }
+
+        #[test]
+        fn before_is_antisymmetric(a in near_wrap(), b in near_wrap()) {
+            prop_assert!(!(a.before(b) && b.before(a)));
+        }
+
+        #[test]
+        fn difference_undoes_addition(a in near_wrap(), n in any::<u32>()) {
+            prop_assert_eq!((a + n) - a, n);
+            prop_assert_eq!(a + n - n, a);
+            let mut b = a;
+            b += n;
+            prop_assert_eq!(b, a + n);
+        }
+
+        #[test]
+        fn between_is_the_open_interval(start in near_wrap(), len in any::<u32>(), i in any::<u32>()) {
+            let end = start + len;
+            let x = start + i;
+            prop_assert_eq!(x.between(start, end), i != 0 && i < len);
+        }
+
+        #[test]
+        fn between_agrees_with_before_for_short_spans(
+            start in near_wrap(),
+            len in 0..HALF,
+            x in near_wrap(),
+        ) {
+            let end = start + len;
+            prop_assert_eq!(x.between(start, end), start.before(x) && x.before(end));
+        }
+    }
+}
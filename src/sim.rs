@@ -0,0 +1,229 @@
+//! Deterministic simulation of several interfaces and their applications on one thread.
+//!
+//! A `Simulation` stands in for the threads that normally run each interface's `packet_loop`,
+//! and for the threads of the application. It steps every interface and every task in turn, and
+//! moves a virtual clock forward by a fixed tick in between. Nothing depends on the wall clock or
+//! on how the OS schedules threads, so a run is decided entirely by its seed, and a seed that
+//! makes a test fail makes it fail the same way every time.
+
+use crate::clock;
+use crate::device::NetDevice;
+use crate::impair::{ImpairedDevice, Impairment};
+use crate::pcap::CaptureDevice;
+use crate::rng::Rng;
+use crate::{tcp, Config, Foobar, Interface, InterfaceBuilder, InterfaceHandle, TICK};
+use std::io;
+use std::time::{Duration, Instant};
+
+type Task = Box<dyn FnMut() -> io::Result<()>>;
+
+/// Runs interfaces, their TCP timers and application tasks against a virtual clock.
+///
+/// Until it is dropped, every TCP timer on the current thread goes by the simulation's clock.
+/// Tasks run on this thread too, so they must not block: streams and listeners they use should
+/// be in nonblocking mode, and connections opened with `Interface::connect_nonblocking`.
+///
+/// ```
+/// # use std::io::{self, Read, Write};
+/// # use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+/// # use std::time::Duration;
+/// # use trust::{PipeDevice, Simulation};
+/// let mut sim = Simulation::new(42);
+/// let (a, b) = PipeDevice::pair(1500);
+/// let mut client = sim.add_interface(a)?;
+/// let mut server = sim.add_interface(b)?;
+///
+/// let mut l = server.bind(80)?;
+/// l.set_nonblocking(true)?;
+/// sim.spawn(move || {
+///     let mut s = l.accept()?;
+///     s.write_all(b"hello")?;
+///     s.shutdown(Shutdown::Write)
+/// });
+///
+/// let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80);
+/// let mut c = client.connect_nonblocking(Ipv4Addr::new(10, 0, 0, 1), addr)?;
+/// let mut got = Vec::new();
+/// sim.spawn(move || {
+///     c.read_to_end(&mut got)?;
+///     assert_eq!(got, b"hello");
+///     Ok(())
+/// });
+///
+/// sim.run(Duration::from_secs(10))?;
+/// # Ok::<(), io::Error>(())
+/// ```
+pub struct Simulation {
+    rng: Rng,
+    start: Instant,
+    now: Instant,
+    hosts: Vec<Host>,
+    tasks: Vec<Task>,
+}
+
+/// An interface and the device it sends and receives on.
+struct Host {
+    nic: Box<dyn NetDevice>,
+    ih: InterfaceHandle,
+    buf: Vec<u8>,
+    out: tcp::Outbox,
+}
+
+impl Host {
+    /// Does what one turn of `packet_loop` would, without waiting for anything.
+    ///
+    /// Returns `false` once the interface has been dropped, and its connections torn down.
+    fn step(&mut self) -> io::Result<bool> {
+        let running = !crate::is_dropped(&self.ih);
+        if running {
+            while let Some(nbytes) = self.nic.recv(&mut self.buf, Duration::ZERO)? {
+                let (verified, ethertype) = (self.nic.checksums_verified(), self.nic.ethertype());
+                let packet = &self.buf[..nbytes];
+                crate::on_packet(&self.ih, packet, verified, ethertype, &mut self.out)?;
+            }
+            crate::on_tick(&self.ih, &mut self.out)?;
+        } else {
+            crate::teardown(&self.ih, &mut self.out)?;
+        }
+        for segment in self.out.drain(..) {
+            self.nic.send(&segment)?;
+        }
+        Ok(running)
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        clock::set(None);
+    }
+}
+
+impl Simulation {
+    /// Starts a simulation on the current thread, with `seed` deciding every random choice.
+    ///
+    /// Panics if a simulation is already running on this thread.
+    pub fn new(seed: u64) -> Self {
+        let now = Instant::now();
+        assert!(
+            clock::set(Some(now)).is_none(),
+            "a simulation is already running on this thread"
+        );
+        Simulation {
+            rng: Rng::new(seed),
+            start: now,
+            now,
+            hosts: Vec::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Runs an interface over `dev`, driven by the simulation instead of a thread of its own.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if `dev` cannot carry IPv4 packets of 68 bytes.
+    pub fn add_interface<D: NetDevice>(&mut self, dev: D) -> io::Result<Interface> {
+        self.add_interface_with(dev, InterfaceBuilder::new())
+    }
+
+    /// Like `add_interface`, set up by `builder`.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if the settings do not make sense for `dev`.
+    pub fn add_interface_with<D: NetDevice>(
+        &mut self,
+        dev: D,
+        mut builder: InterfaceBuilder,
+    ) -> io::Result<Interface> {
+        let capture = builder.capture.take();
+        let config = builder.config_for(&dev)?;
+        match capture {
+            Some(path) => Ok(self.start(CaptureDevice::create(dev, path)?, config)),
+            None => Ok(self.start(dev, config)),
+        }
+    }
+
+    fn start<D: NetDevice>(&mut self, dev: D, config: Config) -> Interface {
+        let ih = Foobar::new(config);
+        self.hosts.push(Host {
+            buf: vec![0; dev.mtu()],
+            nic: Box::new(dev),
+            ih: ih.clone(),
+            out: tcp::Outbox::new(),
+        });
+        Interface {
+            ih: Some(ih),
+            jh: None,
+        }
+    }
+
+    /// Wraps `dev` in an `ImpairedDevice` whose seed is drawn from the simulation's, in place of
+    /// `impairment.seed`.
+    pub fn impair<D: NetDevice>(&mut self, dev: D, impairment: Impairment) -> ImpairedDevice<D> {
+        let seed = self.rng.next_u64();
+        ImpairedDevice::new(dev, Impairment { seed, ..impairment })
+    }
+
+    /// Adds an application task, which is called once every step until it is done.
+    ///
+    /// The task returns `Ok(())` once it is done, or an error of kind `io::ErrorKind::WouldBlock`
+    /// to be called again on the next step. Any other error ends the simulation, and is returned
+    /// from `step` or `run`.
+    pub fn spawn<F>(&mut self, task: F)
+    where
+        F: FnMut() -> io::Result<()> + 'static,
+    {
+        self.tasks.push(Box::new(task));
+    }
+
+    /// How much virtual time has passed since the simulation started.
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    /// Moves the clock forward by one tick, then steps every interface, then every task.
+    ///
+    /// Interfaces step in the order they were added; tasks step in an order the seed shuffles
+    /// anew each time.
+    pub fn step(&mut self) -> io::Result<()> {
+        self.now += TICK;
+        clock::set(Some(self.now));
+
+        let mut i = 0;
+        while i < self.hosts.len() {
+            if self.hosts[i].step()? {
+                i += 1;
+            } else {
+                self.hosts.remove(i);
+            }
+        }
+
+        for i in (1..self.tasks.len()).rev() {
+            let j = self.rng.below(i as u64 + 1) as usize;
+            self.tasks.swap(i, j);
+        }
+        let mut i = 0;
+        while i < self.tasks.len() {
+            match (self.tasks[i])() {
+                Ok(()) => drop(self.tasks.remove(i)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => i += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Steps until every task is done.
+    ///
+    /// Fails with `io::ErrorKind::TimedOut` if that takes more than `limit` of virtual time.
+    pub fn run(&mut self, limit: Duration) -> io::Result<()> {
+        let deadline = self.now + limit;
+        while !self.tasks.is_empty() {
+            if self.now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "simulated tasks did not finish in time",
+                ));
+            }
+            self.step()?;
+        }
+        Ok(())
+    }
+}
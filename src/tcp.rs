@@ -1,6 +1,14 @@
+use crate::clock;
+use crate::congestion::{self, CongestionControl};
+use crate::icmp;
+use crate::ip;
+use crate::pmtu::{self, PathMtu};
+use crate::seq::SeqNum;
 use bitflags::bitflags;
 use std::collections::VecDeque;
 use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
 bitflags! {
     pub(crate) struct Available: u8 {
@@ -9,60 +17,217 @@ bitflags! {
     }
 }
 
+/// Segments produced while a connection is locked, which the caller sends on to the NIC once
+/// the lock has been released.
+pub(crate) type Outbox = Vec<Vec<u8>>;
+
+/// Room taken up by the TCP header (without options) in every segment we send.
+const TCP_HEADER_LEN: usize = 20;
+
+/// Number of received bytes we are willing to buffer before closing our window, unless the
+/// interface was built with another; without window scaling, it can be no more.
+pub(crate) const RECVQUEUE_SIZE: usize = u16::MAX as usize;
+
+/// Maximum Segment Lifetime (RFC 793 S3.3); connections linger in TIME-WAIT for twice this.
+const MSL: Duration = Duration::from_secs(30);
+
+/// Bounds for the retransmission timeout (RFC 6298 S2).
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Number of retransmissions of the same segment after which we give up on the connection.
+const MAX_RETRIES: u32 = 8;
+
 #[derive(Debug)]
 enum State {
     //Listen,
+    SynSent,
     SynRcvd,
     Estab,
     FinWait1,
     FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
     TimeWait,
+    Closed,
 }
 
 impl State {
     fn is_synchronized(&self) -> bool {
         match *self {
-            State::SynRcvd => false,
-            State::Estab | State::FinWait1 | State::FinWait2 | State::TimeWait => true,
+            State::SynSent | State::SynRcvd => false,
+            State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::CloseWait
+            | State::Closing
+            | State::LastAck
+            | State::TimeWait
+            | State::Closed => true,
         }
     }
 }
 
+/// What a new connection is set up with.
+pub(crate) struct Params {
+    /// MTU of the interface's device
+    pub(crate) mtu: usize,
+    /// initial send sequence number
+    pub(crate) iss: SeqNum,
+    /// bytes written but not yet acknowledged that we hold on to before writes block
+    pub(crate) send_buffer: usize,
+    /// bytes received but not yet read that we hold on to before closing our window
+    pub(crate) recv_buffer: usize,
+    pub(crate) congestion: CongestionControl,
+}
+
 pub struct Connection {
     state: State,
     send: SendSequenceSpace,
     recv: RecvSequenceSpace,
-    ip: etherparse::Ipv4Header,
+    ip: ip::Header,
     tcp: etherparse::TcpHeader,
+    timers: Timers,
+    /// how large the segments we send may be
+    pmtu: PathMtu,
+    /// how much we may have in flight before the network pushes back
+    cwnd: congestion::Window,
+    send_buffer: usize,
+    recv_buffer: usize,
 
     pub(crate) incoming: VecDeque<u8>,
     pub(crate) unacked: VecDeque<u8>,
+
+    /// the application will not write any more, so a FIN follows the data in `unacked`
+    pub(crate) closed: bool,
+    /// sequence number of our FIN, once it has been sent
+    closed_at: Option<SeqNum>,
+    /// no `TcpStream` refers to this connection anymore, so it can go away once terminated
+    pub(crate) orphaned: bool,
+    /// why the connection was torn down, if it did not close gracefully
+    error: Option<io::ErrorKind>,
+    /// the last ICMP error about one of our segments that was not bad enough to give up over;
+    /// if we time out, this is why
+    soft_error: Option<io::ErrorKind>,
+    /// we owe the other side an acknowledgment
+    ack_needed: bool,
 }
 
 impl Connection {
     pub(crate) fn is_rcv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            // TODO: any state after rcvd FIN, so also CLOSE-WAIT, LAST-ACK, CLOSED, CLOSING
-            true
-        } else {
-            false
-        }
+        // any state after rcvd FIN
+        matches!(
+            self.state,
+            State::CloseWait | State::Closing | State::LastAck | State::TimeWait | State::Closed
+        )
+    }
+
+    pub(crate) fn is_established(&self) -> bool {
+        self.state.is_synchronized()
+    }
+
+    /// True once the connection has nothing left to do and can be forgotten.
+    pub(crate) fn is_terminated(&self) -> bool {
+        matches!(self.state, State::Closed)
     }
 
-    fn availability(&self) -> Available {
+    /// The error that tore down this connection, if it did not close gracefully.
+    pub(crate) fn error(&self) -> Option<io::Error> {
+        self.error.map(|kind| {
+            let msg = match kind {
+                io::ErrorKind::ConnectionRefused => "connection refused",
+                io::ErrorKind::ConnectionReset => "connection reset by peer",
+                io::ErrorKind::TimedOut => "connection timed out",
+                io::ErrorKind::HostUnreachable => "no route to host",
+                io::ErrorKind::NetworkUnreachable => "network is unreachable",
+                io::ErrorKind::PermissionDenied => "permission denied",
+                _ => "connection aborted",
+            };
+            io::Error::new(kind, msg)
+        })
+    }
+
+    pub(crate) fn availability(&self) -> Available {
         let mut a = Available::empty();
-        if self.is_rcv_closed() || !self.incoming.is_empty() {
+        if self.is_rcv_closed() || self.error.is_some() || !self.incoming.is_empty() {
             a |= Available::READ;
         }
-        // TODO: take into account self.state
-        // TODO: set Available::WRITE
+        if self.closed
+            || self.error.is_some()
+            || (self.state.is_synchronized() && self.unacked.len() < self.send_buffer)
+        {
+            a |= Available::WRITE;
+        }
         a
     }
+
+    /// Moves buffered incoming data into `buf`.
+    ///
+    /// Returns `None` if there is nothing to read yet, and the caller has to wait for more.
+    pub(crate) fn try_read(&mut self, buf: &mut [u8]) -> Option<io::Result<usize>> {
+        if self.incoming.is_empty() {
+            if let Some(e) = self.error() {
+                return Some(Err(e));
+            }
+            if self.is_rcv_closed() {
+                // no more data to read, and no need to block, because there won't be any more
+                return Some(Ok(0));
+            }
+            return None;
+        }
+
+        let mut nread = 0;
+        let (head, tail) = self.incoming.as_slices();
+        let hread = std::cmp::min(buf.len(), head.len());
+        buf[..hread].copy_from_slice(&head[..hread]);
+        nread += hread;
+        let tread = std::cmp::min(buf.len() - nread, tail.len());
+        buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
+        nread += tread;
+        drop(self.incoming.drain(..nread));
+        Some(Ok(nread))
+    }
+
+    /// Queues as much of `buf` as fits in the send queue.
+    ///
+    /// Returns `None` if the send queue is full, and the caller has to wait for it to drain.
+    pub(crate) fn try_write(&mut self, buf: &[u8]) -> Option<io::Result<usize>> {
+        if let Some(e) = self.error() {
+            return Some(Err(e));
+        }
+        if self.closed {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "stream was shut down for writing",
+            )));
+        }
+        if self.unacked.len() >= self.send_buffer {
+            return None;
+        }
+
+        let nwrite = std::cmp::min(buf.len(), self.send_buffer - self.unacked.len());
+        self.unacked.extend(buf[..nwrite].iter());
+        Some(Ok(nwrite))
+    }
+
+    /// Returns `None` while the peer has yet to acknowledge some of the queued data.
+    pub(crate) fn try_flush(&self) -> Option<io::Result<()>> {
+        if let Some(e) = self.error() {
+            return Some(Err(e));
+        }
+        if self.unacked.is_empty() {
+            Some(Ok(()))
+        } else {
+            None
+        }
+    }
 }
 
 /// State of the Send Sequence Space (RFC 793 S3.2 F4)
 ///
-/// ```
+/// ```text
 ///            1         2          3          4
 ///       ----------|----------|----------|----------
 ///              SND.UNA    SND.NXT    SND.UNA
@@ -73,26 +238,27 @@ impl Connection {
 /// 3 - sequence numbers allowed for new data transmission
 /// 4 - future sequence numbers which are not yet allowed
 /// ```
+#[allow(dead_code)]
 struct SendSequenceSpace {
     /// send unacknowledged
-    una: u32,
+    una: SeqNum,
     /// send next
-    nxt: u32,
+    nxt: SeqNum,
     /// send window
     wnd: u16,
     /// send urgent pointer
     up: bool,
     /// segment sequence number used for last window update
-    wl1: usize,
+    wl1: SeqNum,
     /// segment acknowledgment number used for last window update
-    wl2: usize,
+    wl2: SeqNum,
     /// initial send sequence number
-    iss: u32,
+    iss: SeqNum,
 }
 
 /// State of the Receive Sequence Space (RFC 793 S3.2 F5)
 ///
-/// ```
+/// ```text
 ///                1          2          3
 ///            ----------|----------|----------
 ///                   RCV.NXT    RCV.NXT
@@ -102,150 +268,519 @@ struct SendSequenceSpace {
 /// 2 - sequence numbers allowed for new reception
 /// 3 - future sequence numbers which are not yet allowed
 /// ```
+#[allow(dead_code)]
 struct RecvSequenceSpace {
     /// receive next
-    nxt: u32,
+    nxt: SeqNum,
     /// receive window
     wnd: u16,
     /// receive urgent pointer
     up: bool,
     /// initial receive sequence number
-    irs: u32,
+    irs: SeqNum,
 }
 
-impl Connection {
-    pub fn accept<'a>(
-        nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice<'a>,
-        tcph: etherparse::TcpHeaderSlice<'a>,
-        data: &'a [u8],
-    ) -> io::Result<Option<Self>> {
-        let buf = [0u8; 1500];
-        if !tcph.syn() {
-            // only expected SYN packet
-            return Ok(None);
+/// Retransmission and TIME-WAIT timers (RFC 6298, RFC 793 S3.9)
+struct Timers {
+    /// smoothed round-trip time
+    srtt: Option<Duration>,
+    /// round-trip time variation
+    rttvar: Duration,
+    /// retransmission timeout
+    rto: Duration,
+    /// when the oldest unacknowledged segment is due for retransmission
+    retransmit_at: Option<Instant>,
+    /// number of back-to-back retransmissions of the oldest unacknowledged segment
+    retries: u32,
+    /// segment being timed for a round-trip sample: the ack that covers it, and when it was sent
+    rtt_sample: Option<(SeqNum, Instant)>,
+    /// when TIME-WAIT ends
+    time_wait_until: Option<Instant>,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Timers {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            retransmit_at: None,
+            retries: 0,
+            rtt_sample: None,
+            time_wait_until: None,
         }
+    }
+}
 
-        let iss = 0;
-        let wnd = 1024;
-        let mut c = Connection {
-            state: State::SynRcvd,
+impl Timers {
+    /// Folds a new round-trip measurement into the retransmission timeout (RFC 6298 S2).
+    fn on_rtt_sample(&mut self, r: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = r / 2;
+                r
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(r);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                srtt * 7 / 8 + r / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+}
+
+impl Connection {
+    fn new(
+        state: State,
+        local: (IpAddr, u16),
+        remote: (IpAddr, u16),
+        params: Params,
+    ) -> io::Result<Self> {
+        let iss = params.iss;
+        let wnd = params.recv_buffer as u16;
+        let ip = ip::Header::new(local.0, remote.0, ip::TCP)?;
+        let pmtu = PathMtu::new(remote.0, params.mtu);
+        let mss = pmtu.mtu() - ip.len() - TCP_HEADER_LEN;
+        Ok(Connection {
+            state,
             send: SendSequenceSpace {
                 iss,
                 una: iss,
                 nxt: iss,
-                wnd: wnd,
+                wnd: 0,
                 up: false,
 
-                wl1: 0,
-                wl2: 0,
+                wl1: SeqNum::default(),
+                wl2: SeqNum::default(),
             },
             recv: RecvSequenceSpace {
-                irs: tcph.sequence_number(),
-                nxt: tcph.sequence_number() + 1,
-                wnd: tcph.window_size(),
+                irs: SeqNum::default(),
+                nxt: SeqNum::default(),
+                wnd,
                 up: false,
             },
-            tcp: etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), iss, wnd),
-            ip: etherparse::Ipv4Header::new(
-                0,
-                64,
-                etherparse::IpTrafficClass::Tcp,
-                [
-                    iph.destination()[0],
-                    iph.destination()[1],
-                    iph.destination()[2],
-                    iph.destination()[3],
-                ],
-                [
-                    iph.source()[0],
-                    iph.source()[1],
-                    iph.source()[2],
-                    iph.source()[3],
-                ],
-            ),
+            tcp: etherparse::TcpHeader::new(local.1, remote.1, iss.into(), wnd),
+            timers: Timers::default(),
+            pmtu,
+            cwnd: congestion::Window::new(params.congestion, mss),
+            send_buffer: params.send_buffer,
+            recv_buffer: params.recv_buffer,
+            ip,
 
             incoming: Default::default(),
             unacked: Default::default(),
-        };
+
+            closed: false,
+            closed_at: None,
+            orphaned: false,
+            error: None,
+            soft_error: None,
+            ack_needed: false,
+        })
+    }
+
+    /// Starts a passive open in answer to a SYN from `remote` to `local`.
+    pub fn accept<'a>(
+        out: &mut Outbox,
+        params: Params,
+        local: IpAddr,
+        remote: IpAddr,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        _data: &'a [u8],
+    ) -> io::Result<Option<Self>> {
+        if !tcph.syn() || tcph.ack() || tcph.rst() {
+            // only expected SYN packet
+            return Ok(None);
+        }
+
+        let mut c = Connection::new(
+            State::SynRcvd,
+            (local, tcph.destination_port()),
+            (remote, tcph.source_port()),
+            params,
+        )?;
+        c.recv.irs = tcph.sequence_number().into();
+        c.recv.nxt = c.recv.irs + 1;
+        c.send.wnd = tcph.window_size();
+        c.send.wl1 = c.recv.irs;
 
         // need to start establishing a connection
-        c.tcp.syn = true;
-        c.tcp.ack = true;
-        c.write(nic, &[])?;
+        c.transmit(out)?;
         Ok(Some(c))
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, payload: &[u8]) -> io::Result<usize> {
-        let mut buf = [0u8; 1500];
-        self.tcp.sequence_number = self.send.nxt;
-        self.tcp.acknowledgment_number = self.recv.nxt;
-
-        let size = std::cmp::min(
-            buf.len(),
-            self.tcp.header_len() as usize + self.ip.header_len() as usize + payload.len(),
-        );
-        self.ip
-            .set_payload_len(size - self.ip.header_len() as usize);
-
-        // the kernel is nice and does this for us
-        self.tcp.checksum = self
-            .tcp
-            .calc_checksum_ipv4(&self.ip, &[])
-            .expect("failed to compute checksum");
-
-        // write out the headers
-        use std::io::Write;
-        let mut unwritten = &mut buf[..];
-        self.ip.write(&mut unwritten);
-        self.tcp.write(&mut unwritten);
-        let payload_bytes = unwritten.write(payload)?;
-        let unwritten = unwritten.len();
-        self.send.nxt = self.send.nxt.wrapping_add(payload_bytes as u32);
+    /// Starts an active open from `local` towards `remote`.
+    ///
+    /// Nothing is sent until the next `on_tick`, which is where the SYN goes out.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if the addresses are of different families.
+    pub(crate) fn connect(
+        local: (IpAddr, u16),
+        remote: (IpAddr, u16),
+        params: Params,
+    ) -> io::Result<Self> {
+        Connection::new(State::SynSent, local, remote, params)
+    }
+
+    /// Queues a FIN behind whatever data is still waiting in `unacked`.
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+        if let State::SynSent = self.state {
+            // nobody to say goodbye to yet
+            self.state = State::Closed;
+        }
+    }
+
+    /// Largest amount of data we put in a single segment, so that it fits the path MTU.
+    fn mss(&self) -> usize {
+        self.mss_for(self.pmtu.mtu())
+    }
+
+    /// Largest amount of data that fits in a packet of `mtu` bytes.
+    fn mss_for(&self, mtu: usize) -> usize {
+        mtu - self.ip.len() - TCP_HEADER_LEN
+    }
+
+    /// Queues a segment starting at sequence number `seq` with at most `limit` bytes of data taken
+    /// from `unacked`, and whichever control bits are currently set in `self.tcp`.
+    fn write(&mut self, out: &mut Outbox, seq: SeqNum, limit: usize) -> io::Result<usize> {
+        self.tcp.sequence_number = seq.into();
+        self.tcp.acknowledgment_number = self.recv.nxt.into();
+        self.recv.wnd = (self.recv_buffer - self.incoming.len()) as u16;
+        self.tcp.window_size = self.recv.wnd;
+
+        // find the part of `unacked` that starts at `seq`
+        let offset = (seq - self.send.una) as usize;
+        let (mut h, mut t) = self.unacked.as_slices();
+        if h.len() >= offset {
+            h = &h[offset..];
+        } else {
+            t = &t[std::cmp::min(offset - h.len(), t.len())..];
+            h = &[];
+        }
+        let max_data = std::cmp::min(limit, h.len() + t.len());
+        let data_at = self.ip.len() + self.tcp.header_len() as usize;
+        let size = data_at + max_data;
+        let mut buf = vec![0u8; size];
+
+        // the payload may straddle both halves of the ring buffer
+        let hn = std::cmp::min(max_data, h.len());
+        let tn = max_data - hn;
+        buf[data_at..][..hn].copy_from_slice(&h[..hn]);
+        buf[data_at + hn..][..tn].copy_from_slice(&t[..tn]);
+        let payload_bytes = hn + tn;
+
+        // and finally the headers in front of it, since the tcp checksum covers the payload
+        self.ip.write(&mut self.tcp, &mut buf)?;
+
+        let mut next_seq = seq + payload_bytes as u32;
         if self.tcp.syn {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
+            next_seq += 1;
             self.tcp.syn = false;
         }
         if self.tcp.fin {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
+            self.closed_at = Some(next_seq);
+            next_seq += 1;
             self.tcp.fin = false;
         }
-        nic.send(&buf[..buf.len() - unwritten])?;
+        if self.tcp.rst {
+            self.tcp.rst = false;
+        } else if next_seq != seq {
+            let now = clock::now();
+            if self.send.nxt.before(next_seq) {
+                if seq == self.send.nxt && self.timers.rtt_sample.is_none() {
+                    self.timers.rtt_sample = Some((next_seq, now));
+                }
+                self.send.nxt = next_seq;
+            }
+            if self.timers.retransmit_at.is_none() {
+                self.timers.retransmit_at = Some(now + self.timers.rto);
+            }
+        }
+        self.ack_needed = false;
+
+        out.push(buf);
         Ok(payload_bytes)
     }
 
-    fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
+    /// Sends `<SEQ=seq><CTL=RST>`, as required when an unacceptable segment arrives for a
+    /// connection that is not yet synchronized (RFC 793 S3.4).
+    fn send_rst(&mut self, out: &mut Outbox, seq: SeqNum) -> io::Result<()> {
         self.tcp.rst = true;
-        // TODO: fix sequence numbers here
-        // If the incoming segment has an ACK field, the reset takes its
-        // sequence number from the ACK field of the segment, otherwise the
-        // reset has sequence number zero and the ACK field is set to the sum
-        // of the sequence number and segment length of the incoming segment.
-        // The connection remains in the same state.
-        //
-        // TODO: handle synchronized RST
-        // 3.  If the connection is in a synchronized state (ESTABLISHED,
-        // FIN-WAIT-1, FIN-WAIT-2, CLOSE-WAIT, CLOSING, LAST-ACK, TIME-WAIT),
-        // any unacceptable segment (out of window sequence number or
-        // unacceptible acknowledgment number) must elicit only an empty
-        // acknowledgment segment containing the current send-sequence number
-        // and an acknowledgment indicating the next sequence number expected
-        // to be received, and the connection remains in the same state.
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
-        self.write(nic, &[])?;
+        let ack = std::mem::replace(&mut self.tcp.ack, false);
+        self.write(out, seq, 0)?;
+        self.tcp.ack = ack;
+        Ok(())
+    }
+
+    /// Tears down the connection on the spot.
+    fn abort(&mut self, reason: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(reason);
+        self.timers.retransmit_at = None;
+        self.timers.time_wait_until = None;
+        self.unacked.clear();
+    }
+
+    /// Tears down the connection on the spot, and tells the other side with a reset if it still
+    /// expects to hear from us (RFC 9293 S3.10.4 ABORT call).
+    pub(crate) fn reset(&mut self, out: &mut Outbox, reason: io::ErrorKind) -> io::Result<()> {
+        if let State::SynRcvd
+        | State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::CloseWait = self.state
+        {
+            self.tcp.syn = false;
+            self.tcp.fin = false;
+            let nxt = self.send.nxt;
+            self.send_rst(out, nxt)?;
+        }
+        self.abort(reason);
+        Ok(())
+    }
+
+    /// Drives the connection's timers, and sends whatever data, control bits and
+    /// acknowledgments are due.
+    pub(crate) fn on_tick(&mut self, out: &mut Outbox) -> io::Result<()> {
+        let now = clock::now();
+
+        if let Some(until) = self.timers.time_wait_until {
+            if now >= until {
+                self.timers.time_wait_until = None;
+                self.state = State::Closed;
+            }
+        }
+
+        if let Some(at) = self.timers.retransmit_at {
+            if now >= at {
+                // a lost probe only means the path MTU is smaller than the probe, so there is
+                // no call to back off
+                if !self.pmtu.on_timeout() {
+                    if self.timers.retries >= MAX_RETRIES {
+                        self.abort(self.soft_error.unwrap_or(io::ErrorKind::TimedOut));
+                        return Ok(());
+                    }
+                    self.timers.retries += 1;
+                    self.timers.rto = std::cmp::min(self.timers.rto * 2, MAX_RTO);
+                    let in_flight = (self.send.nxt - self.send.una) as usize;
+                    let mss = self.mss();
+                    self.cwnd.on_timeout(in_flight, mss);
+                    if self.timers.retries.is_multiple_of(pmtu::BLACK_HOLE_RETRIES)
+                        && self.state.is_synchronized()
+                    {
+                        self.pmtu.on_black_hole();
+                    }
+                }
+                self.timers.rtt_sample = None;
+                self.timers.retransmit_at = None;
+
+                if self.send.nxt == self.send.una && self.send.wnd == 0 {
+                    // the peer's window is shut; probe it with a single byte
+                    let una = self.send.una;
+                    self.write(out, una, 1)?;
+                } else {
+                    // go back and resend everything from the oldest unacknowledged byte
+                    self.send.nxt = self.send.una;
+                }
+            }
+        }
+
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            // the application has made room since we last advertised a (nearly) closed window;
+            // a buffer smaller than a segment only has to be half empty (RFC 1122 S4.2.3.3)
+            let enough = std::cmp::min(self.recv_buffer.div_ceil(2), self.mss());
+            let room = self.recv_buffer - self.incoming.len();
+            if (self.recv.wnd as usize) < enough && room >= enough {
+                self.ack_needed = true;
+            }
+        }
+
+        self.transmit(out)
+    }
+
+    /// Sends as much as the connection state and the peer's window allow.
+    fn transmit(&mut self, out: &mut Outbox) -> io::Result<()> {
+        match self.state {
+            State::SynSent | State::SynRcvd => {
+                if self.send.nxt == self.send.iss {
+                    self.tcp.syn = true;
+                    self.tcp.ack = matches!(self.state, State::SynRcvd);
+                    let iss = self.send.iss;
+                    self.write(out, iss, 0)?;
+                }
+                return Ok(());
+            }
+            State::Closed => return Ok(()),
+            _ => {}
+        }
+
+        // new (or retransmitted) data
+        let data_end = self.send.una + self.unacked.len() as u32;
+        while self.send.nxt.before(data_end) {
+            let unsent = (data_end - self.send.nxt) as usize;
+            let in_flight = (self.send.nxt - self.send.una) as usize;
+            let wnd = std::cmp::min(self.send.wnd as usize, self.cwnd.get());
+            let allowed = wnd.saturating_sub(in_flight);
+            let mut n = std::cmp::min(std::cmp::min(unsent, allowed), self.mss());
+            // a segment larger than the path MTU lets through so far, to see if it fits
+            let mut probe = None;
+            if n == self.mss() {
+                if let Some(size) = self.pmtu.probe_size() {
+                    let m = self.mss_for(size);
+                    if unsent >= m && allowed >= m {
+                        n = m;
+                        probe = Some(size);
+                    }
+                }
+            }
+            if n == 0 {
+                if in_flight == 0 && self.timers.retransmit_at.is_none() {
+                    // persist timer, so that we probe the window once it fires
+                    self.timers.retransmit_at = Some(clock::now() + self.timers.rto);
+                }
+                break;
+            }
+            let nxt = self.send.nxt;
+            self.write(out, nxt, n)?;
+            if let Some(size) = probe {
+                self.pmtu.on_probe_sent(nxt + n as u32, size);
+            }
+        }
+
+        // our FIN goes out once all the data has
+        if self.closed && self.send.nxt == data_end {
+            let next = match self.state {
+                State::Estab => Some(State::FinWait1),
+                State::CloseWait => Some(State::LastAck),
+                // retransmission
+                State::FinWait1 => Some(State::FinWait1),
+                State::Closing => Some(State::Closing),
+                State::LastAck => Some(State::LastAck),
+                // already acknowledged
+                _ => None,
+            };
+            if let Some(next) = next {
+                self.tcp.fin = true;
+                self.write(out, data_end, 0)?;
+                self.state = next;
+            }
+        }
+
+        if self.ack_needed {
+            let nxt = self.send.nxt;
+            self.write(out, nxt, 0)?;
+        }
         Ok(())
     }
 
+    /// Processes an acknowledgment of everything before `ackn`.
+    fn on_ack(&mut self, ackn: SeqNum) {
+        let mut acked = (ackn - self.send.una) as usize;
+        if !self.state.is_synchronized() {
+            // our SYN occupies a sequence number, but is not in `unacked`
+            acked -= 1;
+        }
+        let acked = std::cmp::min(acked, self.unacked.len());
+        drop(self.unacked.drain(..acked));
+        let mss = self.mss();
+        self.cwnd.on_ack(acked, mss);
+        self.send.una = ackn;
+        self.pmtu.on_ack(ackn);
+
+        let now = clock::now();
+        if let Some((end, sent_at)) = self.timers.rtt_sample {
+            if !ackn.before(end) {
+                self.timers.on_rtt_sample(now - sent_at);
+                self.timers.rtt_sample = None;
+            }
+        }
+        self.timers.retries = 0;
+        // the path works after all
+        self.soft_error = None;
+        self.timers.retransmit_at = if self.send.una == self.send.nxt {
+            None
+        } else {
+            Some(now + self.timers.rto)
+        };
+    }
+
+    /// True if our FIN has been sent and acknowledged.
+    fn fin_acked(&self) -> bool {
+        self.closed_at == Some(self.send.una - 1)
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.timers.retransmit_at = None;
+        self.timers.time_wait_until = Some(clock::now() + 2 * MSL);
+    }
+
+    /// Takes in an ICMP error about the segment we sent starting at `seq`.
+    ///
+    /// A hard error aborts a connection that is still opening; anything else is kept in case
+    /// the connection times out (RFC 1122 S4.2.3.9, RFC 5461 S4).
+    pub(crate) fn on_icmp_error(&mut self, seq: SeqNum, error: icmp::Error) -> Available {
+        // only a segment that is still unacknowledged can be in trouble (RFC 5927 S4.1)
+        if seq - self.send.una >= self.send.nxt - self.send.una {
+            return self.availability();
+        }
+        if error.hard && !self.state.is_synchronized() {
+            self.abort(error.kind);
+        } else {
+            self.soft_error = Some(error.kind);
+        }
+        self.availability()
+    }
+
+    /// Takes in an ICMP message saying that the segment we sent starting at `seq` was too big
+    /// for a link with an MTU of `next_hop` (or zero, if the router did not say), and resends
+    /// what is in flight in smaller pieces.
+    pub(crate) fn on_packet_too_big(
+        &mut self,
+        out: &mut Outbox,
+        seq: SeqNum,
+        next_hop: usize,
+    ) -> io::Result<Available> {
+        if seq - self.send.una >= self.send.nxt - self.send.una {
+            return Ok(self.availability());
+        }
+        self.pmtu.on_too_big(next_hop);
+        if self.state.is_synchronized() {
+            self.send.nxt = self.send.una;
+            self.timers.rtt_sample = None;
+            self.transmit(out)?;
+        }
+        Ok(self.availability())
+    }
+
     pub(crate) fn on_packet<'a>(
         &mut self,
-        nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice<'a>,
+        out: &mut Outbox,
         tcph: etherparse::TcpHeaderSlice<'a>,
         data: &'a [u8],
     ) -> io::Result<Available> {
+        match self.state {
+            State::Closed => return Ok(self.availability()),
+            State::SynSent => {
+                self.on_packet_syn_sent(out, tcph)?;
+                return Ok(self.availability());
+            }
+            State::SynRcvd
+                if tcph.syn() && SeqNum::from(tcph.sequence_number()) == self.recv.irs =>
+            {
+                // the other side retransmitted its SYN, so our SYN-ACK must have been lost
+                self.send.nxt = self.send.iss;
+                self.transmit(out)?;
+                return Ok(self.availability());
+            }
+            _ => {}
+        }
+
         // first, check that sequence numbers are valid (RFC 793 S3.3)
-        let seqn = tcph.sequence_number();
+        let seqn = SeqNum::from(tcph.sequence_number());
         let mut slen = data.len() as u32;
         if tcph.fin() {
             slen += 1;
@@ -253,148 +788,232 @@ impl Connection {
         if tcph.syn() {
             slen += 1;
         };
-        let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+        let wend = self.recv.nxt + self.recv.wnd as u32;
         let okay = if slen == 0 {
             // zero-length segment has separate rules for acceptance
             if self.recv.wnd == 0 {
-                if seqn != self.recv.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
-                false
+                seqn == self.recv.nxt
             } else {
-                true
+                seqn.between(self.recv.nxt - 1, wend)
             }
+        } else if self.recv.wnd == 0 {
+            false
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
-                && !is_between_wrapped(
-                    self.recv.nxt.wrapping_sub(1),
-                    seqn.wrapping_add(slen - 1),
-                    wend,
-                )
-            {
-                false
-            } else {
-                true
-            }
+            seqn.between(self.recv.nxt - 1, wend)
+                || (seqn + (slen - 1)).between(self.recv.nxt - 1, wend)
         };
 
         if !okay {
-            eprintln!("NOT OKAY");
-            self.write(nic, &[])?;
+            if !tcph.rst() {
+                if tcph.fin() && matches!(self.state, State::TimeWait) {
+                    // our ack of their FIN got lost, so restart the 2 MSL timeout
+                    self.enter_time_wait();
+                }
+                self.ack_needed = true;
+                self.transmit(out)?;
+            }
             return Ok(self.availability());
         }
-        // self.recv.nxt = seqn.wrapping_add(slen);
 
-        if !tcph.ack() {
-            if tcph.syn() {
-                assert!(data.is_empty());
-                self.recv.nxt = seqn.wrapping_add(1);
+        // second, check the RST bit, only trusting an exact match (RFC 5961 S3)
+        if tcph.rst() {
+            if seqn == self.recv.nxt {
+                self.abort(io::ErrorKind::ConnectionReset);
+            } else {
+                self.ack_needed = true;
+                self.transmit(out)?;
             }
-            eprintln!("NO ACK");
             return Ok(self.availability());
         }
 
-        let ackn = tcph.acknowledgment_number();
+        // fourth, check the SYN bit, answering with a challenge ACK (RFC 5961 S4)
+        if tcph.syn() {
+            self.ack_needed = true;
+            self.transmit(out)?;
+            return Ok(self.availability());
+        }
+
+        // fifth, check the ACK field
+        if !tcph.ack() {
+            return Ok(self.availability());
+        }
+
+        let ackn = SeqNum::from(tcph.acknowledgment_number());
         if let State::SynRcvd = self.state {
-            if is_between_wrapped(
-                self.send.una.wrapping_sub(1),
-                ackn,
-                self.send.nxt.wrapping_add(1),
-            ) {
+            if ackn.between(self.send.una, self.send.nxt + 1) {
                 // must have ACKed our SYN, since we detected at least one acked byte,
                 // and we have only sent one byte (the SYN).
+                self.on_ack(ackn);
                 self.state = State::Estab;
             } else {
-                // TODO: <SEQ=SEG.ACK><CTL=RST>
+                self.send_rst(out, ackn)?;
+                return Ok(self.availability());
             }
         }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
-                self.send.una = ackn;
-            }
-            // if wrapping_lt(seqn, self.send.una) {
-
-            // }
-
-            // TODO prune self.unacked
-            // TODO if unacked empty and waiting flush, notify
-            // TODO update window
-
-            // TODO: accept data
-            // assert!(data.is_empty());
-
-            // // TODO only read stuff we haven't read
-            
-            // // TODO: wake up waiting readers
-
-            //  we dont support
-            if let State::Estab = self.state {
-                // now let's terminate the connection!
-                // TODO: needs to be stored in the retransmission queue!
-                self.tcp.fin = true;
-                // self.write(nic, &[])?;
-                self.state = State::FinWait1;
+        if self.send.nxt.before(ackn) {
+            // acknowledges something we haven't sent yet
+            self.ack_needed = true;
+            self.transmit(out)?;
+            return Ok(self.availability());
+        }
+        if ackn.between(self.send.una, self.send.nxt + 1) {
+            self.on_ack(ackn);
+        }
+        if !ackn.before(self.send.una)
+            && (self.send.wl1.before(seqn)
+                || (self.send.wl1 == seqn && !ackn.before(self.send.wl2)))
+        {
+            let was_shut = self.send.wnd == 0;
+            self.send.wnd = tcph.window_size();
+            self.send.wl1 = seqn;
+            self.send.wl2 = ackn;
+            if self.send.wnd == 0 {
+                // the peer is alive, it just has no room; keep probing
+                self.timers.retries = 0;
+            } else if was_shut && self.send.nxt != self.send.una {
+                // a probe the peer had no room for goes out again with the data behind it,
+                // rather than leave a hole until the RTO expires
+                self.send.nxt = self.send.una;
+                self.timers.rtt_sample = None;
             }
         }
 
-        if let State::FinWait1 = self.state {
-            if self.send.una == self.send.iss + 2 {
-                // our FIN has been ACKed!
-                self.state = State::FinWait2;
+        if self.fin_acked() {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(),
+                State::LastAck => {
+                    self.state = State::Closed;
+                    self.timers.retransmit_at = None;
+                    return Ok(self.availability());
+                }
+                _ => {}
             }
         }
 
+        // seventh, process the segment text
         if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            let mut unread_data_at = (self.recv.nxt - seqn) as usize;
-            if unread_data_at > data.len() {
-                assert_eq!(unread_data_at, data.len() + 1);
-                unread_data_at = 0;
+            if !data.is_empty() {
+                let unread_data_at = (self.recv.nxt - seqn) as usize;
+                if !self.recv.nxt.before(seqn) && unread_data_at < data.len() {
+                    let room = self.recv_buffer - self.incoming.len();
+                    let new = &data[unread_data_at..];
+                    let new = &new[..std::cmp::min(new.len(), room)];
+                    self.incoming.extend(new);
+                    self.recv.nxt += new.len() as u32;
+                }
+                // we don't hold on to out-of-order segments; a duplicate ack tells the peer
+                // where to resume
+                self.ack_needed = true;
             }
-            println!("reading data from {} at (rnxt {}- seqn {})from{:?}",unread_data_at, self.recv.nxt, seqn, data);
-            self.incoming.extend(&data[unread_data_at..]);
-
-            // self.recv.nxt = seqn.wrapping_add(slen);
-
-            self.recv.nxt = seqn
-                    .wrapping_add(data.len() as u32)
-                    // .wrapping_add(if tcph.syn() { 1 } else { 0 })
-                    .wrapping_add(if tcph.fin() { 1 } else { 0 });
-                    
-            self.write(nic, &[])?;
         }
 
+        // eighth, check the FIN bit
         if tcph.fin() {
-            match self.state {
-                State::FinWait2 => {
-                    // we're done with the connection!
-                    self.write(nic, &[])?;
-                    self.state = State::TimeWait;
+            self.ack_needed = true;
+            if seqn + data.len() as u32 == self.recv.nxt && !self.is_rcv_closed() {
+                self.recv.nxt += 1;
+                match self.state {
+                    State::Estab => self.state = State::CloseWait,
+                    State::FinWait1 => {
+                        if self.fin_acked() {
+                            self.enter_time_wait();
+                        } else {
+                            self.state = State::Closing;
+                        }
+                    }
+                    State::FinWait2 => self.enter_time_wait(),
+                    _ => {}
                 }
-                _ => unimplemented!(),
             }
         }
 
+        self.transmit(out)?;
         Ok(self.availability())
     }
-}
 
-fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
-    // From RFC1323:
-    //     TCP determines if a data segment is "old" or "new" by testing
-    //     whether its sequence number is within 2**31 bytes of the left edge
-    //     of the window, and if it is not, discarding the data as "old".  To
-    //     insure that new data is never mistakenly considered old and vice-
-    //     versa, the left edge of the sender's window has to be at most
-    //     2**31 away from the right edge of the receiver's window.
-    lhs.wrapping_sub(rhs) > 2 ^ 31
+    /// Segment processing in SYN-SENT (RFC 793 S3.9, "If the state is SYN-SENT").
+    fn on_packet_syn_sent(
+        &mut self,
+        out: &mut Outbox,
+        tcph: etherparse::TcpHeaderSlice<'_>,
+    ) -> io::Result<()> {
+        let seqn = SeqNum::from(tcph.sequence_number());
+        let ackn = SeqNum::from(tcph.acknowledgment_number());
+
+        // first, check the ACK bit
+        if tcph.ack() && !ackn.between(self.send.iss, self.send.nxt + 1) {
+            if !tcph.rst() {
+                self.send_rst(out, ackn)?;
+            }
+            return Ok(());
+        }
+
+        // second, check the RST bit
+        if tcph.rst() {
+            if tcph.ack() {
+                self.abort(io::ErrorKind::ConnectionRefused);
+            }
+            return Ok(());
+        }
+
+        // fourth, check the SYN bit
+        if !tcph.syn() {
+            return Ok(());
+        }
+        self.recv.irs = seqn;
+        self.recv.nxt = seqn + 1;
+        self.send.wnd = tcph.window_size();
+        self.send.wl1 = seqn;
+        self.send.wl2 = ackn;
+        self.tcp.ack = true;
+        if tcph.ack() {
+            self.on_ack(ackn);
+            self.state = State::Estab;
+            self.ack_needed = true;
+        } else {
+            // simultaneous open
+            self.state = State::SynRcvd;
+            self.send.nxt = self.send.iss;
+        }
+        self.transmit(out)
+    }
 }
 
-fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
-    wrapping_lt(start, x) && wrapping_lt(x, end)
-}
\ No newline at end of file
+/// Answers a segment that belongs to no connection with a reset, unless it is a reset itself
+/// (RFC 9293 S3.10.7.1). The segment came from `remote` to `local`.
+pub(crate) fn reset<'a>(
+    out: &mut Outbox,
+    local: IpAddr,
+    remote: IpAddr,
+    tcph: etherparse::TcpHeaderSlice<'a>,
+    data: &'a [u8],
+) -> io::Result<()> {
+    if tcph.rst() {
+        return Ok(());
+    }
+
+    let mut tcp = etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), 0, 0);
+    tcp.rst = true;
+    if tcph.ack() {
+        // <SEQ=SEG.ACK><CTL=RST>
+        tcp.sequence_number = tcph.acknowledgment_number();
+    } else {
+        // <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+        let mut slen = data.len() as u32;
+        if tcph.syn() {
+            slen += 1;
+        }
+        if tcph.fin() {
+            slen += 1;
+        }
+        tcp.ack = true;
+        tcp.acknowledgment_number = (SeqNum::from(tcph.sequence_number()) + slen).into();
+    }
+    let mut ip = ip::Header::new(local, remote, ip::TCP)?;
+    let mut buf = vec![0; ip.len() + tcp.header_len() as usize];
+    ip.write(&mut tcp, &mut buf)?;
+    out.push(buf);
+    Ok(())
+}
@@ -0,0 +1,330 @@
+//! UDP (RFC 768), on the same interface as the TCP connections.
+
+use crate::{deadline_after, has_passed, icmp, ip, wait, InterfaceHandle};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Length of the UDP header: ports, length and checksum.
+const HEADER_LEN: usize = 8;
+
+/// Most bytes of datagrams a socket holds on to before it drops new ones, like `SO_RCVBUF`.
+const RECV_BUFFER: usize = 64 * 1024;
+
+/// Most datagrams waiting to go out on an interface; past that, sending fails.
+const SEND_QUEUE_LEN: usize = 256;
+
+/// A UDP datagram that arrived.
+pub(crate) struct Datagram<'a> {
+    pub(crate) src_port: u16,
+    pub(crate) dst_port: u16,
+    pub(crate) data: &'a [u8],
+}
+
+/// Finds the ports and data of the datagram that `packet` carries.
+///
+/// Fails with `io::ErrorKind::InvalidData` if the datagram is truncated.
+pub(crate) fn parse<'a>(packet: &ip::Packet<'a>) -> io::Result<Datagram<'a>> {
+    let d = packet.payload;
+    let len = match d.get(4..6) {
+        Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+        None => 0,
+    };
+    // the IP layer may have padded the datagram, but not cut it short
+    if len < HEADER_LEN || len > d.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("UDP datagram of {} bytes claims {}", d.len(), len),
+        ));
+    }
+    Ok(Datagram {
+        src_port: u16::from_be_bytes([d[0], d[1]]),
+        dst_port: u16::from_be_bytes([d[2], d[3]]),
+        data: &d[HEADER_LEN..len],
+    })
+}
+
+/// Whether the datagram in `packet` has the right checksum, or (over IPv4 only) none at all.
+pub(crate) fn checksum_ok(packet: &ip::Packet<'_>) -> bool {
+    if packet.src.is_ipv4() && packet.payload.get(6..8) == Some(&[0, 0]) {
+        return true;
+    }
+    ip::payload_checksum_ok(packet)
+}
+
+/// A datagram from `src` to `dst`, IP header and all.
+///
+/// Fails with `io::ErrorKind::InvalidInput` if the addresses are of different families, or
+/// `data` does not fit in a datagram.
+fn build(src: SocketAddr, dst: SocketAddr, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut ip = ip::Header::new(src.ip(), dst.ip(), ip::UDP)?;
+    let len = HEADER_LEN + data.len();
+    if len > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message too long",
+        ));
+    }
+    let at = ip.len();
+    let mut buf = vec![0; at + len];
+    buf[at..at + 2].copy_from_slice(&src.port().to_be_bytes());
+    buf[at + 2..at + 4].copy_from_slice(&dst.port().to_be_bytes());
+    buf[at + 4..at + 6].copy_from_slice(&(len as u16).to_be_bytes());
+    buf[at + HEADER_LEN..].copy_from_slice(data);
+    // a checksum that comes out as zero is sent as all ones, since zero means none
+    let sum = match ip::pseudo_checksum(src.ip(), dst.ip(), ip::UDP, &buf[at..]) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    buf[at + 6..at + 8].copy_from_slice(&sum.to_be_bytes());
+    ip.write_header(&mut buf)?;
+    Ok(buf)
+}
+
+/// A bound port, shared between `packet_loop` and the `UdpSocket` for it.
+pub(crate) struct Binding {
+    state: Mutex<State>,
+    /// threads blocked receiving wait here
+    var: Condvar,
+}
+
+struct State {
+    local: SocketAddr,
+    /// where datagrams go, and the only place they are taken from, once connected
+    peer: Option<SocketAddr>,
+    /// datagrams that arrived, with where they came from
+    queue: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// bytes in `queue`
+    queued: usize,
+    /// what an ICMP error said about a datagram sent to `peer`, until the next receive
+    error: Option<io::ErrorKind>,
+}
+
+impl Binding {
+    /// Whether a datagram from `src` to `dst` is for this socket.
+    pub(crate) fn accepts(&self, src: SocketAddr, dst: IpAddr) -> bool {
+        let s = self.state.lock().unwrap();
+        s.local.ip() == dst && s.peer.is_none_or(|peer| peer == src)
+    }
+
+    /// Queues a datagram from `src`, unless the socket has too many waiting already.
+    pub(crate) fn deliver(&self, src: SocketAddr, data: &[u8]) {
+        let mut s = self.state.lock().unwrap();
+        if s.queued + data.len() > RECV_BUFFER {
+            eprintln!("udp receive buffer full, dropping datagram");
+            return;
+        }
+        s.queued += data.len();
+        s.queue.push_back((src, data.to_vec()));
+        drop(s);
+        self.var.notify_all();
+    }
+
+    /// Takes in an ICMP error about a datagram we sent to `dst`.
+    ///
+    /// As in Linux, only a connected socket hears of it, since an unconnected one could not
+    /// tell which of its datagrams it was about.
+    pub(crate) fn on_icmp_error(&self, dst: SocketAddr, error: icmp::Error) {
+        let mut s = self.state.lock().unwrap();
+        if s.peer == Some(dst) {
+            s.error = Some(error.kind);
+            drop(s);
+            self.var.notify_all();
+        }
+    }
+}
+
+/// A UDP socket, bound to a port on an `Interface`.
+///
+/// Created with `Interface::bind_udp`. The port is free again once the socket is dropped.
+pub struct UdpSocket {
+    binding: Arc<Binding>,
+    h: InterfaceHandle,
+    nonblocking: AtomicBool,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let port = self.binding.state.lock().unwrap().local.port();
+        self.h.manager.lock().unwrap().udp.remove(&port);
+    }
+}
+
+impl UdpSocket {
+    /// Binds `local`, which must be a specific address; port 0 picks an ephemeral port.
+    pub(crate) fn bind(h: InterfaceHandle, mut local: SocketAddr) -> io::Result<Self> {
+        if local.ip().is_unspecified() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot bind to the unspecified address",
+            ));
+        }
+
+        let mut cm = h.manager.lock().unwrap();
+        if !cm.config.is_local(local.ip()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "not an address of the interface",
+            ));
+        }
+        if local.port() == 0 {
+            local.set_port(cm.udp_ephemeral_port()?);
+        } else if cm.udp.contains_key(&local.port()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "port already bound",
+            ));
+        }
+        let binding = Arc::new(Binding {
+            state: Mutex::new(State {
+                local,
+                peer: None,
+                queue: VecDeque::new(),
+                queued: 0,
+                error: None,
+            }),
+            var: Condvar::new(),
+        });
+        cm.udp.insert(local.port(), binding.clone());
+        drop(cm);
+
+        Ok(UdpSocket {
+            binding,
+            h,
+            nonblocking: AtomicBool::new(false),
+            read_timeout: Mutex::new(None),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.binding.state.lock().unwrap().local)
+    }
+
+    /// The address the socket is connected to.
+    ///
+    /// Fails with `io::ErrorKind::NotConnected` if it is not.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.binding
+            .state
+            .lock()
+            .unwrap()
+            .peer
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not connected"))
+    }
+
+    /// Sends `buf` as a single datagram to `addr`, and returns how many bytes that was.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if `addr` is of the other family, or `buf` does
+    /// not fit in a packet on the interface, and with `io::ErrorKind::WouldBlock` if datagrams
+    /// are being sent faster than the device takes them.
+    pub fn send_to(&self, buf: &[u8], addr: impl Into<SocketAddr>) -> io::Result<usize> {
+        let local = self.binding.state.lock().unwrap().local;
+        let datagram = build(local, addr.into(), buf)?;
+
+        let mut cm = self.h.manager.lock().unwrap();
+        if datagram.len() > cm.config.mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message too long",
+            ));
+        }
+        if cm.udp_out.len() >= SEND_QUEUE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "too many datagrams queued",
+            ));
+        }
+        cm.udp_out.push(datagram);
+        Ok(buf.len())
+    }
+
+    /// Receives a single datagram, and returns how many bytes of it fit in `buf` (the rest is
+    /// lost) and where it came from.
+    ///
+    /// If an ICMP error came back about a datagram sent on a connected socket, fails once with
+    /// what it said, e.g. `io::ErrorKind::ConnectionRefused` for a closed port.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = deadline_after(*self.read_timeout.lock().unwrap())?;
+        let mut s = self.binding.state.lock().unwrap();
+        loop {
+            if let Some(kind) = s.error.take() {
+                return Err(io::Error::new(kind, "destination unreachable"));
+            }
+            if let Some((src, data)) = s.queue.pop_front() {
+                s.queued -= data.len();
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                return Ok((n, src));
+            }
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no datagrams available",
+                ));
+            }
+            if has_passed(deadline) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "recv timed out"));
+            }
+
+            s = wait(&self.binding.var, s, deadline);
+        }
+    }
+
+    /// Sends datagrams to `addr` by default, and only takes them from there.
+    ///
+    /// Datagrams from elsewhere that are already queued are dropped.
+    pub fn connect(&self, addr: impl Into<SocketAddr>) -> io::Result<()> {
+        let addr = addr.into();
+        let mut s = self.binding.state.lock().unwrap();
+        if addr.is_ipv4() != s.local.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "local and remote addresses are of different families",
+            ));
+        }
+        s.peer = Some(addr);
+        s.error = None;
+        s.queue.retain(|(src, _)| *src == addr);
+        s.queued = s.queue.iter().map(|(_, d)| d.len()).sum();
+        Ok(())
+    }
+
+    /// Like `send_to`, to the address the socket is connected to.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_to(buf, self.peer_addr()?)
+    }
+
+    /// Like `recv_from`, on a connected socket.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.peer_addr()?;
+        self.recv_from(buf).map(|(n, _)| n)
+    }
+
+    /// Moves this socket into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, `recv_from` and `recv` return an error of kind
+    /// `io::ErrorKind::WouldBlock` instead of waiting for a datagram to arrive.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sets how long `recv_from` and `recv` may block before failing with
+    /// `io::ErrorKind::WouldBlock`.
+    ///
+    /// `None` means they block indefinitely. Passing a zero `Duration` is an error.
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        deadline_after(dur)?;
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+}
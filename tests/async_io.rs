@@ -0,0 +1,252 @@
+//! Wakeups of tasks polling futures, and streams through `AsyncRead`/`AsyncWrite`.
+
+#![cfg(feature = "async")]
+
+use futures_io::{AsyncRead, AsyncWrite};
+use std::future::Future;
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use trust::{Interface, InterfaceBuilder, PipeDevice, Simulation, TcpStream};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// Records whether it has been woken.
+#[derive(Default)]
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Flag {
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// Adds a client and a server interface, joined by a pipe, to `sim`.
+fn interfaces(sim: &mut Simulation) -> (Interface, Interface) {
+    let (a, b) = PipeDevice::pair(1500);
+    (sim.add_interface(a).unwrap(), sim.add_interface(b).unwrap())
+}
+
+/// Steps `sim` until `flag` is raised, then polls `f` again, expecting it to be ready.
+fn wait<F: Future + Unpin>(sim: &mut Simulation, flag: &Flag, f: &mut F) -> F::Output {
+    let waker = Waker::noop();
+    let mut cx = Context::from_waker(waker);
+    for _ in 0..100 {
+        if flag.take() {
+            match Pin::new(&mut *f).poll(&mut cx) {
+                Poll::Ready(r) => return r,
+                Poll::Pending => panic!("woken, but still pending"),
+            }
+        }
+        sim.step().unwrap();
+    }
+    panic!("pending future was never woken");
+}
+
+/// Connects a client to a server whose connections take at most `recv_buffer` bytes at once,
+/// and returns both ends.
+fn connect(sim: &mut Simulation, recv_buffer: usize) -> (TcpStream, TcpStream) {
+    let (a, b) = PipeDevice::pair(1500);
+    let mut client = sim.add_interface(a).unwrap();
+    let server = InterfaceBuilder::new().recv_buffer(recv_buffer);
+    let mut server = sim.add_interface_with(b, server).unwrap();
+
+    let mut l = server.bind(9).unwrap();
+    l.set_nonblocking(true).unwrap();
+    let c = client
+        .connect_nonblocking(CLIENT, SocketAddrV4::new(SERVER, 9))
+        .unwrap();
+    for _ in 0..100 {
+        sim.step().unwrap();
+        match l.accept() {
+            Ok(s) => {
+                s.set_nonblocking(true).unwrap();
+                // the interfaces keep running in the simulation once dropped here
+                return (c, s);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("accept failed: {}", e),
+        }
+    }
+    panic!("connection was never accepted");
+}
+
+#[test]
+fn arriving_data_wakes_pending_read() {
+    let mut sim = Simulation::new(1);
+    let (mut c, mut s) = connect(&mut sim, 65535);
+    let flag = Arc::new(Flag::default());
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut buf = [0; 16];
+    assert!(Pin::new(&mut s).poll_read(&mut cx, &mut buf).is_pending());
+    for _ in 0..10 {
+        sim.step().unwrap();
+    }
+    assert!(!flag.take(), "woken with nothing to read");
+
+    assert!(matches!(
+        Pin::new(&mut c).poll_write(&mut cx, b"hello"),
+        Poll::Ready(Ok(5))
+    ));
+    for _ in 0..100 {
+        if flag.take() {
+            match Pin::new(&mut s).poll_read(&mut cx, &mut buf) {
+                Poll::Ready(Ok(n)) => return assert_eq!(&buf[..n], b"hello"),
+                r => panic!("woken, but read gave {:?}", r),
+            }
+        }
+        sim.step().unwrap();
+    }
+    panic!("pending read was never woken");
+}
+
+#[test]
+fn opening_window_wakes_pending_write() {
+    let mut sim = Simulation::new(2);
+    let (mut c, mut s) = connect(&mut sim, 1000);
+    let flag = Arc::new(Flag::default());
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    // fill the server's window and then the client's send buffer
+    let data = [7; 4096];
+    let mut sent = 0;
+    for _ in 0..200 {
+        while let Poll::Ready(r) = Pin::new(&mut c).poll_write(&mut cx, &data) {
+            sent += r.unwrap();
+        }
+        sim.step().unwrap();
+    }
+    flag.take();
+    assert!(Pin::new(&mut c).poll_write(&mut cx, &data).is_pending());
+    for _ in 0..10 {
+        sim.step().unwrap();
+    }
+    assert!(!flag.take(), "woken while the window was still closed");
+
+    // reading opens the window, and although its buffer is smaller than a segment, the server
+    // says so at once rather than leave the client's persist timer to find out
+    let mut got = Vec::new();
+    for _ in 0..100 {
+        if flag.take() {
+            assert!(matches!(
+                Pin::new(&mut c).poll_write(&mut cx, &data),
+                Poll::Ready(Ok(_))
+            ));
+            assert!(!got.is_empty() && got.len() <= sent);
+            return;
+        }
+        match s.read_to_end(&mut got) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            r => panic!("read gave {:?}", r),
+        }
+        sim.step().unwrap();
+    }
+    panic!("pending write was never woken");
+}
+
+#[test]
+fn incoming_syn_wakes_pending_accept() {
+    let mut sim = Simulation::new(3);
+    let (mut client, mut server) = interfaces(&mut sim);
+    let flag = Arc::new(Flag::default());
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut l = server.bind(9).unwrap();
+    let mut accept = l.accept_async();
+    assert!(Pin::new(&mut accept).poll(&mut cx).is_pending());
+    for _ in 0..10 {
+        sim.step().unwrap();
+    }
+    assert!(!flag.take(), "woken with nothing to accept");
+
+    let mut c = client
+        .connect_nonblocking(CLIENT, SocketAddrV4::new(SERVER, 9))
+        .unwrap();
+    let mut s = wait(&mut sim, &flag, &mut accept).unwrap();
+    assert!(matches!(
+        Pin::new(&mut c).poll_write(&mut cx, b"hi"),
+        Poll::Ready(Ok(2))
+    ));
+    for _ in 0..10 {
+        sim.step().unwrap();
+    }
+    let mut buf = [0; 16];
+    assert!(matches!(
+        Pin::new(&mut s).poll_read(&mut cx, &mut buf),
+        Poll::Ready(Ok(2))
+    ));
+}
+
+#[test]
+fn connect_async_resolves_once_established() {
+    let mut sim = Simulation::new(4);
+    let (mut client, mut server) = interfaces(&mut sim);
+    let flag = Arc::new(Flag::default());
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let _l = server.bind(9).unwrap();
+    let mut connect = client.connect_async(CLIENT, SocketAddrV4::new(SERVER, 9));
+    assert!(Pin::new(&mut connect).poll(&mut cx).is_pending());
+    let mut c = wait(&mut sim, &flag, &mut connect).unwrap();
+    assert!(matches!(
+        Pin::new(&mut c).poll_write(&mut cx, b"hi"),
+        Poll::Ready(Ok(2))
+    ));
+}
+
+#[test]
+fn refused_connect_async_fails() {
+    let mut sim = Simulation::new(5);
+    let (mut client, _server) = interfaces(&mut sim);
+    let flag = Arc::new(Flag::default());
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut connect = client.connect_async(CLIENT, SocketAddrV4::new(SERVER, 9));
+    assert!(Pin::new(&mut connect).poll(&mut cx).is_pending());
+    match wait(&mut sim, &flag, &mut connect) {
+        Ok(_) => panic!("connected without a listener"),
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+    }
+}
+
+#[test]
+fn dropping_pending_connect_forgets_it() {
+    let mut sim = Simulation::new(6);
+    // nothing on the other end of the pipe to answer the SYN
+    let (a, _b) = PipeDevice::pair(1500);
+    let builder = InterfaceBuilder::new().max_connections(1);
+    let mut client = sim.add_interface_with(a, builder).unwrap();
+    let waker = Waker::noop();
+    let mut cx = Context::from_waker(waker);
+    let addr = SocketAddrV4::new(SERVER, 9);
+
+    let mut first = client.connect_async(CLIENT, addr);
+    assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+    sim.step().unwrap();
+    let mut second = client.connect_async(CLIENT, addr);
+    assert!(
+        matches!(Pin::new(&mut second).poll(&mut cx), Poll::Ready(Err(_))),
+        "room for two connections"
+    );
+
+    // the first attempt is abandoned, which leaves room for another
+    drop(first);
+    let mut third = client.connect_async(CLIENT, addr);
+    assert!(Pin::new(&mut third).poll(&mut cx).is_pending());
+}
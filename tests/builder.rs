@@ -0,0 +1,220 @@
+//! Interfaces set up with an `InterfaceBuilder`, played against raw segments in a simulation.
+
+mod common;
+
+use common::{LOCAL, REMOTE};
+use etherparse::{IpTrafficClass, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use trust::{CongestionControl, Interface, InterfaceBuilder, NetDevice, PipeDevice, Simulation};
+
+fn setup(builder: InterfaceBuilder) -> (Simulation, Interface, PipeDevice) {
+    common::setup_with(builder.address(LOCAL))
+}
+
+/// A segment from `REMOTE:port` to `dst`, with `build` setting its flags.
+fn segment(dst: Ipv4Addr, port: u16, seq: u32, build: impl FnOnce(&mut TcpHeader)) -> Vec<u8> {
+    let mut tcp = TcpHeader::new(port, 80, seq, 65535);
+    build(&mut tcp);
+    tcp.checksum = tcp
+        .calc_checksum_ipv4_raw(REMOTE.octets(), dst.octets(), &[])
+        .unwrap();
+    let ip = Ipv4Header::new(
+        tcp.header_len(),
+        64,
+        IpTrafficClass::Tcp,
+        REMOTE.octets(),
+        dst.octets(),
+    );
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    tcp.write(&mut packet).unwrap();
+    packet
+}
+
+fn syn(dst: Ipv4Addr, port: u16) -> Vec<u8> {
+    segment(dst, port, 1000, |tcp| tcp.syn = true)
+}
+
+/// Steps the simulation for `steps` ticks, and returns the TCP headers of whatever the
+/// interface sent, with the length of their data.
+fn run(sim: &mut Simulation, wire: &mut PipeDevice, steps: usize) -> Vec<(TcpHeader, usize)> {
+    common::run(sim, wire, steps)
+        .iter()
+        .map(|packet| {
+            let ip = Ipv4HeaderSlice::from_slice(packet).unwrap();
+            let tcp = TcpHeaderSlice::from_slice(&packet[ip.slice().len()..]).unwrap();
+            let len = ip.total_len() as usize - ip.slice().len() - tcp.slice().len();
+            (tcp.to_header(), len)
+        })
+        .collect()
+}
+
+#[test]
+fn only_takes_packets_for_its_addresses() {
+    let (mut sim, mut iface, mut wire) = setup(InterfaceBuilder::new());
+    let _l = iface.bind(80).unwrap();
+
+    wire.send(&syn(Ipv4Addr::new(192, 0, 2, 3), 40000)).unwrap();
+    assert!(run(&mut sim, &mut wire, 10).is_empty());
+    assert_eq!(iface.stats().misaddressed, 1);
+
+    wire.send(&syn(LOCAL, 40000)).unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert!(sent.len() == 1 && sent[0].0.syn && sent[0].0.ack);
+
+    // nor can sockets claim addresses that are not the interface's
+    let other = Ipv4Addr::new(192, 0, 2, 3);
+    assert_eq!(
+        iface.bind_udp((other, 53)).err().map(|e| e.kind()),
+        Some(io::ErrorKind::AddrNotAvailable)
+    );
+    assert_eq!(
+        iface
+            .connect_nonblocking(other, SocketAddrV4::new(REMOTE, 80))
+            .err()
+            .map(|e| e.kind()),
+        Some(io::ErrorKind::AddrNotAvailable)
+    );
+}
+
+/// The ISNs an interface set up by `builder` picks for connections from two ports.
+fn isns(builder: InterfaceBuilder) -> Vec<u32> {
+    let (mut sim, mut iface, mut wire) = setup(builder);
+    let _l = iface.bind(80).unwrap();
+
+    let mut isns = Vec::new();
+    for port in [40000, 40001] {
+        wire.send(&syn(LOCAL, port)).unwrap();
+        let sent = run(&mut sim, &mut wire, 10);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.window_size, 4096);
+        isns.push(sent[0].0.sequence_number);
+    }
+    isns
+}
+
+#[test]
+fn initial_sequence_numbers_and_windows() {
+    let builder = InterfaceBuilder::new().recv_buffer(4096);
+    // in a simulation, without a secret, every connection starts at zero
+    assert_eq!(isns(builder.clone()), [0, 0]);
+
+    // with one, neither starts at zero, nor where the other does, but both do again next time
+    let secret = builder.isn_secret(*b"sixteen byte key");
+    let first = isns(secret.clone());
+    assert!(
+        first[0] != 0 && first[1] != 0 && first[0] != first[1],
+        "{:?}",
+        first
+    );
+    assert_eq!(isns(secret), first);
+}
+
+#[test]
+fn random_initial_sequence_numbers_by_default() {
+    // outside a simulation, each interface draws a secret of its own
+    let mut isns = Vec::new();
+    for _ in 0..2 {
+        let (a, mut wire) = PipeDevice::pair(1500);
+        let mut iface = InterfaceBuilder::new()
+            .address(LOCAL)
+            .build_with_device(a)
+            .unwrap();
+        let _l = iface.bind(80).unwrap();
+        wire.send(&syn(LOCAL, 40000)).unwrap();
+
+        let mut buf = [0; 1500];
+        let n = wire
+            .recv(&mut buf, Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        let ip = Ipv4HeaderSlice::from_slice(&buf[..n]).unwrap();
+        let tcp = TcpHeaderSlice::from_slice(&buf[ip.slice().len()..n]).unwrap();
+        assert!(tcp.syn() && tcp.ack());
+        isns.push(tcp.sequence_number());
+    }
+    assert_ne!(isns[0], isns[1]);
+}
+
+#[test]
+fn limits_connections() {
+    {
+        let (mut sim, mut iface, mut wire) = setup(InterfaceBuilder::new().backlog(2));
+        let _l = iface.bind(80).unwrap();
+
+        // the third SYN finds the backlog full, and goes unanswered
+        for port in [40000, 40001, 40002] {
+            wire.send(&syn(LOCAL, port)).unwrap();
+        }
+        assert_eq!(run(&mut sim, &mut wire, 10).len(), 2);
+    }
+
+    let (mut sim, mut iface, mut wire) = setup(InterfaceBuilder::new().max_connections(1));
+    let _l = iface.bind(80).unwrap();
+    wire.send(&syn(LOCAL, 40000)).unwrap();
+    wire.send(&syn(LOCAL, 40001)).unwrap();
+    assert_eq!(run(&mut sim, &mut wire, 10).len(), 1);
+    assert!(iface
+        .connect_nonblocking(LOCAL, SocketAddrV4::new(REMOTE, 80))
+        .is_err());
+}
+
+#[test]
+fn slow_start() {
+    let (mut sim, mut iface, mut wire) = setup(
+        InterfaceBuilder::new()
+            .congestion_control(CongestionControl::Reno)
+            .send_buffer(64 * 1024),
+    );
+    let mut l = iface.bind(80).unwrap();
+
+    wire.send(&syn(LOCAL, 40000)).unwrap();
+    let synack = run(&mut sim, &mut wire, 10)[0].0.clone();
+    let iss = synack.sequence_number;
+    wire.send(&segment(LOCAL, 40000, 1001, |tcp| {
+        tcp.ack = true;
+        tcp.acknowledgment_number = iss.wrapping_add(1);
+    }))
+    .unwrap();
+    run(&mut sim, &mut wire, 10);
+    let mut s = l.accept().unwrap();
+    s.set_nonblocking(true).unwrap();
+    assert_eq!(s.write(&[0; 32 * 1024]).unwrap(), 32 * 1024);
+
+    // three segments of 1460 bytes in the initial window, then nothing until they are acked
+    let sent = run(&mut sim, &mut wire, 10);
+    assert_eq!(
+        sent.len(),
+        3,
+        "{:?}",
+        sent.iter().map(|s| s.1).collect::<Vec<_>>()
+    );
+    assert!(sent.iter().all(|(_, len)| *len == 1460));
+
+    // each ack in slow start lets out two more
+    wire.send(&segment(LOCAL, 40000, 1001, |tcp| {
+        tcp.ack = true;
+        tcp.acknowledgment_number = iss.wrapping_add(1 + 1460);
+    }))
+    .unwrap();
+    assert_eq!(run(&mut sim, &mut wire, 10).len(), 2);
+}
+
+#[test]
+fn rejects_settings_that_do_not_fit() {
+    let mut sim = Simulation::new(0);
+    for builder in [
+        InterfaceBuilder::new().mtu(9000),
+        InterfaceBuilder::new().mtu(40),
+        InterfaceBuilder::new().recv_buffer(100_000),
+        InterfaceBuilder::new().address(Ipv4Addr::UNSPECIFIED),
+    ] {
+        let (a, _b) = PipeDevice::pair(1500);
+        assert_eq!(
+            sim.add_interface_with(a, builder).err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidInput)
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Checksums on what arrives: packets that were damaged on the way are dropped and counted,
+//! unless the device says it has checked them already.
+
+mod common;
+
+use common::{run, setup, setup_over, LOCAL, REMOTE};
+use etherparse::{IpTrafficClass, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddrV4};
+use std::rc::Rc;
+use std::time::Duration;
+use trust::{Impairment, NetDevice, PipeDevice, Simulation, Stats};
+
+/// A device whose every packet has had its checksums checked, as if by hardware.
+struct Offloaded(PipeDevice);
+
+impl NetDevice for Offloaded {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        self.0.recv(buf, timeout)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.0.send(packet)
+    }
+
+    fn mtu(&self) -> usize {
+        self.0.mtu()
+    }
+
+    fn checksums_verified(&self) -> bool {
+        true
+    }
+}
+
+/// A SYN to port 80, with its checksum off by `error`.
+fn syn(error: u16) -> Vec<u8> {
+    let mut tcp = TcpHeader::new(40000, 80, 1000, 65535);
+    tcp.syn = true;
+    tcp.checksum = tcp
+        .calc_checksum_ipv4_raw(REMOTE.octets(), LOCAL.octets(), &[])
+        .unwrap()
+        .wrapping_add(error);
+    let ip = Ipv4Header::new(
+        tcp.header_len(),
+        64,
+        IpTrafficClass::Tcp,
+        REMOTE.octets(),
+        LOCAL.octets(),
+    );
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    tcp.write(&mut packet).unwrap();
+    packet
+}
+
+fn is_synack(packet: &[u8]) -> bool {
+    let ip = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let tcp = TcpHeaderSlice::from_slice(&packet[ip.slice().len()..]).unwrap();
+    tcp.syn() && tcp.ack()
+}
+
+#[test]
+fn bad_checksums_are_dropped_and_counted() {
+    let (mut sim, mut iface, mut wire) = setup();
+    let _l = iface.bind(80).unwrap();
+
+    wire.send(&syn(1)).unwrap();
+    assert!(run(&mut sim, &mut wire, 10).is_empty());
+
+    let mut bad_header = syn(0);
+    bad_header[8] -= 1; // the TTL, without fixing up the checksum
+    wire.send(&bad_header).unwrap();
+    assert!(run(&mut sim, &mut wire, 10).is_empty());
+
+    let stats = iface.stats();
+    assert_eq!(
+        (stats.bad_ip_checksums, stats.bad_tcp_checksums),
+        (1, 1),
+        "{:?}",
+        stats
+    );
+
+    wire.send(&syn(0)).unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert!(sent.len() == 1 && is_synack(&sent[0]));
+    assert_eq!(iface.stats(), stats);
+}
+
+#[test]
+fn verified_packets_are_trusted() {
+    let (mut sim, mut iface, mut wire) = setup_over(1500, Offloaded);
+    let _l = iface.bind(80).unwrap();
+
+    // e.g. a checksum left for the device to fill in, on a packet that never left the host
+    wire.send(&syn(1)).unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert!(sent.len() == 1 && is_synack(&sent[0]));
+    assert_eq!(iface.stats(), Stats::default());
+}
+
+#[test]
+fn corruption_does_not_reach_the_application() {
+    let mut sim = Simulation::new(0);
+    let (a, b) = PipeDevice::pair(1500);
+    let flaky = Impairment {
+        corrupt: 0.05,
+        ..Default::default()
+    };
+    let a = sim.impair(a, flaky.clone());
+    let b = sim.impair(b, flaky);
+    let mut client_if = sim.add_interface(a).unwrap();
+    let mut server_if = sim.add_interface(b).unwrap();
+
+    let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let got = Rc::new(RefCell::new(Vec::new()));
+
+    let mut l = server_if.bind(9).unwrap();
+    l.set_nonblocking(true).unwrap();
+    let mut accepted = None;
+    let sink = got.clone();
+    sim.spawn(move || {
+        if accepted.is_none() {
+            let s = l.accept()?;
+            s.set_nonblocking(true)?;
+            accepted = Some(s);
+        }
+        accepted
+            .as_mut()
+            .unwrap()
+            .read_to_end(&mut sink.borrow_mut())?;
+        Ok(())
+    });
+
+    let mut c = client_if
+        .connect_nonblocking(REMOTE, SocketAddrV4::new(LOCAL, 9))
+        .unwrap();
+    let mut sent = 0;
+    let expected = data.clone();
+    sim.spawn(move || {
+        while sent < data.len() {
+            sent += c.write(&data[sent..])?;
+        }
+        c.flush()?;
+        c.shutdown(Shutdown::Write)
+    });
+
+    sim.run(Duration::from_secs(600)).unwrap();
+    assert!(*got.borrow() == expected, "data was mangled in transit");
+    let stats = server_if.stats();
+    assert!(
+        stats.bad_ip_checksums + stats.bad_tcp_checksums > 0,
+        "nothing was corrupted"
+    );
+}
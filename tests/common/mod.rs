@@ -0,0 +1,98 @@
+//! Fixtures for the tests that play raw packets against an interface in a simulation.
+
+// each test crate uses only some of them
+#![allow(dead_code)]
+
+use etherparse::{IpTrafficClass, Ipv4Header};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use trust::{Interface, InterfaceBuilder, NetDevice, PipeDevice, Simulation};
+
+pub const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+pub const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// An interface in a simulation, and the other end of its link.
+pub fn setup() -> (Simulation, Interface, PipeDevice) {
+    setup_over(1500, |dev| dev)
+}
+
+/// Like `setup`, with the interface set up by `builder`.
+pub fn setup_with(builder: InterfaceBuilder) -> (Simulation, Interface, PipeDevice) {
+    let mut sim = Simulation::new(0);
+    let (a, b) = PipeDevice::pair(1500);
+    let iface = sim.add_interface_with(a, builder).unwrap();
+    (sim, iface, b)
+}
+
+/// An interface in a simulation, on whatever `device` makes of its end of a link that carries
+/// `mtu` bytes, and the other end of that link.
+pub fn setup_over<D: NetDevice>(
+    mtu: usize,
+    device: impl FnOnce(PipeDevice) -> D,
+) -> (Simulation, Interface, PipeDevice) {
+    let mut sim = Simulation::new(0);
+    let (a, b) = PipeDevice::pair(mtu);
+    let iface = sim.add_interface(device(a)).unwrap();
+    (sim, iface, b)
+}
+
+/// Steps the simulation for `steps` ticks, and returns whatever the interface sent.
+pub fn run(sim: &mut Simulation, wire: &mut PipeDevice, steps: usize) -> Vec<Vec<u8>> {
+    let mut sent = Vec::new();
+    let mut buf = vec![0; wire.mtu()];
+    for _ in 0..steps {
+        sim.step().unwrap();
+        while let Some(n) = wire.recv(&mut buf, Duration::ZERO).unwrap() {
+            sent.push(buf[..n].to_vec());
+        }
+    }
+    sent
+}
+
+/// The Internet checksum of `bytes`.
+pub fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = bytes
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], w.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// An ICMP echo request carrying `data`.
+pub fn ping(data: &[u8]) -> Vec<u8> {
+    let mut msg = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1];
+    msg.extend_from_slice(data);
+    let sum = checksum(&msg);
+    msg[2..4].copy_from_slice(&sum.to_be_bytes());
+    msg
+}
+
+/// The part of a datagram from `REMOTE` to `LOCAL` carrying `payload` from `offset` on, with the
+/// given identification.
+pub fn fragment(
+    protocol: IpTrafficClass,
+    id: u16,
+    payload: &[u8],
+    offset: usize,
+    len: usize,
+) -> Vec<u8> {
+    let part = &payload[offset..(offset + len).min(payload.len())];
+    let mut ip = Ipv4Header::new(
+        part.len() as u16,
+        64,
+        protocol,
+        REMOTE.octets(),
+        LOCAL.octets(),
+    );
+    ip.identification = id;
+    ip.dont_fragment = false;
+    ip.more_fragments = offset + len < payload.len();
+    ip.fragments_offset = (offset / 8) as u16;
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(part);
+    packet
+}
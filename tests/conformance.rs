@@ -0,0 +1,548 @@
+//! Scripted conformance tests, in the spirit of packetdrill.
+//!
+//! Each script in `tests/scripts` plays the remote end of a connection: it injects segments into
+//! an interface, checks the segments the interface sends back, and drives the local application
+//! through its sockets. Everything runs in a `Simulation`, so times are exact.
+//!
+//! A script has one event per line, starting with the time in seconds since the script started:
+//!
+//! ```text
+//! 0.000 listen 8080
+//! 0.100 < S 0:0(0) win 65535 <mss 1460>
+//! 0.100 > S. 0:0(0) ack 1
+//! 0.200 < . 1:1(0) ack 1 win 65535
+//! 0.200 accept
+//! ```
+//!
+//! `<` lines are segments to inject and `>` lines are segments the interface must send, written
+//! as `FLAGS SEQ:END(LEN)`, then optionally `ack N`, `win N`, `<options>` and a quoted payload.
+//! Flags are `S`, `F`, `R`, `P` and `.` for ACK. The interface's sequence numbers are relative
+//! to its initial sequence number; the script's own are used as written.
+//! Options and the window are only checked on outbound segments if the script gives them.
+//!
+//! Other lines are calls on the application side: `listen PORT`, `connect PORT`, `accept`,
+//! `write "DATA"`, `read "DATA"`, `read eof`, `read error KIND`, `shutdown` and `close`, which
+//! closes the connection, or the listener if there is none. Calls that would block are retried
+//! until they succeed, for as long as an expected segment could be late.
+//!
+//! The interface must send nothing the script does not expect, and must send what it does expect
+//! within `TOLERANCE` of the given time.
+
+use etherparse::TcpOptionElement;
+use etherparse::{IpTrafficClass, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use trust::{Interface, NetDevice, Simulation, TcpListener, TcpStream};
+
+/// The script's address.
+const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+/// The interface's address.
+const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+/// The script's port, when the interface is the one listening.
+const REMOTE_PORT: u16 = 40000;
+
+const TOLERANCE: Duration = Duration::from_millis(4);
+
+/// Packets in flight between the script and the interface.
+#[derive(Clone, Default)]
+struct Wire {
+    inbound: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    outbound: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+struct ScriptDevice(Wire);
+
+impl NetDevice for ScriptDevice {
+    fn recv(&mut self, buf: &mut [u8], _timeout: Duration) -> io::Result<Option<usize>> {
+        Ok(self.0.inbound.lock().unwrap().pop_front().map(|p| {
+            buf[..p.len()].copy_from_slice(&p);
+            p.len()
+        }))
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.0.outbound.lock().unwrap().push_back(packet.to_vec());
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        1500
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    flags: String,
+    seq: u32,
+    len: u32,
+    ack: Option<u32>,
+    win: Option<u16>,
+    options: Option<Vec<TcpOptionElement>>,
+    payload: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+enum Event {
+    Inbound(Segment),
+    Outbound(Segment),
+    Listen(u16),
+    Connect(u16),
+    Accept,
+    Write(Vec<u8>),
+    Read(Vec<u8>),
+    ReadEof,
+    ReadError(String),
+    Shutdown,
+    Close,
+}
+
+fn quoted(s: &str) -> Option<Vec<u8>> {
+    s.strip_prefix('"')?
+        .strip_suffix('"')
+        .map(|s| s.as_bytes().to_vec())
+}
+
+fn parse_options(s: &str) -> Vec<TcpOptionElement> {
+    s.split(',')
+        .map(|o| {
+            let words: Vec<_> = o.split_whitespace().collect();
+            match words[..] {
+                ["nop"] => TcpOptionElement::Nop,
+                ["mss", n] => TcpOptionElement::MaximumSegmentSize(n.parse().unwrap()),
+                ["wscale", n] => TcpOptionElement::WindowScale(n.parse().unwrap()),
+                ["sackOK"] => TcpOptionElement::SelectiveAcknowledgementPermitted,
+                _ => panic!("unknown option {:?}", o),
+            }
+        })
+        .collect()
+}
+
+fn parse_segment(s: &str) -> Segment {
+    let (s, payload) = match s.find('"') {
+        Some(i) => (
+            &s[..i],
+            Some(quoted(s[i..].trim()).expect("unterminated payload")),
+        ),
+        None => (s, None),
+    };
+    let (s, options) = match s.find('<') {
+        Some(i) => {
+            let end = s.find('>').expect("unterminated options");
+            (&s[..i], Some(parse_options(&s[i + 1..end])))
+        }
+        None => (s, None),
+    };
+
+    let mut words = s.split_whitespace();
+    let flags = words.next().expect("missing flags").to_string();
+    let range = words.next().expect("missing sequence numbers");
+    let (seq, rest) = range.split_once(':').expect("bad sequence numbers");
+    let (end, len) = rest.split_once('(').expect("bad sequence numbers");
+    let len = len.strip_suffix(')').expect("bad sequence numbers");
+    let seq: u32 = seq.parse().unwrap();
+    let len: u32 = len.parse().unwrap();
+    assert_eq!(
+        seq.wrapping_add(len),
+        end.parse::<u32>().unwrap(),
+        "bad length"
+    );
+
+    let mut segment = Segment {
+        flags,
+        seq,
+        len,
+        ack: None,
+        win: None,
+        options,
+        payload,
+    };
+    while let Some(word) = words.next() {
+        let n = words.next().expect("missing number");
+        match word {
+            "ack" => segment.ack = Some(n.parse().unwrap()),
+            "win" => segment.win = Some(n.parse().unwrap()),
+            _ => panic!("unknown field {:?}", word),
+        }
+    }
+    if let Some(payload) = &segment.payload {
+        assert_eq!(payload.len() as u32, len, "payload does not match length");
+    }
+    segment
+}
+
+/// Parses a script into its events, each with its line number, time and text.
+fn parse(script: &str) -> Vec<(usize, Duration, Event, &str)> {
+    let mut events = Vec::new();
+    for (i, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (time, rest) = line.split_once(' ').expect("missing time");
+        let time = Duration::from_secs_f64(time.parse().expect("bad time"));
+        let rest = rest.trim();
+        let (word, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+        let event = match word {
+            "<" => Event::Inbound(parse_segment(arg)),
+            ">" => Event::Outbound(parse_segment(arg)),
+            "listen" => Event::Listen(arg.parse().expect("bad port")),
+            "connect" => Event::Connect(arg.parse().expect("bad port")),
+            "accept" => Event::Accept,
+            "write" => Event::Write(quoted(arg).expect("write needs quoted data")),
+            "read" => match arg.split_once(' ') {
+                Some(("error", kind)) => Event::ReadError(kind.to_string()),
+                _ if arg == "eof" => Event::ReadEof,
+                _ => Event::Read(quoted(arg).expect("read needs quoted data")),
+            },
+            "shutdown" => Event::Shutdown,
+            "close" => Event::Close,
+            _ => panic!("line {}: unknown event {:?}", i + 1, word),
+        };
+        events.push((i + 1, time, event, rest));
+    }
+    events
+}
+
+fn flags_of(tcph: &TcpHeaderSlice<'_>) -> String {
+    let mut flags = String::new();
+    for (set, c) in [
+        (tcph.syn(), 'S'),
+        (tcph.fin(), 'F'),
+        (tcph.rst(), 'R'),
+        (tcph.psh(), 'P'),
+        (tcph.ack(), '.'),
+    ] {
+        if set {
+            flags.push(c);
+        }
+    }
+    flags
+}
+
+struct Harness {
+    sim: Simulation,
+    iface: Interface,
+    wire: Wire,
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+    /// the interface's initial sequence number, once it has sent its SYN
+    iss: Option<u32>,
+    local_port: Option<u16>,
+    remote_port: u16,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let wire = Wire::default();
+        let mut sim = Simulation::new(0);
+        let iface = sim.add_interface(ScriptDevice(wire.clone())).unwrap();
+        Harness {
+            sim,
+            iface,
+            wire,
+            listener: None,
+            stream: None,
+            iss: None,
+            local_port: None,
+            remote_port: REMOTE_PORT,
+        }
+    }
+
+    fn inject(&mut self, s: &Segment) {
+        let mut tcp = TcpHeader::new(
+            self.remote_port,
+            self.local_port.expect("no connection to send to"),
+            s.seq,
+            s.win.unwrap_or(65535),
+        );
+        for c in s.flags.chars() {
+            match c {
+                'S' => tcp.syn = true,
+                'F' => tcp.fin = true,
+                'R' => tcp.rst = true,
+                'P' => tcp.psh = true,
+                '.' => tcp.ack = true,
+                _ => panic!("unknown flag {:?}", c),
+            }
+        }
+        if let Some(ack) = s.ack {
+            tcp.acknowledgment_number = ack.wrapping_add(self.iss.unwrap_or(0));
+        }
+        if let Some(options) = &s.options {
+            tcp.set_options(options).unwrap();
+        }
+        let payload = s.payload.clone().unwrap_or_else(|| vec![0; s.len as usize]);
+        let ip = Ipv4Header::new(
+            tcp.header_len() + payload.len() as u16,
+            64,
+            IpTrafficClass::Tcp,
+            REMOTE.octets(),
+            LOCAL.octets(),
+        );
+        tcp.checksum = tcp.calc_checksum_ipv4(&ip, &payload).unwrap();
+
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(&payload);
+        self.wire.inbound.lock().unwrap().push_back(packet);
+    }
+
+    /// Describes a segment the interface sent, in the script's notation.
+    ///
+    /// The first SYN the interface sends tells us its initial sequence number and its port.
+    fn describe(&mut self, packet: &[u8]) -> String {
+        let iph = Ipv4HeaderSlice::from_slice(packet).unwrap();
+        let tcph = TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]).unwrap();
+        let data = &packet[iph.slice().len() + tcph.slice().len()..];
+        if tcph.syn() && self.iss.is_none() {
+            self.iss = Some(tcph.sequence_number());
+            self.local_port = Some(tcph.source_port());
+        }
+
+        let seq = tcph.sequence_number().wrapping_sub(self.iss.unwrap_or(0));
+        let mut d = format!(
+            "{} {}:{}({})",
+            flags_of(&tcph),
+            seq,
+            seq.wrapping_add(data.len() as u32),
+            data.len()
+        );
+        if tcph.ack() {
+            d += &format!(" ack {}", tcph.acknowledgment_number());
+        }
+        d += &format!(" win {}", tcph.window_size());
+        let options: Vec<_> = tcph.options_iterator().map(|o| o.unwrap()).collect();
+        if !options.is_empty() {
+            d += &format!(" {:?}", options);
+        }
+        if !data.is_empty() {
+            d += &format!(" {:?}", String::from_utf8_lossy(data));
+        }
+        let ports = (tcph.source_port(), tcph.destination_port());
+        if Some(ports) != self.local_port.map(|p| (p, self.remote_port)) {
+            d += &format!(" between the wrong ports {:?}", ports);
+        }
+        d
+    }
+
+    /// Checks `packet` against `expected`, which must already have been through `describe`.
+    fn matches(&self, expected: &Segment, packet: &[u8]) -> bool {
+        let iph = Ipv4HeaderSlice::from_slice(packet).unwrap();
+        let tcph = TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]).unwrap();
+        let data = &packet[iph.slice().len() + tcph.slice().len()..];
+        let options: Vec<_> = tcph.options_iterator().map(|o| o.unwrap()).collect();
+
+        Some((tcph.source_port(), tcph.destination_port()))
+            == self.local_port.map(|p| (p, self.remote_port))
+            && flags_of(&tcph) == expected.flags
+            && tcph.sequence_number().wrapping_sub(self.iss.unwrap_or(0)) == expected.seq
+            && data.len() as u32 == expected.len
+            && expected
+                .ack
+                .is_none_or(|a| a == tcph.acknowledgment_number())
+            && expected.win.is_none_or(|w| w == tcph.window_size())
+            && expected.options.as_ref().is_none_or(|o| *o == options)
+            && expected.payload.as_ref().is_none_or(|p| p == data)
+    }
+
+    /// Tries an application call, returning `false` if it would block.
+    fn call(&mut self, event: &Event) -> io::Result<bool> {
+        const NO_STREAM: &str = "no stream to use";
+        let r = match event {
+            Event::Listen(port) => {
+                let l = self.iface.bind(*port)?;
+                l.set_nonblocking(true)?;
+                self.listener = Some(l);
+                self.local_port = Some(*port);
+                Ok(())
+            }
+            Event::Connect(port) => {
+                self.remote_port = *port;
+                self.stream = Some(
+                    self.iface
+                        .connect_nonblocking(LOCAL, SocketAddrV4::new(REMOTE, *port))?,
+                );
+                Ok(())
+            }
+            Event::Accept => self
+                .listener
+                .as_mut()
+                .expect("accept without listen")
+                .accept()
+                .and_then(|s| {
+                    s.set_nonblocking(true)?;
+                    self.stream = Some(s);
+                    Ok(())
+                }),
+            Event::Write(data) => {
+                let s = self.stream.as_mut().expect(NO_STREAM);
+                let n = s.write(data)?;
+                assert_eq!(n, data.len(), "short write");
+                Ok(())
+            }
+            Event::Read(expected) => {
+                let s = self.stream.as_mut().expect(NO_STREAM);
+                let mut buf = vec![0; expected.len()];
+                s.read_exact(&mut buf).map(|()| {
+                    assert_eq!(buf, *expected, "read the wrong data");
+                })
+            }
+            Event::ReadEof => {
+                let s = self.stream.as_mut().expect(NO_STREAM);
+                s.read(&mut [0; 16])
+                    .map(|n| assert_eq!(n, 0, "read data, not EOF"))
+            }
+            Event::ReadError(kind) => {
+                let s = self.stream.as_mut().expect(NO_STREAM);
+                match s.read(&mut [0; 16]) {
+                    Ok(n) => panic!("read {} bytes instead of failing with {}", n, kind),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(e),
+                    Err(e) => {
+                        assert_eq!(&format!("{:?}", e.kind()), kind, "wrong error");
+                        Ok(())
+                    }
+                }
+            }
+            Event::Shutdown => self
+                .stream
+                .as_ref()
+                .expect(NO_STREAM)
+                .shutdown(Shutdown::Write),
+            Event::Close => {
+                if self.stream.take().is_none() {
+                    drop(self.listener.take().expect("nothing to close"));
+                }
+                Ok(())
+            }
+            Event::Inbound(_) | Event::Outbound(_) => unreachable!(),
+        };
+        match r {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The oldest segment the interface has sent that the script has yet to check.
+    fn peek(&self) -> Option<Vec<u8>> {
+        self.wire.outbound.lock().unwrap().front().cloned()
+    }
+
+    fn run(&mut self, name: &str, script: &str) {
+        for (line, time, event, text) in parse(script) {
+            let fail = |sim: &Simulation, msg: String| -> ! {
+                panic!(
+                    "{}:{}: at {:.3}s: {}",
+                    name,
+                    line,
+                    sim.elapsed().as_secs_f64(),
+                    msg
+                )
+            };
+
+            // wait for the event's time, making sure nothing is sent in the meantime
+            loop {
+                if let Some(packet) = self.peek() {
+                    let early = time.saturating_sub(TOLERANCE);
+                    if matches!(event, Event::Outbound(_)) && self.sim.elapsed() >= early {
+                        break;
+                    }
+                    let got = self.describe(&packet);
+                    fail(&self.sim, format!("unexpected segment `{}`", got));
+                }
+                if self.sim.elapsed() >= time {
+                    break;
+                }
+                self.sim.step().unwrap();
+            }
+
+            let late = time + TOLERANCE;
+            match &event {
+                Event::Inbound(s) => self.inject(s),
+                Event::Outbound(s) => loop {
+                    if let Some(packet) = self.peek() {
+                        let got = self.describe(&packet);
+                        if !self.matches(s, &packet) {
+                            fail(&self.sim, format!("expected `{}`, got `{}`", text, got));
+                        }
+                        self.wire.outbound.lock().unwrap().pop_front();
+                        break;
+                    }
+                    if self.sim.elapsed() > late {
+                        fail(&self.sim, format!("expected `{}`, got nothing", text));
+                    }
+                    self.sim.step().unwrap();
+                },
+                _ => loop {
+                    match self.call(&event) {
+                        Ok(true) => break,
+                        Ok(false) if self.sim.elapsed() <= late => self.sim.step().unwrap(),
+                        Ok(false) => fail(&self.sim, format!("`{}` would block", text)),
+                        Err(e) => fail(&self.sim, format!("`{}` failed: {}", text, e)),
+                    }
+                },
+            }
+        }
+
+        if let Some(packet) = self.peek() {
+            let got = self.describe(&packet);
+            panic!(
+                "{}: unexpected segment `{}` after the script ended",
+                name, got
+            );
+        }
+    }
+}
+
+macro_rules! scripts {
+    ($($name:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $name() {
+                let name = concat!("tests/scripts/", stringify!($name), ".pkt");
+                let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/scripts/", stringify!($name), ".pkt");
+                Harness::new().run(name, &std::fs::read_to_string(path).unwrap());
+            }
+        )*
+    };
+}
+
+scripts! {
+    close_active,
+    close_fin_retransmit,
+    close_passive,
+    close_simultaneous,
+    close_time_wait,
+    data_out_of_window,
+    data_receive,
+    data_retransmit,
+    data_send,
+    data_wraparound,
+    handshake_active,
+    handshake_passive,
+    handshake_refused,
+    handshake_syn_retransmit,
+    handshake_synack_retransmit,
+    rst_challenge,
+    rst_closed_port,
+    rst_established,
+    rst_syn_in_window,
+    window_respected,
+    window_zero_probe,
+    window_zero_reopen,
+}
+
+#[test]
+#[should_panic(expected = "expected `> S. 0:0(0) ack 2`, got `S. 0:0(0) ack 1 win 65535`")]
+fn harness_catches_wrong_segments() {
+    let script = "
+        0.000 listen 8080
+        0.100 < S 0:0(0) win 65535
+        0.100 > S. 0:0(0) ack 2
+    ";
+    Harness::new().run("inline", script);
+}
@@ -0,0 +1,198 @@
+//! Ethernet framing and ARP, with the interface as a host on a LAN.
+
+mod common;
+
+use common::{run, setup_over};
+use etherparse::{IpTrafficClass, Ipv4Header};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::thread;
+use trust::{EthernetDevice, Interface, NetDevice, PipeDevice, Simulation};
+
+const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+const PEER: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+/// An interface on a LAN in a simulation, and the other end of its link.
+fn setup(gateway: Option<Ipv4Addr>) -> (Simulation, Interface, PipeDevice) {
+    let lan = |dev| {
+        let dev = EthernetDevice::new(dev, MAC, LOCAL, 24);
+        match gateway {
+            Some(gateway) => dev.with_gateway(gateway),
+            None => dev,
+        }
+    };
+    setup_over(1514, lan)
+}
+
+fn frame(dst: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut f = dst.to_vec();
+    f.extend_from_slice(&PEER_MAC);
+    f.extend_from_slice(&ethertype.to_be_bytes());
+    f.extend_from_slice(payload);
+    f
+}
+
+/// An ARP packet from the peer, asking who has `tpa` or (`op` 2) answering the interface.
+fn arp(op: u16, tha: [u8; 6], tpa: Ipv4Addr) -> Vec<u8> {
+    let mut a = vec![0, 1, 0x08, 0x00, 6, 4];
+    a.extend_from_slice(&op.to_be_bytes());
+    a.extend_from_slice(&PEER_MAC);
+    a.extend_from_slice(&PEER.octets());
+    a.extend_from_slice(&tha);
+    a.extend_from_slice(&tpa.octets());
+    a
+}
+
+/// A ping from the peer to the interface.
+fn ping() -> Vec<u8> {
+    let msg = common::ping(&[]);
+    let ip = Ipv4Header::new(
+        msg.len() as u16,
+        64,
+        IpTrafficClass::Icmp,
+        PEER.octets(),
+        LOCAL.octets(),
+    );
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(&msg);
+    packet
+}
+
+/// Whether `f` is an ARP request from the interface, broadcast, for `ip`.
+fn is_request_for(f: &[u8], ip: Ipv4Addr) -> bool {
+    f[..6] == BROADCAST
+        && f[12..14] == [0x08, 0x06]
+        && f[20..22] == [0, 1]
+        && f[38..42] == ip.octets()
+}
+
+#[test]
+fn answers_arp_for_its_own_address() {
+    let (mut sim, _iface, mut wire) = setup(None);
+
+    wire.send(&frame(BROADCAST, 0x0806, &arp(1, [0; 6], LOCAL)))
+        .unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert_eq!(sent.len(), 1);
+    let reply = &sent[0];
+    assert_eq!(reply[..6], PEER_MAC);
+    assert_eq!(reply[6..12], MAC);
+    // a reply, from the interface's addresses, to the peer's
+    assert_eq!(reply[20..22], [0, 2]);
+    assert_eq!(reply[22..28], MAC);
+    assert_eq!(reply[28..32], LOCAL.octets());
+    assert_eq!(reply[32..38], PEER_MAC);
+    assert_eq!(reply[38..42], PEER.octets());
+
+    // not for someone else's
+    let other = Ipv4Addr::new(192, 168, 0, 3);
+    wire.send(&frame(BROADCAST, 0x0806, &arp(1, [0; 6], other)))
+        .unwrap();
+    assert!(run(&mut sim, &mut wire, 10).is_empty());
+
+    // having been asked, it knows where to send its answer to a ping
+    wire.send(&frame(MAC, 0x0800, &ping())).unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0][..6], PEER_MAC);
+    assert_eq!(sent[0][12..14], [0x08, 0x00]);
+
+    // but does not answer pings sent to another host on the bridge
+    let stranger = [0x02, 0, 0, 0, 0, 3];
+    wire.send(&frame(stranger, 0x0800, &ping())).unwrap();
+    assert!(run(&mut sim, &mut wire, 10).is_empty());
+}
+
+#[test]
+fn resolves_neighbors_and_forgets_them() {
+    let (mut sim, _iface, mut wire) = setup(None);
+
+    // the echo reply has to wait for the peer to say where it is
+    wire.send(&frame(MAC, 0x0800, &ping())).unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert_eq!(sent.len(), 1);
+    assert!(is_request_for(&sent[0], PEER));
+
+    wire.send(&frame(MAC, 0x0806, &arp(2, MAC, LOCAL))).unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0][..6], PEER_MAC);
+    assert_eq!(sent[0][12..14], [0x08, 0x00]);
+
+    // long after, the interface asks again
+    run(&mut sim, &mut wire, 31_000);
+    wire.send(&frame(MAC, 0x0800, &ping())).unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert_eq!(sent.len(), 1);
+    assert!(is_request_for(&sent[0], PEER));
+}
+
+#[test]
+fn gives_up_on_silent_neighbors() {
+    let (mut sim, mut iface, mut wire) = setup(None);
+    let sock = iface.bind_udp((LOCAL, 0)).unwrap();
+    sock.send_to(b"hello?", (PEER, 53)).unwrap();
+
+    // asked three times, a second apart, and then not again
+    let sent = run(&mut sim, &mut wire, 10_000);
+    assert_eq!(sent.len(), 3);
+    assert!(sent.iter().all(|f| is_request_for(f, PEER)));
+
+    // off the subnet there is no one to ask without a gateway
+    sock.send_to(b"hello?", (Ipv4Addr::new(198, 51, 100, 1), 53))
+        .unwrap();
+    assert!(run(&mut sim, &mut wire, 10).is_empty());
+}
+
+#[test]
+fn routes_through_the_gateway() {
+    let (mut sim, mut iface, mut wire) = setup(Some(PEER));
+    let sock = iface.bind_udp((LOCAL, 0)).unwrap();
+    sock.send_to(b"hello?", (Ipv4Addr::new(198, 51, 100, 1), 53))
+        .unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert_eq!(sent.len(), 1);
+    assert!(is_request_for(&sent[0], PEER));
+}
+
+#[test]
+fn tcp_between_hosts_on_a_lan() {
+    let (a, b) = PipeDevice::pair(1514);
+    let client_mac = [0x02, 0, 0, 0, 0, 0x10];
+    let server_mac = [0x02, 0, 0, 0, 0, 0x20];
+    let client_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let server_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let mut client =
+        Interface::with_device(EthernetDevice::new(a, client_mac, client_ip, 24)).unwrap();
+    let mut server =
+        Interface::with_device(EthernetDevice::new(b, server_mac, server_ip, 24)).unwrap();
+
+    let mut l = server.bind(7).unwrap();
+    let echo = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut buf = Vec::new();
+        s.read_to_end(&mut buf).unwrap();
+        s.write_all(&buf).unwrap();
+        s.shutdown(Shutdown::Write).unwrap();
+        s.flush().unwrap();
+        drop(s);
+        l
+    });
+
+    let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+    let mut s = client
+        .connect(client_ip, SocketAddrV4::new(server_ip, 7))
+        .unwrap();
+    s.write_all(&data).unwrap();
+    s.shutdown(Shutdown::Write).unwrap();
+    let mut reply = Vec::new();
+    s.read_to_end(&mut reply).unwrap();
+    assert!(reply == data, "data was mangled in transit");
+
+    drop(s);
+    drop(echo.join().unwrap());
+}
@@ -0,0 +1,124 @@
+//! IPv4 datagrams that arrive in fragments.
+
+mod common;
+
+use common::{fragment, ping, run, setup, LOCAL, REMOTE};
+use etherparse::{IpTrafficClass, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use trust::NetDevice;
+
+#[test]
+fn fragmented_ping_is_answered() {
+    let (mut sim, _iface, mut wire) = setup();
+    let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    let msg = ping(&data);
+
+    // last first, with the middle one twice
+    for offset in [160, 80, 0, 80] {
+        wire.send(&fragment(IpTrafficClass::Icmp, 7, &msg, offset, 80))
+            .unwrap();
+    }
+    let sent = run(&mut sim, &mut wire, 10);
+    assert_eq!(sent.len(), 1, "expected exactly one echo reply");
+    let reply = &sent[0];
+    let ip = Ipv4HeaderSlice::from_slice(reply).unwrap();
+    assert_eq!(ip.destination_addr(), REMOTE);
+    let body = &reply[ip.slice().len()..];
+    assert_eq!(body[0], 0, "not an echo reply");
+    assert_eq!(&body[4..], &msg[4..]);
+}
+
+#[test]
+fn overlapping_fragments_are_dropped() {
+    let (mut sim, _iface, mut wire) = setup();
+    let msg = ping(&[0; 200]);
+
+    wire.send(&fragment(IpTrafficClass::Icmp, 7, &msg, 0, 96))
+        .unwrap();
+    // overlaps the last 16 bytes of the first
+    wire.send(&fragment(IpTrafficClass::Icmp, 7, &msg, 80, 80))
+        .unwrap();
+    wire.send(&fragment(IpTrafficClass::Icmp, 7, &msg, 160, 80))
+        .unwrap();
+    assert!(run(&mut sim, &mut wire, 10).is_empty());
+
+    // a datagram sent again afterwards, properly cut up, goes through
+    for offset in [0, 104] {
+        wire.send(&fragment(IpTrafficClass::Icmp, 8, &msg, offset, 104))
+            .unwrap();
+    }
+    assert_eq!(run(&mut sim, &mut wire, 10).len(), 1);
+}
+
+#[test]
+fn reassembly_times_out() {
+    let (mut sim, _iface, mut wire) = setup();
+    let msg = ping(&[0; 200]);
+    let first = fragment(IpTrafficClass::Icmp, 7, &msg, 0, 80);
+    wire.send(&first).unwrap();
+
+    assert!(run(&mut sim, &mut wire, 29_000).is_empty());
+    let sent = run(&mut sim, &mut wire, 2_000);
+    assert_eq!(sent.len(), 1);
+    let ip = Ipv4HeaderSlice::from_slice(&sent[0]).unwrap();
+    let body = &sent[0][ip.slice().len()..];
+    // time exceeded, in reassembly, quoting the first fragment
+    assert_eq!(&body[..2], &[11, 1]);
+    assert_eq!(&body[8..], &first[..]);
+
+    // and the rest of the datagram is no use anymore
+    for offset in [80, 160] {
+        wire.send(&fragment(IpTrafficClass::Icmp, 7, &msg, offset, 80))
+            .unwrap();
+    }
+    assert!(run(&mut sim, &mut wire, 10).is_empty());
+}
+
+#[test]
+fn reassembly_timeouts_come_in_order() {
+    let (mut sim, _iface, mut wire) = setup();
+    let msg = ping(&[0; 200]);
+    for id in [9, 3, 7] {
+        wire.send(&fragment(IpTrafficClass::Icmp, id, &msg, 0, 80))
+            .unwrap();
+    }
+    run(&mut sim, &mut wire, 1);
+    wire.send(&fragment(IpTrafficClass::Icmp, 1, &msg, 0, 80))
+        .unwrap();
+
+    // by when they started arriving, and then by identification
+    let ids: Vec<u16> = run(&mut sim, &mut wire, 31_000)
+        .iter()
+        .map(|sent| {
+            let ip = Ipv4HeaderSlice::from_slice(sent).unwrap();
+            let quoted = Ipv4HeaderSlice::from_slice(&sent[ip.slice().len() + 8..]).unwrap();
+            quoted.identification()
+        })
+        .collect();
+    assert_eq!(ids, [3, 7, 9, 1]);
+}
+
+#[test]
+fn fragmented_syn_reaches_listener() {
+    let (mut sim, mut iface, mut wire) = setup();
+    let _l = iface.bind(80).unwrap();
+
+    let mut tcp = TcpHeader::new(40000, 80, 1000, 65535);
+    tcp.syn = true;
+    tcp.checksum = tcp
+        .calc_checksum_ipv4_raw(REMOTE.octets(), LOCAL.octets(), &[])
+        .unwrap();
+    let mut segment = Vec::new();
+    tcp.write(&mut segment).unwrap();
+
+    // the TCP header itself is cut in two
+    wire.send(&fragment(IpTrafficClass::Tcp, 9, &segment, 16, 8))
+        .unwrap();
+    wire.send(&fragment(IpTrafficClass::Tcp, 9, &segment, 0, 16))
+        .unwrap();
+    let sent = run(&mut sim, &mut wire, 10);
+    assert_eq!(sent.len(), 1);
+    let ip = Ipv4HeaderSlice::from_slice(&sent[0]).unwrap();
+    let synack = TcpHeaderSlice::from_slice(&sent[0][ip.slice().len()..]).unwrap();
+    assert!(synack.syn() && synack.ack());
+    assert_eq!(synack.acknowledgment_number(), 1001);
+}
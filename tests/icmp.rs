@@ -0,0 +1,293 @@
+//! Pings, unreachable errors for what the interface cannot deliver, and ICMP errors about the
+//! interface's own segments.
+
+mod common;
+
+use common::{fragment, run, setup, setup_over, LOCAL, REMOTE};
+use etherparse::{IpTrafficClass, Ipv4Header, Ipv4HeaderSlice, Ipv6Header, Ipv6HeaderSlice};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::time::Duration;
+use trust::{Interface, NetDevice, PipeDevice, Simulation, TcpStream};
+
+const LOCAL6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+const REMOTE6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+/// Wraps `payload` in an IP header from `src` to `dst`.
+fn ip_packet(src: IpAddr, dst: IpAddr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut ip = Ipv4Header::new(
+                payload.len() as u16,
+                64,
+                IpTrafficClass::Tcp,
+                src.octets(),
+                dst.octets(),
+            );
+            ip.protocol = protocol;
+            ip.write(&mut packet).unwrap();
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => Ipv6Header {
+            traffic_class: 0,
+            flow_label: 0,
+            payload_length: payload.len() as u16,
+            next_header: protocol,
+            hop_limit: 64,
+            source: src.octets(),
+            destination: dst.octets(),
+        }
+        .write(&mut packet)
+        .unwrap(),
+        _ => unreachable!(),
+    }
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// The Internet checksum of `msg`, with the pseudo-header for `protocol` where it has one.
+fn msg_checksum(src: IpAddr, dst: IpAddr, protocol: u8, msg: &[u8]) -> u16 {
+    let mut bytes = Vec::new();
+    match (src, dst) {
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            bytes.extend_from_slice(&src.octets());
+            bytes.extend_from_slice(&dst.octets());
+            bytes.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&[0, 0, 0, protocol]);
+        }
+        (IpAddr::V4(src), IpAddr::V4(dst)) if protocol != 1 => {
+            bytes.extend_from_slice(&src.octets());
+            bytes.extend_from_slice(&dst.octets());
+            bytes.extend_from_slice(&[0, protocol]);
+            bytes.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        }
+        _ => {}
+    }
+    bytes.extend_from_slice(msg);
+    common::checksum(&bytes)
+}
+
+/// An ICMP message of type `ty` from `src` to `dst`.
+fn icmp_packet(src: IpAddr, dst: IpAddr, ty: u8, code: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = vec![ty, code, 0, 0];
+    msg.extend_from_slice(body);
+    let protocol = if src.is_ipv4() { 1 } else { 58 };
+    let sum = msg_checksum(src, dst, protocol, &msg);
+    msg[2..4].copy_from_slice(&sum.to_be_bytes());
+    ip_packet(src, dst, protocol, &msg)
+}
+
+/// A UDP datagram from `src` to port 53 on `dst`, with four bytes of data.
+fn udp_packet(src: IpAddr, dst: IpAddr) -> Vec<u8> {
+    let mut msg = vec![0x14, 0xe9, 0, 53, 0, 12, 0, 0, 1, 2, 3, 4];
+    let sum = msg_checksum(src, dst, 17, &msg);
+    msg[6..8].copy_from_slice(&sum.to_be_bytes());
+    ip_packet(src, dst, 17, &msg)
+}
+
+/// Steps the simulation until the interface sends something.
+fn next_packet(sim: &mut Simulation, wire: &mut PipeDevice) -> Vec<u8> {
+    let mut buf = vec![0; wire.mtu()];
+    for _ in 0..100 {
+        sim.step().unwrap();
+        if let Some(n) = wire.recv(&mut buf, Duration::ZERO).unwrap() {
+            return buf[..n].to_vec();
+        }
+    }
+    panic!("the interface sent nothing");
+}
+
+/// Splits an ICMP packet the interface sent into its type, code and the rest of the message,
+/// checking the checksum on the way.
+fn icmp_of(packet: &[u8]) -> (u8, u8, Vec<u8>) {
+    let (src, dst, protocol, msg): (IpAddr, IpAddr, u8, &[u8]) = match packet[0] >> 4 {
+        4 => {
+            let ip = Ipv4HeaderSlice::from_slice(packet).unwrap();
+            let msg = &packet[ip.slice().len()..];
+            (
+                ip.source_addr().into(),
+                ip.destination_addr().into(),
+                ip.protocol(),
+                msg,
+            )
+        }
+        _ => {
+            let ip = Ipv6HeaderSlice::from_slice(packet).unwrap();
+            let msg = &packet[40..];
+            (
+                ip.source_addr().into(),
+                ip.destination_addr().into(),
+                ip.next_header(),
+                msg,
+            )
+        }
+    };
+    assert_eq!(protocol, if src.is_ipv4() { 1 } else { 58 });
+    assert_eq!(
+        msg_checksum(src, dst, protocol, msg),
+        0,
+        "bad ICMP checksum"
+    );
+    (msg[0], msg[1], msg[4..].to_vec())
+}
+
+#[test]
+fn answers_pings() {
+    let (mut sim, _iface, mut wire) = setup();
+    let body = b"\x12\x34\x00\x01ping!";
+
+    wire.send(&icmp_packet(REMOTE.into(), LOCAL.into(), 8, 0, body))
+        .unwrap();
+    let reply = next_packet(&mut sim, &mut wire);
+    let ip = Ipv4HeaderSlice::from_slice(&reply).unwrap();
+    assert_eq!((ip.source_addr(), ip.destination_addr()), (LOCAL, REMOTE));
+    assert_eq!(icmp_of(&reply), (0, 0, body.to_vec()));
+
+    wire.send(&icmp_packet(REMOTE6.into(), LOCAL6.into(), 128, 0, body))
+        .unwrap();
+    let reply = next_packet(&mut sim, &mut wire);
+    let ip = Ipv6HeaderSlice::from_slice(&reply).unwrap();
+    assert_eq!((ip.source_addr(), ip.destination_addr()), (LOCAL6, REMOTE6));
+    assert_eq!(icmp_of(&reply), (129, 0, body.to_vec()));
+}
+
+#[test]
+fn ignores_pings_too_large_to_answer() {
+    let (mut sim, _iface, mut wire) = setup_over(576, |dev| dev);
+
+    // 1008 bytes of ICMP arrive in two fragments, but the reply would not fit the link
+    let body: Vec<u8> = (0..1004).map(|i| i as u8).collect();
+    let packet = icmp_packet(REMOTE.into(), LOCAL.into(), 8, 0, &body);
+    let msg = &packet[20..];
+    for offset in [0, 552] {
+        wire.send(&fragment(IpTrafficClass::Icmp, 7, msg, offset, 552))
+            .unwrap();
+    }
+    assert!(run(&mut sim, &mut wire, 100).is_empty());
+
+    // smaller ones are still answered
+    let small = icmp_packet(REMOTE.into(), LOCAL.into(), 8, 0, &body[..500]);
+    wire.send(&small).unwrap();
+    assert_eq!(icmp_of(&next_packet(&mut sim, &mut wire)).0, 0);
+}
+
+#[test]
+fn refuses_what_it_cannot_deliver() {
+    let (mut sim, _iface, mut wire) = setup();
+
+    // UDP, with no one to hand it to
+    let udp = udp_packet(REMOTE.into(), LOCAL.into());
+    wire.send(&udp).unwrap();
+    let (ty, code, body) = icmp_of(&next_packet(&mut sim, &mut wire));
+    assert_eq!((ty, code), (3, 3));
+    assert_eq!(&body[4..], &udp[..]);
+
+    // GRE, which we do not speak at all
+    wire.send(&ip_packet(REMOTE.into(), LOCAL.into(), 47, &[0; 4]))
+        .unwrap();
+    let (ty, code, _) = icmp_of(&next_packet(&mut sim, &mut wire));
+    assert_eq!((ty, code), (3, 2));
+
+    wire.send(&udp_packet(REMOTE6.into(), LOCAL6.into()))
+        .unwrap();
+    let (ty, code, _) = icmp_of(&next_packet(&mut sim, &mut wire));
+    assert_eq!((ty, code), (1, 4));
+
+    // a parameter problem, pointing at the next header field
+    wire.send(&ip_packet(REMOTE6.into(), LOCAL6.into(), 47, &[0; 4]))
+        .unwrap();
+    let (ty, code, body) = icmp_of(&next_packet(&mut sim, &mut wire));
+    assert_eq!((ty, code), (4, 1));
+    assert_eq!(body[..4], 6u32.to_be_bytes());
+
+    // but not to a multicast group
+    let group = Ipv4Addr::new(224, 0, 0, 1);
+    wire.send(&udp_packet(REMOTE.into(), group.into())).unwrap();
+    assert!(run(&mut sim, &mut wire, 10).is_empty());
+}
+
+/// Starts connecting to port 80 on `REMOTE`, and returns the SYN the interface sent.
+fn connect(
+    sim: &mut Simulation,
+    iface: &mut Interface,
+    wire: &mut PipeDevice,
+) -> (TcpStream, Vec<u8>) {
+    let c = iface
+        .connect_nonblocking(LOCAL, SocketAddrV4::new(REMOTE, 80))
+        .unwrap();
+    let syn = next_packet(sim, wire);
+    (c, syn)
+}
+
+/// Runs the simulation until reading from `c` stops blocking, and returns what it says.
+fn read_error(sim: &mut Simulation, c: &mut TcpStream, limit: Duration) -> io::ErrorKind {
+    let mut buf = [0; 16];
+    while sim.elapsed() < limit {
+        match c.read(&mut buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => sim.step().unwrap(),
+            Err(e) => return e.kind(),
+            Ok(n) => panic!("read {} bytes from a failed connection", n),
+        }
+    }
+    panic!("the connection did not fail");
+}
+
+#[test]
+fn hard_error_aborts_connect() {
+    let (mut sim, mut iface, mut wire) = setup();
+    let (mut c, syn) = connect(&mut sim, &mut iface, &mut wire);
+
+    // port unreachable, quoting the IP header and the start of the SYN
+    let mut body = vec![0; 4];
+    body.extend_from_slice(&syn[..28]);
+    wire.send(&icmp_packet(REMOTE.into(), LOCAL.into(), 3, 3, &body))
+        .unwrap();
+    assert_eq!(
+        read_error(&mut sim, &mut c, Duration::from_secs(1)),
+        io::ErrorKind::ConnectionRefused
+    );
+}
+
+#[test]
+fn soft_error_explains_timeout() {
+    let (mut sim, mut iface, mut wire) = setup();
+    let (mut c, syn) = connect(&mut sim, &mut iface, &mut wire);
+
+    // net unreachable, from a router on the way
+    let router: IpAddr = Ipv4Addr::new(198, 51, 100, 1).into();
+    let mut body = vec![0; 4];
+    body.extend_from_slice(&syn[..28]);
+    wire.send(&icmp_packet(router, LOCAL.into(), 3, 0, &body))
+        .unwrap();
+    for _ in 0..10 {
+        sim.step().unwrap();
+    }
+    // only a warning for now
+    let mut buf = [0; 16];
+    assert_eq!(
+        c.read(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    assert_eq!(
+        read_error(&mut sim, &mut c, Duration::from_secs(600)),
+        io::ErrorKind::NetworkUnreachable
+    );
+}
+
+#[test]
+fn errors_about_other_segments_are_ignored() {
+    let (mut sim, mut iface, mut wire) = setup();
+    let (mut c, mut syn) = connect(&mut sim, &mut iface, &mut wire);
+
+    // a port unreachable for a segment we never sent
+    let seq = u32::from_be_bytes([syn[24], syn[25], syn[26], syn[27]]);
+    syn[24..28].copy_from_slice(&seq.wrapping_add(1000).to_be_bytes());
+    let mut body = vec![0; 4];
+    body.extend_from_slice(&syn[..28]);
+    wire.send(&icmp_packet(REMOTE.into(), LOCAL.into(), 3, 3, &body))
+        .unwrap();
+    assert_eq!(
+        read_error(&mut sim, &mut c, Duration::from_secs(600)),
+        io::ErrorKind::TimedOut
+    );
+}
@@ -0,0 +1,93 @@
+//! Transfers over a `PipeDevice` pair whose ends are wrapped in `ImpairedDevice`s.
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+use trust::{ImpairedDevice, Impairment, Interface, PipeDevice};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn pair(impairment: Impairment) -> (Interface, Interface) {
+    let (a, b) = PipeDevice::pair(1500);
+    let other = Impairment {
+        seed: impairment.seed.wrapping_add(1),
+        ..impairment.clone()
+    };
+    (
+        Interface::with_device(ImpairedDevice::new(a, impairment)).unwrap(),
+        Interface::with_device(ImpairedDevice::new(b, other)).unwrap(),
+    )
+}
+
+/// Sends `len` bytes from client to server, and checks that they all arrive intact.
+fn transfer(impairment: Impairment, len: usize) -> Duration {
+    let (mut client, mut server) = pair(impairment);
+    let mut l = server.bind(9).unwrap();
+
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let sink = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).unwrap();
+        drop(s);
+        (l, got)
+    });
+
+    let start = Instant::now();
+    let mut s = client
+        .connect(CLIENT, SocketAddrV4::new(SERVER, 9))
+        .unwrap();
+    s.write_all(&data).unwrap();
+    s.shutdown(Shutdown::Write).unwrap();
+
+    let (l, got) = sink.join().unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(got.len(), expected.len());
+    assert!(got == expected, "data was mangled in transit");
+    drop(s);
+    drop(l);
+    elapsed
+}
+
+#[test]
+fn recovers_from_loss() {
+    transfer(
+        Impairment {
+            loss: 0.05,
+            seed: 1,
+            ..Default::default()
+        },
+        100_000,
+    );
+}
+
+#[test]
+fn recovers_from_reordering_and_duplication() {
+    transfer(
+        Impairment {
+            delay: Duration::from_millis(5),
+            jitter: Duration::from_millis(4),
+            reorder: 0.1,
+            duplicate: 0.05,
+            seed: 2,
+            ..Default::default()
+        },
+        100_000,
+    );
+}
+
+#[test]
+fn rate_limit_slows_transfer() {
+    // 100kB at 1MB/s cannot take less than a tenth of a second
+    let elapsed = transfer(
+        Impairment {
+            rate: Some(1_000_000),
+            ..Default::default()
+        },
+        100_000,
+    );
+    assert!(elapsed >= Duration::from_millis(100), "took {:?}", elapsed);
+}
@@ -0,0 +1,154 @@
+//! Connections over IPv6, alone and alongside IPv4.
+
+use etherparse::{Ipv6HeaderSlice, TcpHeaderSlice};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddrV4, SocketAddrV6};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use trust::{Interface, NetDevice, PipeDevice, Simulation, TcpStream};
+
+const CLIENT: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+const SERVER: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+const CLIENT4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// Keeps a copy of every packet sent through it.
+struct Recorder {
+    inner: PipeDevice,
+    sent: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl NetDevice for Recorder {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        self.inner.recv(buf, timeout)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.sent.lock().unwrap().push(packet.to_vec());
+        self.inner.send(packet)
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+}
+
+/// Runs an echo server on port 7 of `server`, for `n` connections.
+fn echo(sim: &mut Simulation, server: &mut Interface, n: usize) {
+    let mut l = server.bind(7).unwrap();
+    l.set_nonblocking(true).unwrap();
+    let mut streams: Vec<(TcpStream, Vec<u8>, bool)> = Vec::new();
+    sim.spawn(move || {
+        while streams.len() < n {
+            let s = l.accept()?;
+            s.set_nonblocking(true)?;
+            streams.push((s, Vec::new(), false));
+        }
+        let mut pending = false;
+        for (s, buf, done) in streams.iter_mut().filter(|(_, _, done)| !*done) {
+            match s.read_to_end(buf) {
+                Ok(_) => {
+                    s.write_all(buf)?;
+                    s.shutdown(Shutdown::Write)?;
+                    *done = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => pending = true,
+                Err(e) => return Err(e),
+            }
+        }
+        if pending {
+            Err(io::ErrorKind::WouldBlock.into())
+        } else {
+            Ok(())
+        }
+    });
+}
+
+/// Sends `msg` on `c`, and checks that it comes back.
+fn check_echo(sim: &mut Simulation, mut c: TcpStream, msg: &'static [u8]) {
+    let mut sent = 0;
+    let mut closed = false;
+    let mut got = Vec::new();
+    sim.spawn(move || {
+        if !closed {
+            while sent < msg.len() {
+                sent += c.write(&msg[sent..])?;
+            }
+            // closing before the handshake completes would drop what we wrote
+            c.flush()?;
+            c.shutdown(Shutdown::Write)?;
+            closed = true;
+        }
+        c.read_to_end(&mut got)?;
+        assert_eq!(got, msg);
+        Ok(())
+    });
+}
+
+#[test]
+fn echo_over_ipv6() {
+    let mut sim = Simulation::new(0);
+    let (a, b) = PipeDevice::pair(1500);
+    let sent = Arc::default();
+    let a = Recorder {
+        inner: a,
+        sent: Arc::clone(&sent),
+    };
+    let mut client = sim.add_interface(a).unwrap();
+    let mut server = sim.add_interface(b).unwrap();
+
+    echo(&mut sim, &mut server, 1);
+    let c = client
+        .connect_nonblocking(CLIENT, SocketAddrV6::new(SERVER, 7, 0, 0))
+        .unwrap();
+    check_echo(&mut sim, c, b"hello over ipv6");
+    sim.run(Duration::from_secs(10)).unwrap();
+
+    // everything the client sent was IPv6, with checksums over the IPv6 pseudo-header
+    let sent = sent.lock().unwrap();
+    assert!(!sent.is_empty());
+    for packet in sent.iter() {
+        let iph = Ipv6HeaderSlice::from_slice(packet).unwrap();
+        assert_eq!(iph.source_addr(), CLIENT);
+        assert_eq!(iph.destination_addr(), SERVER);
+        let tcph = TcpHeaderSlice::from_slice(&packet[40..]).unwrap();
+        let data = &packet[40 + tcph.slice().len()..];
+        assert_eq!(
+            tcph.checksum(),
+            tcph.to_header()
+                .calc_checksum_ipv6(&iph.to_header(), data)
+                .unwrap()
+        );
+    }
+}
+
+#[test]
+fn listener_accepts_both_families() {
+    let mut sim = Simulation::new(0);
+    let (a, b) = PipeDevice::pair(1500);
+    let mut client = sim.add_interface(a).unwrap();
+    let mut server = sim.add_interface(b).unwrap();
+
+    echo(&mut sim, &mut server, 2);
+    let c6 = client
+        .connect_nonblocking(CLIENT, SocketAddrV6::new(SERVER, 7, 0, 0))
+        .unwrap();
+    check_echo(&mut sim, c6, b"over ipv6");
+    let c4 = client
+        .connect_nonblocking(CLIENT4, SocketAddrV4::new(SERVER4, 7))
+        .unwrap();
+    check_echo(&mut sim, c4, b"over ipv4");
+    sim.run(Duration::from_secs(10)).unwrap();
+}
+
+#[test]
+fn mixed_families_are_refused() {
+    let mut sim = Simulation::new(0);
+    let (a, _b) = PipeDevice::pair(1500);
+    let mut client = sim.add_interface(a).unwrap();
+
+    match client.connect_nonblocking(CLIENT4, SocketAddrV6::new(SERVER, 7, 0, 0)) {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        Ok(_) => panic!("connected from IPv4 to IPv6"),
+    }
+}
@@ -0,0 +1,272 @@
+//! Two interfaces talking to each other over an in-memory `PipeDevice`.
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+use trust::{Interest, Interface, NetDevice, PipeDevice, Simulation, Token};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn pair(mtu: usize) -> (Interface, Interface) {
+    let (a, b) = PipeDevice::pair(mtu);
+    (
+        Interface::with_device(a).unwrap(),
+        Interface::with_device(b).unwrap(),
+    )
+}
+
+#[test]
+fn echo() {
+    let (mut client, mut server) = pair(1500);
+    let mut l = server.bind(7).unwrap();
+
+    let echo = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut buf = Vec::new();
+        s.read_to_end(&mut buf).unwrap();
+        s.write_all(&buf).unwrap();
+        s.shutdown(Shutdown::Write).unwrap();
+        s.flush().unwrap();
+        drop(s);
+        l
+    });
+
+    let mut s = client
+        .connect(CLIENT, SocketAddrV4::new(SERVER, 7))
+        .unwrap();
+    s.write_all(b"hello over a pipe").unwrap();
+    s.shutdown(Shutdown::Write).unwrap();
+    let mut reply = String::new();
+    s.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "hello over a pipe");
+
+    drop(s);
+    drop(echo.join().unwrap());
+}
+
+#[test]
+fn bulk_transfer_small_mtu() {
+    let (mut client, mut server) = pair(576);
+    let mut l = server.bind(9).unwrap();
+
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let sink = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).unwrap();
+        drop(s);
+        (l, got)
+    });
+
+    let mut s = client
+        .connect(CLIENT, SocketAddrV4::new(SERVER, 9))
+        .unwrap();
+    s.write_all(&data).unwrap();
+    s.shutdown(Shutdown::Write).unwrap();
+    s.flush().unwrap();
+
+    let (l, got) = sink.join().unwrap();
+    assert_eq!(got.len(), expected.len());
+    assert!(got == expected);
+    drop(s);
+    drop(l);
+}
+
+#[test]
+fn connect_refused_without_listener() {
+    let (mut client, _server) = pair(1500);
+    match client.connect(CLIENT, SocketAddrV4::new(SERVER, 1234)) {
+        Ok(_) => panic!("connected without a listener"),
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+    }
+}
+
+#[test]
+fn connect_timeout_without_peer() {
+    // nothing on the other end of the pipe to answer the SYN
+    let (a, _b) = PipeDevice::pair(1500);
+    let mut client = Interface::with_device(a).unwrap();
+    let r = client.connect_timeout(
+        CLIENT,
+        SocketAddrV4::new(SERVER, 1234),
+        Duration::from_millis(200),
+    );
+    match r {
+        Ok(_) => panic!("connected without a listener"),
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+    }
+}
+
+#[test]
+fn read_times_out() {
+    let (mut client, mut server) = pair(1500);
+    let mut l = server.bind(80).unwrap();
+    let _c = client
+        .connect(CLIENT, SocketAddrV4::new(SERVER, 80))
+        .unwrap();
+    let mut s = l.accept().unwrap();
+
+    assert_eq!(
+        s.set_read_timeout(Some(Duration::ZERO)).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    let timeout = Duration::from_millis(100);
+    s.set_read_timeout(Some(timeout)).unwrap();
+    assert_eq!(s.read_timeout().unwrap(), Some(timeout));
+
+    // nothing is ever sent
+    let start = Instant::now();
+    let mut buf = [0; 16];
+    assert_eq!(
+        s.read(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    assert!(start.elapsed() >= timeout);
+}
+
+#[test]
+fn write_times_out() {
+    let (mut client, mut server) = pair(1500);
+    let mut l = server.bind(80).unwrap();
+    let mut c = client
+        .connect(CLIENT, SocketAddrV4::new(SERVER, 80))
+        .unwrap();
+    // never read from, so once its receive window is full, writes have nowhere to go
+    let _s = l.accept().unwrap();
+
+    let timeout = Duration::from_millis(100);
+    c.set_write_timeout(Some(timeout)).unwrap();
+    assert_eq!(c.write_timeout().unwrap(), Some(timeout));
+    let buf = [0; 1024];
+    let mut written = 0;
+    let e = loop {
+        match c.write(&buf) {
+            Ok(n) => written += n,
+            Err(e) => break e,
+        }
+        assert!(
+            written < 1 << 20,
+            "wrote {} bytes without blocking",
+            written
+        );
+    };
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+
+    // and neither can what was written be flushed
+    let start = Instant::now();
+    assert_eq!(c.flush().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    assert!(start.elapsed() >= timeout);
+}
+
+#[test]
+fn nonblocking_read() {
+    let (mut client, mut server) = pair(1500);
+    let mut l = server.bind(80).unwrap();
+    let mut c = client
+        .connect(CLIENT, SocketAddrV4::new(SERVER, 80))
+        .unwrap();
+    let mut s = l.accept().unwrap();
+
+    s.set_nonblocking(true).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(
+        s.read(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    c.write_all(b"ready").unwrap();
+    s.set_nonblocking(false).unwrap();
+    let n = s.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"ready");
+}
+
+#[test]
+fn poller_reports_accept_and_data() {
+    let (mut client, mut server) = pair(1500);
+    let mut l = server.bind(80).unwrap();
+    let mut poller = server.poller();
+    poller
+        .register_listener(&l, Token(0), Interest::ACCEPTABLE)
+        .unwrap();
+
+    let mut events = Vec::new();
+    assert_eq!(
+        poller
+            .poll(&mut events, Some(Duration::from_millis(50)))
+            .unwrap(),
+        0
+    );
+
+    let mut c = client
+        .connect(CLIENT, SocketAddrV4::new(SERVER, 80))
+        .unwrap();
+    poller
+        .poll(&mut events, Some(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].token(), Token(0));
+    assert!(events[0].is_acceptable());
+
+    let s = l.accept().unwrap();
+    poller
+        .register_stream(&s, Token(1), Interest::READABLE | Interest::HUP)
+        .unwrap();
+    c.write_all(b"x").unwrap();
+    c.shutdown(Shutdown::Write).unwrap();
+
+    poller
+        .poll(&mut events, Some(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].token(), Token(1));
+    assert!(events[0].is_readable());
+}
+
+/// A device that claims an MTU too small for IPv4.
+struct Tiny(PipeDevice);
+
+impl NetDevice for Tiny {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        self.0.recv(buf, timeout)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.0.send(packet)
+    }
+
+    fn mtu(&self) -> usize {
+        40
+    }
+}
+
+#[test]
+fn rejects_devices_that_are_too_small() {
+    let (a, b) = PipeDevice::pair(1500);
+    assert_eq!(
+        Interface::with_device(Tiny(a)).err().map(|e| e.kind()),
+        Some(io::ErrorKind::InvalidInput)
+    );
+    let mut sim = Simulation::new(0);
+    assert_eq!(
+        sim.add_interface(Tiny(b)).err().map(|e| e.kind()),
+        Some(io::ErrorKind::InvalidInput)
+    );
+}
+
+#[test]
+fn pipe_keeps_to_its_mtu() {
+    let (mut a, mut b) = PipeDevice::pair(576);
+    assert_eq!(
+        a.send(&[0; 577]).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+
+    // a buffer too small for the packet gets as much of it as fits
+    a.send(&[7; 576]).unwrap();
+    let mut buf = [0; 100];
+    assert_eq!(b.recv(&mut buf, Duration::ZERO).unwrap(), Some(100));
+    assert_eq!(buf, [7; 100]);
+}
@@ -0,0 +1,63 @@
+//! Devices that say what each packet is, as a tun device with packet info does: the stack goes
+//! by what they say rather than by the version in the IP header.
+
+mod common;
+
+use common::{run, setup_over, LOCAL, REMOTE};
+use etherparse::{IpTrafficClass, Ipv4Header};
+use std::io;
+use std::time::Duration;
+use trust::{NetDevice, PipeDevice};
+
+/// A device that labels every packet with the same EtherType.
+struct Labelled(PipeDevice, u16);
+
+impl NetDevice for Labelled {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        self.0.recv(buf, timeout)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.0.send(packet)
+    }
+
+    fn mtu(&self) -> usize {
+        self.0.mtu()
+    }
+
+    fn ethertype(&self) -> Option<u16> {
+        Some(self.1)
+    }
+}
+
+/// A ping from the remote end to the interface.
+fn ping() -> Vec<u8> {
+    let msg = common::ping(&[]);
+    let ip = Ipv4Header::new(
+        msg.len() as u16,
+        64,
+        IpTrafficClass::Icmp,
+        REMOTE.octets(),
+        LOCAL.octets(),
+    );
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(&msg);
+    packet
+}
+
+/// How many packets the interface sends back for a ping arriving labelled `ethertype`.
+fn replies(ethertype: u16) -> usize {
+    let (mut sim, _iface, mut wire) = setup_over(1500, |dev| Labelled(dev, ethertype));
+    wire.send(&ping()).unwrap();
+    run(&mut sim, &mut wire, 10).len()
+}
+
+#[test]
+fn dispatches_on_the_ethertype() {
+    assert_eq!(replies(0x0800), 1);
+    // an IPv4 packet the device calls IPv6 is malformed, not IPv4 after all
+    assert_eq!(replies(0x86dd), 0);
+    // and neither is for the IP stack
+    assert_eq!(replies(0x0806), 0);
+}
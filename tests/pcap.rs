@@ -0,0 +1,186 @@
+//! Captures of a connection over a `PipeDevice` pair.
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use trust::{CaptureDevice, Interface, InterfaceBuilder, PipeDevice, Simulation};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(b[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+/// A captured packet: its data, its `epb_flags`, and its comment.
+struct Packet {
+    data: Vec<u8>,
+    flags: u32,
+    comment: String,
+}
+
+/// Splits a pcapng capture into its packets, checking the headers along the way.
+fn parse(capture: &[u8]) -> Vec<Packet> {
+    let mut packets = Vec::new();
+    let mut at = 0;
+    while at < capture.len() {
+        let kind = u32_at(capture, at);
+        let len = u32_at(capture, at + 4) as usize;
+        assert_eq!(len % 4, 0);
+        assert_eq!(u32_at(capture, at + len - 4) as usize, len);
+        let body = &capture[at + 8..at + len - 4];
+        match kind {
+            0x0a0d_0d0a => assert_eq!(u32_at(body, 0), 0x1a2b_3c4d),
+            1 => assert_eq!(u16_at(body, 0), 101, "not LINKTYPE_RAW"),
+            6 => {
+                let caplen = u32_at(body, 12) as usize;
+                let mut packet = Packet {
+                    data: body[20..20 + caplen].to_vec(),
+                    flags: 0,
+                    comment: String::new(),
+                };
+                let mut opt = 20 + caplen.next_multiple_of(4);
+                loop {
+                    let code = u16_at(body, opt);
+                    let olen = u16_at(body, opt + 2) as usize;
+                    let value = &body[opt + 4..opt + 4 + olen];
+                    match code {
+                        0 => break,
+                        1 => packet.comment = String::from_utf8(value.to_vec()).unwrap(),
+                        2 => packet.flags = u32_at(value, 0),
+                        _ => {}
+                    }
+                    opt += 4 + olen.next_multiple_of(4);
+                }
+                packets.push(packet);
+            }
+            _ => panic!("unexpected block type {:#x}", kind),
+        }
+        at += len;
+    }
+    packets
+}
+
+/// A capture file of its own for each test.
+fn capture_path(test: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("trust-{}-{}.pcapng", test, std::process::id()))
+}
+
+/// Whether `packet` is an IPv4 TCP SYN from `CLIENT` to `SERVER`.
+fn is_syn(packet: &[u8]) -> bool {
+    packet[0] >> 4 == 4
+        && packet[12..16] == CLIENT.octets()
+        && packet[16..20] == SERVER.octets()
+        && packet[20 + 13] & 0x02 != 0
+}
+
+#[test]
+fn captures_both_directions() {
+    let path = capture_path("capture");
+    let (a, b) = PipeDevice::pair(1500);
+    let mut client = Interface::with_device(CaptureDevice::create(a, &path).unwrap()).unwrap();
+    let mut server = Interface::with_device(b).unwrap();
+    let mut l = server.bind(7).unwrap();
+
+    let echo = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut buf = Vec::new();
+        s.read_to_end(&mut buf).unwrap();
+        s.write_all(&buf).unwrap();
+        s.shutdown(Shutdown::Write).unwrap();
+        s.flush().unwrap();
+        drop(s);
+        l
+    });
+
+    let mut s = client
+        .connect(CLIENT, SocketAddrV4::new(SERVER, 7))
+        .unwrap();
+    s.write_all(b"captured").unwrap();
+    s.shutdown(Shutdown::Write).unwrap();
+    let mut reply = Vec::new();
+    s.read_to_end(&mut reply).unwrap();
+    drop(s);
+    drop(echo.join().unwrap());
+    drop(client);
+
+    let capture = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let packets = parse(&capture);
+
+    let sent: Vec<_> = packets.iter().filter(|p| p.flags & 3 == 2).collect();
+    let received: Vec<_> = packets.iter().filter(|p| p.flags & 3 == 1).collect();
+    assert_eq!(sent.len() + received.len(), packets.len());
+    assert!(sent.iter().all(|p| p.comment == "sent"));
+    assert!(received.iter().all(|p| p.comment == "received"));
+
+    // the SYN goes first, from client to server
+    assert!(is_syn(&sent[0].data), "first packet is not a SYN");
+
+    let carries = |ps: &[&Packet]| {
+        ps.iter()
+            .any(|p| p.data.windows(8).any(|w| w == b"captured"))
+    };
+    assert!(carries(&sent));
+    assert!(carries(&received));
+}
+
+#[test]
+fn builder_captures_before_the_stack_starts() {
+    let path = capture_path("builder");
+    // nothing on the other end of the pipe to answer the SYN
+    let (a, _b) = PipeDevice::pair(1500);
+    let mut client = InterfaceBuilder::new()
+        .capture(&path)
+        .build_with_device(a)
+        .unwrap();
+    assert!(client
+        .connect_timeout(
+            CLIENT,
+            SocketAddrV4::new(SERVER, 7),
+            Duration::from_millis(100)
+        )
+        .is_err());
+    drop(client);
+
+    let capture = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let packets = parse(&capture);
+    assert!(!packets.is_empty());
+    assert!(packets
+        .iter()
+        .all(|p| p.comment == "sent" && is_syn(&p.data)));
+}
+
+#[test]
+fn simulated_interfaces_capture_too() {
+    let path = capture_path("sim");
+    let mut sim = Simulation::new(7);
+    let (a, b) = PipeDevice::pair(1500);
+    let builder = InterfaceBuilder::new().capture(&path);
+    let mut client = sim.add_interface_with(a, builder).unwrap();
+    let mut server = sim.add_interface(b).unwrap();
+
+    let _l = server.bind(7).unwrap();
+    let _c = client
+        .connect_nonblocking(CLIENT, SocketAddrV4::new(SERVER, 7))
+        .unwrap();
+    for _ in 0..100 {
+        sim.step().unwrap();
+    }
+    drop(sim);
+
+    let capture = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let packets = parse(&capture);
+    // SYN, SYN-ACK and ACK
+    assert_eq!(packets.len(), 3);
+    assert!(is_syn(&packets[0].data));
+    assert_eq!(packets[1].comment, "received");
+}
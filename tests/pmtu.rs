@@ -0,0 +1,155 @@
+//! Path MTU discovery, over a path narrower than the device: with routers that say so, and
+//! with a black hole that silently drops what does not fit.
+
+mod common;
+
+use common::checksum;
+use etherparse::{IpTrafficClass, Ipv4Header};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use trust::{NetDevice, PipeDevice, Simulation};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const ROUTER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 254);
+
+/// A link that only carries packets of up to `mtu` bytes, on a device that takes larger ones.
+struct Bottleneck {
+    inner: PipeDevice,
+    mtu: usize,
+    /// whether the router in front of the link reports what it drops
+    icmp: bool,
+    /// ICMP messages on their way back
+    replies: VecDeque<Vec<u8>>,
+    /// sizes of the packets that made it through
+    delivered: Arc<Mutex<Vec<usize>>>,
+}
+
+impl NetDevice for Bottleneck {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        if let Some(p) = self.replies.pop_front() {
+            buf[..p.len()].copy_from_slice(&p);
+            return Ok(Some(p.len()));
+        }
+        self.inner.recv(buf, timeout)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        if packet.len() <= self.mtu {
+            self.delivered.lock().unwrap().push(packet.len());
+            return self.inner.send(packet);
+        }
+        if self.icmp {
+            self.replies.push_back(too_big(packet, self.mtu));
+        }
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+}
+
+/// The ICMP fragmentation needed message a router sends back about `packet`, which did not
+/// fit its next hop.
+fn too_big(packet: &[u8], mtu: usize) -> Vec<u8> {
+    let mut msg = vec![3, 4, 0, 0, 0, 0, (mtu >> 8) as u8, mtu as u8];
+    msg.extend_from_slice(&packet[..packet.len().min(548)]);
+    let sum = checksum(&msg);
+    msg[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let mut ip = Ipv4Header::new(
+        msg.len() as u16,
+        64,
+        IpTrafficClass::Icmp,
+        ROUTER.octets(),
+        packet[12..16].try_into().unwrap(),
+    );
+    ip.dont_fragment = false;
+    let mut reply = Vec::new();
+    ip.write(&mut reply).unwrap();
+    reply.extend_from_slice(&msg);
+    reply
+}
+
+/// Sends `len` bytes from `client` to `server` through a bottleneck, and returns the sizes of
+/// the packets that got through.
+fn transfer(mtu: usize, icmp: bool, len: usize) -> Vec<usize> {
+    let mut sim = Simulation::new(0);
+    let (a, b) = PipeDevice::pair(1500);
+    let delivered = Arc::default();
+    let a = Bottleneck {
+        inner: a,
+        mtu,
+        icmp,
+        replies: VecDeque::new(),
+        delivered: Arc::clone(&delivered),
+    };
+    let mut client_if = sim.add_interface(a).unwrap();
+    let mut server_if = sim.add_interface(b).unwrap();
+
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    let got = Rc::new(RefCell::new(Vec::new()));
+
+    let mut l = server_if.bind(9).unwrap();
+    l.set_nonblocking(true).unwrap();
+    let mut accepted = None;
+    let sink = got.clone();
+    sim.spawn(move || {
+        if accepted.is_none() {
+            let s = l.accept()?;
+            s.set_nonblocking(true)?;
+            accepted = Some(s);
+        }
+        accepted
+            .as_mut()
+            .unwrap()
+            .read_to_end(&mut sink.borrow_mut())?;
+        Ok(())
+    });
+
+    let mut c = client_if
+        .connect_nonblocking(CLIENT, SocketAddrV4::new(SERVER, 9))
+        .unwrap();
+    let mut sent = 0;
+    let expected = data.clone();
+    sim.spawn(move || {
+        while sent < data.len() {
+            sent += c.write(&data[sent..])?;
+        }
+        c.flush()?;
+        c.shutdown(Shutdown::Write)
+    });
+
+    sim.run(Duration::from_secs(600)).unwrap();
+    assert!(*got.borrow() == expected, "data was mangled in transit");
+    let sizes = delivered.lock().unwrap().clone();
+    sizes
+}
+
+// Segments carry at most 1024 bytes of data (all the send queue holds), so only paths narrower
+// than that need discovering, and no IPv6 path is.
+
+#[test]
+fn router_lowers_the_path_mtu() {
+    let sizes = transfer(800, true, 100_000);
+    // full-sized segments fill the narrower path exactly
+    assert_eq!(sizes.iter().max(), Some(&800));
+}
+
+#[test]
+fn black_hole_is_probed_past() {
+    let sizes = transfer(900, false, 500_000);
+    // after falling back to 1024 bytes and then 576, probes find most of the way back up
+    let largest = *sizes.iter().max().unwrap();
+    assert!(
+        largest > 900 - 32,
+        "largest packet through was {} bytes",
+        largest
+    );
+}
@@ -0,0 +1,109 @@
+//! Replays of captured sessions, checked against golden transcripts.
+//!
+//! Set `TRUST_BLESS=1` to rewrite the golden files from the current behaviour, after checking
+//! that the change is intended.
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown};
+use std::path::Path;
+use std::time::Duration;
+use trust::{ReplayDevice, Simulation, Transcript};
+
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn check_golden(transcript: &Transcript, golden: &str) {
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join(golden);
+    let got = transcript.to_string();
+    if std::env::var_os("TRUST_BLESS").is_some() {
+        std::fs::write(&golden, got).unwrap();
+        return;
+    }
+    let want = std::fs::read_to_string(&golden).unwrap();
+    assert!(
+        got == want,
+        "transcript differs from {}:\n{}",
+        golden.display(),
+        got
+    );
+}
+
+/// Rewrites a pcapng capture as written by `CaptureDevice` in the older pcap format, with
+/// microsecond timestamps.
+fn to_pcap(pcapng: &[u8]) -> Vec<u8> {
+    let u32_at = |at: usize| u32::from_le_bytes(pcapng[at..at + 4].try_into().unwrap());
+    let mut pcap = Vec::new();
+    pcap.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    pcap.extend_from_slice(&2u16.to_le_bytes()); // major version
+    pcap.extend_from_slice(&4u16.to_le_bytes()); // minor version
+    pcap.extend_from_slice(&0u32.to_le_bytes()); // time zone
+    pcap.extend_from_slice(&0u32.to_le_bytes()); // timestamp accuracy
+    pcap.extend_from_slice(&65535u32.to_le_bytes()); // snap length
+    pcap.extend_from_slice(&101u32.to_le_bytes()); // LINKTYPE_RAW
+
+    let mut at = 0;
+    while at < pcapng.len() {
+        let len = u32_at(at + 4) as usize;
+        if u32_at(at) == 6 {
+            let ts = (u32_at(at + 12) as u64) << 32 | u32_at(at + 16) as u64;
+            let caplen = u32_at(at + 20) as usize;
+            let micros = ts / 1000;
+            for field in [micros / 1_000_000, micros % 1_000_000] {
+                pcap.extend_from_slice(&(field as u32).to_le_bytes());
+            }
+            pcap.extend_from_slice(&(caplen as u32).to_le_bytes());
+            pcap.extend_from_slice(&(caplen as u32).to_le_bytes());
+            pcap.extend_from_slice(&pcapng[at + 28..at + 28 + caplen]);
+        }
+        at += len;
+    }
+    pcap
+}
+
+/// Replays `dev` into an echo server.
+fn echo_server(dev: ReplayDevice) -> Transcript {
+    let mut sim = Simulation::new(0);
+    let transcript = dev.transcript();
+    let mut server = sim.add_interface(dev).unwrap();
+
+    let mut l = server.bind(7).unwrap();
+    l.set_nonblocking(true).unwrap();
+    let mut s = None;
+    let mut buf = Vec::new();
+    sim.spawn(move || {
+        if s.is_none() {
+            let accepted = l.accept()?;
+            accepted.set_nonblocking(true)?;
+            s = Some(accepted);
+        }
+        let s = s.as_mut().unwrap();
+        s.read_to_end(&mut buf)?;
+        assert_eq!(buf, b"hello, replay");
+        s.write_all(&buf)?;
+        s.shutdown(Shutdown::Write)
+    });
+    sim.run(Duration::from_secs(5)).unwrap();
+    // let the rest of the capture play out
+    while sim.elapsed() < Duration::from_secs(1) {
+        sim.step().unwrap();
+    }
+    transcript
+}
+
+/// An echo server sees a client send "hello, replay" and close.
+#[test]
+fn echo() {
+    let capture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/echo.pcapng");
+    let transcript = echo_server(ReplayDevice::open(capture, SERVER, 1500).unwrap());
+    check_golden(&transcript, "tests/data/echo.golden");
+}
+
+#[test]
+fn pcap_replays_like_pcapng() {
+    let capture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/echo.pcapng");
+    let pcapng = std::fs::read(capture).unwrap();
+    let pcap = to_pcap(&pcapng);
+    assert_eq!(
+        echo_server(ReplayDevice::new(&pcap[..], SERVER, 1500).unwrap()).to_string(),
+        echo_server(ReplayDevice::new(&pcapng[..], SERVER, 1500).unwrap()).to_string()
+    );
+}
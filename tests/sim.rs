@@ -0,0 +1,135 @@
+//! Transfers run by a `Simulation`, on a virtual clock.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::rc::Rc;
+use std::time::Duration;
+use trust::{Impairment, PipeDevice, Simulation};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// Sends `len` bytes from client to server over a lossy, jittery link, and returns how long that
+/// took in virtual time.
+fn transfer(seed: u64, len: usize) -> Duration {
+    let mut sim = Simulation::new(seed);
+    let impairment = Impairment {
+        loss: 0.05,
+        duplicate: 0.02,
+        delay: Duration::from_millis(10),
+        jitter: Duration::from_millis(5),
+        ..Default::default()
+    };
+    let (a, b) = PipeDevice::pair(1500);
+    let a = sim.impair(a, impairment.clone());
+    let b = sim.impair(b, impairment);
+    let mut client = sim.add_interface(a).unwrap();
+    let mut server = sim.add_interface(b).unwrap();
+
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    let got = Rc::new(RefCell::new(Vec::new()));
+
+    let mut l = server.bind(9).unwrap();
+    l.set_nonblocking(true).unwrap();
+    let mut accepted = None;
+    let sink = got.clone();
+    sim.spawn(move || {
+        if accepted.is_none() {
+            let s = l.accept()?;
+            s.set_nonblocking(true)?;
+            accepted = Some(s);
+        }
+        accepted
+            .as_mut()
+            .unwrap()
+            .read_to_end(&mut sink.borrow_mut())?;
+        Ok(())
+    });
+
+    let mut c = client
+        .connect_nonblocking(CLIENT, SocketAddrV4::new(SERVER, 9))
+        .unwrap();
+    let mut sent = 0;
+    let expected = data.clone();
+    sim.spawn(move || {
+        while sent < data.len() {
+            sent += c.write(&data[sent..])?;
+        }
+        c.flush()?;
+        c.shutdown(Shutdown::Write)
+    });
+
+    sim.run(Duration::from_secs(600)).unwrap();
+    assert!(*got.borrow() == expected, "data was mangled in transit");
+    sim.elapsed()
+}
+
+#[test]
+fn same_seed_same_run() {
+    assert_eq!(transfer(7, 50_000), transfer(7, 50_000));
+}
+
+#[test]
+fn seeds_change_the_run() {
+    let runs: Vec<_> = (0..4).map(|seed| transfer(seed, 50_000)).collect();
+    assert!(runs.iter().any(|r| *r != runs[0]), "{:?}", runs);
+}
+
+#[test]
+fn failing_task_ends_the_run() {
+    let mut sim = Simulation::new(0);
+    sim.spawn(|| Err(io::Error::other("boom")));
+    assert_eq!(
+        sim.run(Duration::from_secs(1)).unwrap_err().to_string(),
+        "boom"
+    );
+}
+
+#[test]
+fn unfinished_task_times_out() {
+    let mut sim = Simulation::new(0);
+    sim.spawn(|| Err(io::ErrorKind::WouldBlock.into()));
+    let e = sim.run(Duration::from_millis(100)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(sim.elapsed(), Duration::from_millis(100));
+}
+
+#[test]
+fn dropped_interface_resets_its_connections() {
+    let mut sim = Simulation::new(0);
+    let (a, b) = PipeDevice::pair(1500);
+    let mut client = sim.add_interface(a).unwrap();
+    let mut server = sim.add_interface(b).unwrap();
+
+    let mut l = server.bind(9).unwrap();
+    l.set_nonblocking(true).unwrap();
+    let c = client
+        .connect_nonblocking(CLIENT, SocketAddrV4::new(SERVER, 9))
+        .unwrap();
+    let mut s = None;
+    while s.is_none() {
+        sim.step().unwrap();
+        s = l.accept().ok();
+    }
+    let mut s = s.unwrap();
+    s.set_nonblocking(true).unwrap();
+    for _ in 0..100 {
+        sim.step().unwrap();
+    }
+
+    // the client's half of the connection lingers on after its stream, until the interface goes
+    drop(c);
+    for _ in 0..100 {
+        sim.step().unwrap();
+    }
+    s.write_all(b"still there?").unwrap();
+    drop(client);
+    for _ in 0..100 {
+        sim.step().unwrap();
+    }
+    assert_eq!(
+        s.write(b"hello?").unwrap_err().kind(),
+        io::ErrorKind::ConnectionReset
+    );
+}
@@ -0,0 +1,120 @@
+//! UDP sockets on interfaces talking to each other over an in-memory `PipeDevice`.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::thread;
+use std::time::Duration;
+use trust::{Interface, PipeDevice};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const CLIENT6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+const SERVER6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+fn pair() -> (Interface, Interface) {
+    let (a, b) = PipeDevice::pair(1500);
+    (
+        Interface::with_device(a).unwrap(),
+        Interface::with_device(b).unwrap(),
+    )
+}
+
+#[test]
+fn echo() {
+    let (mut client, mut server) = pair();
+    for (c, s) in [
+        (IpAddr::from(CLIENT), IpAddr::from(SERVER)),
+        (CLIENT6.into(), SERVER6.into()),
+    ] {
+        let echo = server.bind_udp((s, 7)).unwrap();
+        let echo = thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (n, from) = echo.recv_from(&mut buf).unwrap();
+            echo.send_to(&buf[..n], from).unwrap();
+        });
+
+        let sock = client.bind_udp((c, 0)).unwrap();
+        let local = sock.local_addr().unwrap();
+        assert!(local.port() >= 49152, "{} is not an ephemeral port", local);
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(sock.send_to(b"hello, datagram", (s, 7)).unwrap(), 15);
+        let mut buf = [0; 1500];
+        let (n, from) = sock.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..n], from), (&b"hello, datagram"[..], (s, 7).into()));
+        echo.join().unwrap();
+    }
+}
+
+#[test]
+fn connected_socket_hears_refusal() {
+    let (mut client, _server) = pair();
+    let sock = client.bind_udp((CLIENT, 0)).unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // nothing is bound to port 9
+    sock.connect((SERVER, 9)).unwrap();
+    sock.send(b"anyone?").unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(
+        sock.recv(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::ConnectionRefused
+    );
+}
+
+#[test]
+fn connected_socket_only_hears_its_peer() {
+    let (mut client, mut server) = pair();
+    let sock = server.bind_udp((SERVER, 53)).unwrap();
+    sock.set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let peer = client.bind_udp((CLIENT, 0)).unwrap();
+    let other = client.bind_udp((CLIENT, 0)).unwrap();
+    sock.connect(peer.local_addr().unwrap()).unwrap();
+
+    other.send_to(b"not for you", (SERVER, 53)).unwrap();
+    peer.send_to(b"for you", (SERVER, 53)).unwrap();
+    let mut buf = [0; 16];
+    let (n, from) = sock.recv_from(&mut buf).unwrap();
+    assert_eq!(
+        (&buf[..n], from),
+        (&b"for you"[..], peer.local_addr().unwrap())
+    );
+    assert_eq!(
+        sock.recv(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn bad_binds_and_sends() {
+    let (mut client, _server) = pair();
+    let sock = client.bind_udp((CLIENT, 5353)).unwrap();
+    assert_eq!(
+        client.bind_udp((CLIENT, 5353)).err().map(|e| e.kind()),
+        Some(io::ErrorKind::AddrInUse)
+    );
+    assert_eq!(
+        client
+            .bind_udp((Ipv4Addr::UNSPECIFIED, 5354))
+            .err()
+            .map(|e| e.kind()),
+        Some(io::ErrorKind::InvalidInput)
+    );
+    // the port is free again once the socket is gone
+    drop(sock);
+    let sock = client.bind_udp((CLIENT, 5353)).unwrap();
+
+    let to: SocketAddr = (SERVER, 9).into();
+    assert_eq!(
+        sock.send_to(&[0; 1500], to).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        sock.send_to(b"x", (SERVER6, 9)).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        sock.send(b"x").unwrap_err().kind(),
+        io::ErrorKind::NotConnected
+    );
+}